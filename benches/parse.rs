@@ -0,0 +1,55 @@
+//! Parses a large synthetic clippings file, about the size of a few years of
+//! reading.
+//!
+//! `cargo bench --bench parse -- --sample-size 10`, 5000 highlights with a
+//! note each (~2 MB), on one machine:
+//! - baseline tree: `parse_from_txt` 1.94 s
+//! - now: `parse_from_txt` 1.26 s, `list_terms` over its clippings 7 ms
+//!
+//! `list_terms` compiled the cloze regex once per card when it was added,
+//! which took the two together to 3.00 s. Compiling that regex once only
+//! undid the slowdown. The parser itself is faster than the baseline because
+//! its header regexes are compiled once and it no longer prints every book
+//! line. Most of what is left is compiling one case-insensitive regex per
+//! clozed term, which can't be shared since every term is different.
+use anki_kindle_import::{config::ParseOptions, list_terms, parse_from_txt, Warnings};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+fn synthetic_clippings(count: usize) -> String {
+    let mut clippings_txt = String::new();
+    for i in 0..count {
+        let book = format!("Book {} (Author {})", i % 50, i % 7);
+        clippings_txt.push_str(&format!(
+            "{book}\n- Your Highlight on page {i} | Location {i}-{} | Added on Friday, November 24, 2018 11:31:30 AM\n\nThe cat number {i} walked over the hill and sat down in the tall grass to rest.\n==========\n",
+            i + 1
+        ));
+        clippings_txt.push_str(&format!(
+            "{book}\n- Your Note on page {i} | Location {} | Added on Friday, November 24, 2018 11:32:30 AM\n\nwalked .. to move on foot\nhill\ngrass ... a plant\n==========\n",
+            i + 1
+        ));
+    }
+    clippings_txt
+}
+
+fn parse(c: &mut Criterion) {
+    let clippings_txt = synthetic_clippings(5000);
+    let options = ParseOptions::default();
+
+    c.bench_function("parse_from_txt", |b| {
+        b.iter(|| {
+            parse_from_txt(
+                black_box(&clippings_txt),
+                None,
+                &options,
+                &mut Warnings::default(),
+            )
+            .unwrap()
+        })
+    });
+
+    let entries = parse_from_txt(&clippings_txt, None, &options, &mut Warnings::default()).unwrap();
+    c.bench_function("list_terms", |b| b.iter(|| list_terms(black_box(&entries))));
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
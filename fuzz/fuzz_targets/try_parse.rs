@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(clippings_txt) = std::str::from_utf8(data) {
+        // any result is fine, as long as it doesn't panic
+        let _ = anki_kindle_import::try_parse(clippings_txt);
+    }
+});
@@ -0,0 +1,233 @@
+//! Adds cards straight to a running Anki through the
+//! [AnkiConnect](https://foosoft.net/projects/anki-connect/) add-on, instead of
+//! writing a file to import. Cards use Anki's built-in `Basic` and `Cloze` note
+//! types.
+use anyhow::{bail, Context, Error};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{with_extra, Basic, Card, Cloze};
+
+/// The AnkiConnect api version the requests are written for
+const VERSION: u8 = 6;
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    result: Value,
+    error: Value,
+}
+
+/// Adds one note per card to `deck`, creating the deck if needed, and returns
+/// the ids of the notes that were added. Notes Anki refuses, e.g. duplicates of
+/// notes already in the deck, are logged and skipped.
+pub fn push_cards(cards: &[Card], deck: &str, connect_url: &str) -> Result<Vec<u64>, Error> {
+    request(connect_url, "createDeck", json!({ "deck": deck }))?;
+
+    // duplicates are filtered out first, since `addNotes` fails on them
+    let notes = notes(cards, deck);
+    let can_add: Vec<bool> = serde_json::from_value(request(
+        connect_url,
+        "canAddNotes",
+        json!({ "notes": notes }),
+    )?)?;
+    let mut to_add = Vec::new();
+    for ((card, note), can_add) in cards.iter().zip(notes).zip(can_add) {
+        if can_add {
+            to_add.push(note);
+        } else {
+            let (front, _) = card.export_fields();
+            warn!("skipping note `{front}`, it's already in `{deck}` or has an empty first field");
+        }
+    }
+    if to_add.is_empty() {
+        info!("no new notes to add to `{deck}`");
+        return Ok(Vec::new());
+    }
+
+    let results: Vec<Option<u64>> = serde_json::from_value(request(
+        connect_url,
+        "addNotes",
+        json!({ "notes": to_add }),
+    )?)?;
+    let ids: Vec<u64> = results.into_iter().flatten().collect();
+    if ids.len() < to_add.len() {
+        warn!("{} notes couldn't be added", to_add.len() - ids.len());
+    }
+    info!("added {} of {} notes to `{deck}`", ids.len(), cards.len());
+    Ok(ids)
+}
+
+/// The `notes` of an `addNotes` request
+fn notes(cards: &[Card], deck: &str) -> Vec<Value> {
+    cards
+        .iter()
+        .map(|card| {
+            let (model, fields) = match card {
+                Card::Basic(Basic {
+                    front, back, extra, ..
+                }) => {
+                    let back = with_extra(back, extra.as_deref());
+                    ("Basic", json!({ "Front": front, "Back": back }))
+                }
+                Card::Cloze(Cloze {
+                    text,
+                    back_extra,
+                    context,
+                    extra,
+                    ..
+                }) => {
+                    // the built-in cloze type has no field for the context
+                    let back_extra = match context {
+                        Some(context) => format!("{back_extra}<br>{context}"),
+                        None => back_extra.clone(),
+                    };
+                    let back_extra = with_extra(&back_extra, extra.as_deref());
+                    ("Cloze", json!({ "Text": text, "Back Extra": back_extra }))
+                }
+            };
+            json!({
+                "deckName": deck,
+                "modelName": model,
+                "fields": fields,
+                "tags": card.tags(),
+                "options": { "allowDuplicate": false, "duplicateScope": "deck" },
+            })
+        })
+        .collect()
+}
+
+/// Sends one action to AnkiConnect and returns its result
+fn request(connect_url: &str, action: &str, params: Value) -> Result<Value, Error> {
+    let body = json!({ "action": action, "version": VERSION, "params": params });
+    let response: Response = reqwest::blocking::Client::new()
+        .post(connect_url)
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.text())
+        .with_context(|| format!("unable to reach AnkiConnect at {connect_url}, is Anki running?"))
+        .and_then(|text| {
+            serde_json::from_str(&text)
+                .with_context(|| format!("unexpected response from AnkiConnect: {text}"))
+        })?;
+    if !response.error.is_null() {
+        bail!("AnkiConnect `{action}` failed: {}", response.error);
+    }
+    Ok(response.result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+
+    /// Answers one request with each of `results` in turn on a local port, and
+    /// returns the url and the bodies of the requests
+    fn serve(results: Vec<Value>) -> (String, Receiver<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, requests) = mpsc::channel();
+        std::thread::spawn(move || {
+            for result in results {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                let mut line = String::new();
+                // the headers end with an empty line
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    line.clear();
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                sender
+                    .send(serde_json::from_slice::<Value>(&body).unwrap())
+                    .unwrap();
+                let reply = json!({ "result": result, "error": null }).to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{reply}",
+                    reply.len()
+                )
+                .unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn cards() -> [Card; 2] {
+        [
+            Card::Cloze(Cloze {
+                text: "The cat walked over the {{c1::hill}}".to_string(),
+                back_extra: "a small mountain".to_string(),
+                tags: vec!["moby_dick".to_string()],
+                context: Some("Call me Ishmael".to_string()),
+                raw_note: None,
+                extra: None,
+            }),
+            Card::Basic(Basic {
+                front: "to move on foot".to_string(),
+                back: "walk".to_string(),
+                tags: vec!["moby_dick".to_string(), "verb".to_string()],
+                raw_note: None,
+                extra: Some("from the old english wealcan".to_string()),
+            }),
+        ]
+    }
+
+    #[test]
+    fn maps_cards_to_basic_and_cloze_notes() {
+        assert_eq!(
+            notes(&cards(), "Kindle"),
+            [
+                json!({
+                    "deckName": "Kindle",
+                    "modelName": "Cloze",
+                    "fields": {
+                        "Text": "The cat walked over the {{c1::hill}}",
+                        "Back Extra": "a small mountain<br>Call me Ishmael",
+                    },
+                    "tags": ["moby_dick"],
+                    "options": { "allowDuplicate": false, "duplicateScope": "deck" },
+                }),
+                json!({
+                    "deckName": "Kindle",
+                    "modelName": "Basic",
+                    "fields": {
+                        "Front": "to move on foot",
+                        "Back": "walk<br>from the old english wealcan",
+                    },
+                    "tags": ["moby_dick", "verb"],
+                    "options": { "allowDuplicate": false, "duplicateScope": "deck" },
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_notes_anki_can_add_are_added() {
+        let (url, requests) = serve(vec![
+            Value::Null,
+            json!([false, true]),
+            json!([1_668_988_800_003u64]),
+        ]);
+        let ids = push_cards(&cards(), "Kindle", &url).unwrap();
+        assert_eq!(ids, [1_668_988_800_003]);
+
+        let actions: Vec<Value> = requests.iter().take(3).collect();
+        assert_eq!(actions[0]["action"], "createDeck");
+        assert_eq!(actions[1]["action"], "canAddNotes");
+        assert_eq!(actions[1]["params"]["notes"].as_array().unwrap().len(), 2);
+        assert_eq!(actions[2]["action"], "addNotes");
+        assert_eq!(
+            actions[2]["params"]["notes"],
+            json!([notes(&cards(), "Kindle")[1]])
+        );
+    }
+}
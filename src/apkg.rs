@@ -0,0 +1,620 @@
+//! Writes cards to an `.apkg` package that can be opened in Anki directly: a zip
+//! with a `collection.anki2` SQLite database (the legacy schema, version 11),
+//! an empty `media` map and a `meta` marking the package as legacy.
+//!
+//! With `--append-to`, the cards are added to a package that already exists
+//! instead, e.g. one written by an earlier run, leaving the notes in it alone.
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, Cursor, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::{bail, Context, Error};
+use chrono::Utc;
+use regex::Regex;
+use rusqlite::{params, Connection};
+use serde_json::json;
+use zip::{write::FileOptions, ZipArchive, ZipWriter};
+
+use crate::{with_extra, Basic, Card, Cloze};
+
+/// Note type ids are fixed, so packages from different runs share note types
+const BASIC_MODEL_ID: i64 = 1_668_988_800_001;
+const CLOZE_MODEL_ID: i64 = 1_668_988_800_002;
+
+/// `PackageMetadata { version: LEGACY_1 }`, protobuf encoded
+const META: [u8; 2] = [0x08, 0x01];
+
+const SCHEMA: &str = "
+CREATE TABLE col (
+    id integer primary key, crt integer not null, mod integer not null,
+    scm integer not null, ver integer not null, dty integer not null,
+    usn integer not null, ls integer not null, conf text not null,
+    models text not null, decks text not null, dconf text not null,
+    tags text not null
+);
+CREATE TABLE notes (
+    id integer primary key, guid text not null, mid integer not null,
+    mod integer not null, usn integer not null, tags text not null,
+    flds text not null, sfld integer not null, csum integer not null,
+    flags integer not null, data text not null
+);
+CREATE TABLE cards (
+    id integer primary key, nid integer not null, did integer not null,
+    ord integer not null, mod integer not null, usn integer not null,
+    type integer not null, queue integer not null, due integer not null,
+    ivl integer not null, factor integer not null, reps integer not null,
+    lapses integer not null, left integer not null, odue integer not null,
+    odid integer not null, flags integer not null, data text not null
+);
+CREATE TABLE revlog (
+    id integer primary key, cid integer not null, usn integer not null,
+    ease integer not null, ivl integer not null, lastIvl integer not null,
+    factor integer not null, time integer not null, type integer not null
+);
+CREATE TABLE graves (usn integer not null, oid integer not null, type integer not null);
+CREATE INDEX ix_notes_usn on notes (usn);
+CREATE INDEX ix_cards_usn on cards (usn);
+CREATE INDEX ix_revlog_usn on revlog (usn);
+CREATE INDEX ix_cards_nid on cards (nid);
+CREATE INDEX ix_cards_sched on cards (did, queue, due);
+CREATE INDEX ix_revlog_cid on revlog (cid);
+CREATE INDEX ix_notes_csum on notes (csum);
+";
+
+/// Writes `cards` into a new deck called `deck`, in the package at `apkg_path`
+pub fn write_apkg(cards: &[Card], deck: &str, apkg_path: &Path) -> Result<(), Error> {
+    let collection_path = apkg_path.with_extension("anki2.tmp");
+    // a leftover from a crashed run would already have the tables
+    let _ = fs::remove_file(&collection_path);
+    write_collection(cards, deck, &collection_path)?;
+    let collection = fs::read(&collection_path)?;
+    fs::remove_file(&collection_path)?;
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    zip.start_file("collection.anki2", FileOptions::default())?;
+    zip.write_all(&collection)?;
+    zip.start_file("media", FileOptions::default())?;
+    zip.write_all(b"{}")?;
+    zip.start_file("meta", FileOptions::default())?;
+    zip.write_all(&META)?;
+    let package = zip.finish()?.into_inner();
+    crate::write_atomic(apkg_path, &package)
+}
+
+/// Adds the notes of `cards` that aren't in the package at `apkg_path` yet,
+/// going by their guid, to the deck `deck`. The notes already in it and the
+/// scheduling of their cards are left as they are. Returns how many notes
+/// were added.
+pub fn append_apkg(cards: &[Card], deck: &str, apkg_path: &Path) -> Result<usize, Error> {
+    update_collection(apkg_path, |collection_path| {
+        add_to_collection(cards, deck, collection_path)
+    })
+}
+
+/// Runs `update` on the collection of the package at `apkg_path`, unpacked to
+/// a temporary file, and packs it again. Everything else in the package, like
+/// the media, is copied over as it is.
+fn update_collection<T>(
+    apkg_path: &Path,
+    update: impl FnOnce(&Path) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let file =
+        fs::File::open(apkg_path).with_context(|| format!("unable to open {apkg_path:?}"))?;
+    let mut archive =
+        ZipArchive::new(file).with_context(|| format!("{apkg_path:?} is not an .apkg"))?;
+    if archive
+        .file_names()
+        .any(|name| name == "collection.anki21b")
+    {
+        bail!("{apkg_path:?} was exported by a newer Anki, export it again with \"Support older Anki versions\"");
+    }
+    // packages from Anki 2.1 keep their notes in `collection.anki21`
+    let name = ["collection.anki21", "collection.anki2"]
+        .into_iter()
+        .find(|name| archive.file_names().any(|file_name| file_name == *name))
+        .with_context(|| format!("no collection in {apkg_path:?}"))?;
+
+    let collection_path = apkg_path.with_extension("anki2.tmp");
+    let mut collection_file = fs::File::create(&collection_path)
+        .with_context(|| format!("unable to create {collection_path:?}"))?;
+    io::copy(&mut archive.by_name(name)?, &mut collection_file)?;
+    drop(collection_file);
+    let updated = update(&collection_path);
+    let collection = fs::read(&collection_path);
+    fs::remove_file(&collection_path)?;
+    let (updated, collection) = (updated?, collection?);
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for index in 0..archive.len() {
+        let file = archive.by_index_raw(index)?;
+        if file.name() == name {
+            zip.start_file(name, FileOptions::default())?;
+            zip.write_all(&collection)?;
+        } else {
+            zip.raw_copy_file(file)?;
+        }
+    }
+    let package = zip.finish()?.into_inner();
+    crate::write_atomic(apkg_path, &package)?;
+    Ok(updated)
+}
+
+fn write_collection(cards: &[Card], deck: &str, collection_path: &Path) -> Result<(), Error> {
+    let now = Utc::now();
+    let now_ms = now.timestamp_millis();
+    let deck_id = deck_id(deck);
+
+    let mut db = Connection::open(collection_path)
+        .with_context(|| format!("unable to create {collection_path:?}"))?;
+    db.execute_batch(SCHEMA)?;
+    db.execute(
+        "INSERT INTO col VALUES (1, ?, ?, ?, 11, 0, 0, 0, ?, ?, ?, ?, '{}')",
+        params![
+            now.timestamp(),
+            now_ms,
+            now_ms,
+            conf(deck_id).to_string(),
+            models(deck_id, now.timestamp()).to_string(),
+            decks(deck, deck_id, now.timestamp()).to_string(),
+            dconf().to_string(),
+        ],
+    )?;
+    insert_notes(&mut db, cards, deck_id)?;
+    Ok(())
+}
+
+/// Adds the deck and note types of this crate to the collection at
+/// `collection_path` if it doesn't have them yet, then the notes of `cards`
+/// it doesn't have yet
+fn add_to_collection(cards: &[Card], deck: &str, collection_path: &Path) -> Result<usize, Error> {
+    let now = Utc::now();
+    let deck_id = deck_id(deck);
+
+    let mut db = Connection::open(collection_path)
+        .with_context(|| format!("unable to open {collection_path:?}"))?;
+    let (known_models, known_decks): (String, String) =
+        db.query_row("SELECT models, decks FROM col", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+    let mut known_models: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(&known_models).context("unable to read the note types")?;
+    let mut known_decks: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(&known_decks).context("unable to read the decks")?;
+    if let serde_json::Value::Object(models) = models(deck_id, now.timestamp()) {
+        for (id, model) in models {
+            known_models.entry(id).or_insert(model);
+        }
+    }
+    known_decks
+        .entry(deck_id.to_string())
+        .or_insert_with(|| self::deck(deck, deck_id, now.timestamp()));
+    db.execute(
+        "UPDATE col SET mod = ?, models = ?, decks = ?",
+        params![
+            now.timestamp_millis(),
+            serde_json::Value::Object(known_models).to_string(),
+            serde_json::Value::Object(known_decks).to_string(),
+        ],
+    )?;
+    insert_notes(&mut db, cards, deck_id)
+}
+
+/// Inserts a note for every card whose guid isn't in the collection yet, with
+/// its cards in `deck_id` due after the new cards already there. Returns how
+/// many notes were inserted.
+fn insert_notes(db: &mut Connection, cards: &[Card], deck_id: i64) -> Result<usize, Error> {
+    let now = Utc::now();
+    // ids only have to be unique, and Anki uses the time in ms for them
+    let highest_id: i64 = db.query_row("SELECT COALESCE(MAX(id), 0) FROM notes", [], |row| {
+        row.get(0)
+    })?;
+    let first_id = now.timestamp_millis().max(highest_id + 1);
+    // counted separately, a note can have any number of cards
+    let highest_card_id: i64 =
+        db.query_row("SELECT COALESCE(MAX(id), 0) FROM cards", [], |row| {
+            row.get(0)
+        })?;
+    let mut card_id = now.timestamp_millis().max(highest_card_id + 1);
+    // new cards are shown in the order of their `due`
+    let first_due: i64 = db.query_row(
+        "SELECT COALESCE(MAX(due) + 1, 0) FROM cards WHERE type = 0",
+        [],
+        |row| row.get(0),
+    )?;
+    let mut guids: BTreeSet<String> = db
+        .prepare("SELECT guid FROM notes")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+
+    let tx = db.transaction()?;
+    let mut position = 0;
+    for card in cards {
+        let note_id = first_id + position;
+        let (model_id, fields, ords): (i64, Vec<String>, Vec<i64>) = match card {
+            Card::Basic(Basic {
+                front, back, extra, ..
+            }) => (
+                BASIC_MODEL_ID,
+                vec![front.clone(), with_extra(back, extra.as_deref())],
+                vec![0],
+            ),
+            Card::Cloze(Cloze {
+                text,
+                back_extra,
+                context,
+                extra,
+                ..
+            }) => (
+                CLOZE_MODEL_ID,
+                vec![
+                    text.clone(),
+                    with_extra(back_extra, extra.as_deref()),
+                    context.clone().unwrap_or_default(),
+                ],
+                cloze_ords(text),
+            ),
+        };
+        let flds = fields.join("\x1f");
+        let guid = guid(model_id, &flds);
+        if !guids.insert(guid.clone()) {
+            continue;
+        }
+        let sort_field = strip_html(&fields[0]);
+        let tags = if card.tags().is_empty() {
+            String::new()
+        } else {
+            format!(" {} ", card.tags().join(" "))
+        };
+        tx.execute(
+            "INSERT INTO notes VALUES (?, ?, ?, ?, -1, ?, ?, ?, ?, 0, '')",
+            params![
+                note_id,
+                guid,
+                model_id,
+                now.timestamp(),
+                tags,
+                flds,
+                sort_field,
+                checksum(&sort_field),
+            ],
+        )?;
+        for ord in ords {
+            tx.execute(
+                "INSERT INTO cards VALUES (?, ?, ?, ?, ?, -1, 0, 0, ?, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+                params![
+                    card_id,
+                    note_id,
+                    deck_id,
+                    ord,
+                    now.timestamp(),
+                    first_due + position,
+                ],
+            )?;
+            card_id += 1;
+        }
+        position += 1;
+    }
+    tx.commit()?;
+    Ok(position as usize)
+}
+
+/// Every `{{cN::` in a cloze makes its own card, with ord `N - 1`
+fn cloze_ords(text: &str) -> Vec<i64> {
+    static RE_CLOZE_NUMBER: OnceLock<Regex> = OnceLock::new();
+    let re = RE_CLOZE_NUMBER.get_or_init(|| Regex::new(r"\{\{c(?P<n>\d+)::").unwrap());
+    let ords: BTreeSet<i64> = re
+        .captures_iter(text)
+        .filter_map(|captures| captures["n"].parse::<i64>().ok())
+        .filter(|n| *n > 0)
+        .map(|n| n - 1)
+        .collect();
+    ords.into_iter().collect()
+}
+
+fn strip_html(field: &str) -> String {
+    static RE_TAG: OnceLock<Regex> = OnceLock::new();
+    let re = RE_TAG.get_or_init(|| Regex::new(r"<[^>]*>").unwrap());
+    re.replace_all(field, "").trim().to_string()
+}
+
+/// The first 8 hex digits of the sha1 of the sort field, which Anki uses to
+/// find duplicates
+fn checksum(sort_field: &str) -> i64 {
+    let digest = sha1_smol::Sha1::from(sort_field).digest().bytes();
+    i64::from(u32::from_be_bytes([
+        digest[0], digest[1], digest[2], digest[3],
+    ]))
+}
+
+/// Derived from the note's content, so importing the same card twice updates
+/// it instead of adding a copy
+fn guid(model_id: i64, flds: &str) -> String {
+    let digest = sha1_smol::Sha1::from(format!("{model_id}{flds}"))
+        .digest()
+        .to_string();
+    digest[..16].to_string()
+}
+
+/// Derived from the name, so every package for a deck adds to the same deck
+fn deck_id(deck: &str) -> i64 {
+    let digest = sha1_smol::Sha1::from(deck).digest().bytes();
+    // keep it positive and clear of the default deck's id of 1
+    i64::from(u32::from_be_bytes([
+        digest[0], digest[1], digest[2], digest[3],
+    ])) + 2
+}
+
+fn conf(deck_id: i64) -> serde_json::Value {
+    json!({
+        "activeDecks": [deck_id],
+        "curDeck": deck_id,
+        "newSpread": 0,
+        "collapseTime": 1200,
+        "timeLim": 0,
+        "estTimes": true,
+        "dueCounts": true,
+        "curModel": null,
+        "nextPos": 1,
+        "sortType": "noteFld",
+        "sortBackwards": false,
+        "addToCur": true,
+    })
+}
+
+fn deck(name: &str, id: i64, modified: i64) -> serde_json::Value {
+    json!({
+        "collapsed": false,
+        "conf": 1,
+        "desc": "",
+        "dyn": 0,
+        "extendNew": 10,
+        "extendRev": 50,
+        "id": id,
+        "lrnToday": [0, 0],
+        "mod": modified,
+        "name": name,
+        "newToday": [0, 0],
+        "revToday": [0, 0],
+        "timeToday": [0, 0],
+        "usn": -1,
+    })
+}
+
+fn decks(name: &str, id: i64, modified: i64) -> serde_json::Value {
+    json!({
+        "1": deck("Default", 1, modified),
+        id.to_string(): deck(name, id, modified),
+    })
+}
+
+fn dconf() -> serde_json::Value {
+    json!({
+        "1": {
+            "autoplay": true,
+            "id": 1,
+            "lapse": { "delays": [10], "leechAction": 0, "leechFails": 8, "minInt": 1, "mult": 0 },
+            "maxTaken": 60,
+            "mod": 0,
+            "name": "Default",
+            "new": {
+                "bury": true,
+                "delays": [1, 10],
+                "initialFactor": 2500,
+                "ints": [1, 4, 7],
+                "order": 1,
+                "perDay": 20,
+                "separate": true,
+            },
+            "replayq": true,
+            "rev": {
+                "bury": true,
+                "ease4": 1.3,
+                "fuzz": 0.05,
+                "ivlFct": 1,
+                "maxIvl": 36500,
+                "minSpace": 1,
+                "perDay": 100,
+            },
+            "timer": 0,
+            "usn": 0,
+        }
+    })
+}
+
+fn field(name: &str, ord: usize) -> serde_json::Value {
+    json!({
+        "name": name,
+        "ord": ord,
+        "font": "Arial",
+        "size": 20,
+        "rtl": false,
+        "sticky": false,
+        "media": [],
+    })
+}
+
+fn model(
+    id: i64,
+    name: &str,
+    kind: u8,
+    fields: &[&str],
+    (qfmt, afmt): (&str, &str),
+    (deck_id, modified): (i64, i64),
+) -> serde_json::Value {
+    json!({
+        "id": id,
+        "name": name,
+        "type": kind,
+        "did": deck_id,
+        "mod": modified,
+        "usn": -1,
+        "sortf": 0,
+        "tags": [],
+        "vers": [],
+        "req": [[0, "any", [0]]],
+        "flds": fields.iter().enumerate().map(|(ord, name)| field(name, ord)).collect::<Vec<_>>(),
+        "tmpls": [{
+            "name": "Card 1",
+            "ord": 0,
+            "qfmt": qfmt,
+            "afmt": afmt,
+            "bqfmt": "",
+            "bafmt": "",
+            "did": null,
+        }],
+        "css": ".card { font-family: arial; font-size: 20px; text-align: center; color: black; background-color: white; }\n.cloze { font-weight: bold; color: blue; }",
+        "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+        "latexPost": "\\end{document}",
+    })
+}
+
+fn models(deck_id: i64, modified: i64) -> serde_json::Value {
+    json!({
+        BASIC_MODEL_ID.to_string(): model(
+            BASIC_MODEL_ID,
+            "anki-kindle-import Basic",
+            0,
+            &["Front", "Back"],
+            ("{{Front}}", "{{FrontSide}}<hr id=answer>{{Back}}"),
+            (deck_id, modified),
+        ),
+        CLOZE_MODEL_ID.to_string(): model(
+            CLOZE_MODEL_ID,
+            "anki-kindle-import Cloze",
+            1,
+            &["Text", "Back Extra", "Context"],
+            ("{{cloze:Text}}", "{{cloze:Text}}<br>{{Back Extra}}<br>{{Context}}"),
+            (deck_id, modified),
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_dir;
+
+    fn basic(front: &str, back: &str) -> Card {
+        Card::Basic(Basic {
+            front: front.to_string(),
+            back: back.to_string(),
+            tags: Vec::new(),
+            raw_note: None,
+            extra: None,
+        })
+    }
+
+    /// The guid and the interval of the card of every note in the package
+    fn notes(apkg_path: &Path) -> Vec<(String, i64)> {
+        update_collection(apkg_path, |collection_path| {
+            let db = Connection::open(collection_path)?;
+            let mut query = db.prepare(
+                "SELECT notes.guid, cards.ivl FROM notes JOIN cards ON cards.nid = notes.id ORDER BY notes.id",
+            )?;
+            let notes = query
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+            Ok(notes)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn appends_only_new_notes_and_keeps_scheduling() {
+        let dir = temp_dir("append-apkg");
+        let apkg_path = dir.join("deck.apkg");
+        let hill = basic("a small mountain", "hill");
+        write_apkg(std::slice::from_ref(&hill), "Kindle", &apkg_path).unwrap();
+        // the card was studied since, and is next due in 10 days
+        update_collection(&apkg_path, |collection_path| {
+            Connection::open(collection_path)?
+                .execute("UPDATE cards SET type = 2, queue = 2, ivl = 10", [])?;
+            Ok(())
+        })
+        .unwrap();
+        let before = notes(&apkg_path);
+
+        let walk = basic("to move on foot", "walk");
+        let added = append_apkg(&[hill, walk], "Kindle", &apkg_path).unwrap();
+        assert_eq!(added, 1);
+
+        let after = notes(&apkg_path);
+        assert_eq!(after.len(), 2);
+        assert_eq!(after[0], before[0]);
+        assert_eq!(after[1].1, 0);
+    }
+
+    #[test]
+    fn appending_twice_adds_nothing_the_second_time() {
+        let dir = temp_dir("append-apkg-twice");
+        let apkg_path = dir.join("deck.apkg");
+        write_apkg(&[], "Kindle", &apkg_path).unwrap();
+        let cards = [basic("a small mountain", "hill")];
+        assert_eq!(append_apkg(&cards, "Kindle", &apkg_path).unwrap(), 1);
+        assert_eq!(append_apkg(&cards, "Kindle", &apkg_path).unwrap(), 0);
+        assert_eq!(notes(&apkg_path).len(), 1);
+    }
+
+    #[test]
+    fn package_round_trips_with_a_note_per_card() {
+        let dir = temp_dir("write-apkg");
+        let apkg_path = dir.join("deck.apkg");
+        let cloze = Card::Cloze(Cloze {
+            text: "The cat walked over the {{c1::hill}}".to_string(),
+            back_extra: "a small mountain".to_string(),
+            tags: vec!["moby_dick".to_string()],
+            context: None,
+            raw_note: None,
+            extra: None,
+        });
+        let cards = [cloze, basic("to move on foot", "walk")];
+        write_apkg(&cards, "Kindle::Moby Dick", &apkg_path).unwrap();
+
+        let mut archive = ZipArchive::new(fs::File::open(&apkg_path).unwrap()).unwrap();
+        let mut names: Vec<&str> = archive.file_names().collect();
+        names.sort();
+        assert_eq!(names, ["collection.anki2", "media", "meta"]);
+        let collection_path = dir.join("collection.anki2");
+        io::copy(
+            &mut archive.by_name("collection.anki2").unwrap(),
+            &mut fs::File::create(&collection_path).unwrap(),
+        )
+        .unwrap();
+
+        let db = Connection::open(&collection_path).unwrap();
+        let count = |sql: &str| db.query_row(sql, [], |row| row.get::<_, i64>(0)).unwrap();
+        assert_eq!(count("SELECT COUNT(*) FROM notes"), 2);
+        assert_eq!(count("SELECT COUNT(*) FROM cards"), 2);
+        // the cloze and the basic card each have their own note type
+        assert_eq!(count("SELECT COUNT(DISTINCT mid) FROM notes"), 2);
+        let decks: String = db
+            .query_row("SELECT decks FROM col", [], |row| row.get(0))
+            .unwrap();
+        assert!(decks.contains("Kindle::Moby Dick"));
+    }
+
+    #[test]
+    fn card_ids_stay_unique_past_a_hundred_clozes() {
+        let dir = temp_dir("apkg-many-clozes");
+        let apkg_path = dir.join("deck.apkg");
+        let cloze = |text: &str| {
+            Card::Cloze(Cloze {
+                text: text.to_string(),
+                back_extra: String::new(),
+                tags: Vec::new(),
+                context: None,
+                raw_note: None,
+                extra: None,
+            })
+        };
+        let cards = [
+            cloze("The {{c1::cat}} walked over the {{c101::hill}}"),
+            cloze("The {{c1::dog}} ran"),
+            cloze("The {{c2::bird}} flew"),
+        ];
+        write_apkg(&cards, "Kindle", &apkg_path).unwrap();
+        assert_eq!(notes(&apkg_path).len(), 4);
+    }
+}
@@ -0,0 +1,137 @@
+//! `--batch` runs several conversions in one invocation, e.g. one per reader
+//! of a shared kindle. Jobs are listed in a JSON file,
+//!
+//! ```json
+//! [
+//!     { "input": "andy/My Clippings.txt", "output": "andy", "deck": "Andy::Kindle" },
+//!     { "input": "sam/My Clippings.txt", "output": "sam" }
+//! ]
+//! ```
+//!
+//! where relative paths are relative to the file. Each job runs like a
+//! separate invocation with `--out-dir output/out`, so its files end up in
+//! `output/out/`. Every other flag applies to all of the jobs.
+//!
+//! A job's `deck` replaces `--deck` when its cards are packaged or pushed, and
+//! is put on the cards it makes from clippings as a `deck::Andy::Kindle` tag.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Error};
+use clap::ArgMatches;
+use log::{error, info};
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::convert_config_to_finished_app;
+
+#[derive(Debug, Deserialize)]
+pub struct Job {
+    pub input: PathBuf,
+    /// directory the job's `out/` directory is made in
+    pub output: PathBuf,
+    /// replaces --deck for this job, and tags its cards `deck::<deck>`
+    #[serde(default)]
+    pub deck: Option<String>,
+}
+
+/// Reads the jobs in `batch_path`, with their paths made relative to the
+/// current directory
+pub fn read_jobs(batch_path: &Path) -> Result<Vec<Job>, Error> {
+    let batch = fs::read_to_string(batch_path)
+        .with_context(|| format!("unable to read batch file {batch_path:?}"))?;
+    let mut jobs: Vec<Job> = serde_json::from_str(&batch)
+        .with_context(|| format!("{batch_path:?} is not a JSON list of jobs"))?;
+    let base = batch_path.parent().unwrap_or(Path::new(""));
+    for job in &mut jobs {
+        job.input = base.join(&job.input);
+        job.output = base.join(&job.output);
+    }
+    Ok(jobs)
+}
+
+/// Runs every job in `batch_path`, carrying on past failed ones, and fails
+/// at the end if any of them did
+pub fn run(batch_path: &Path, matches: &ArgMatches) -> Result<(), Error> {
+    let jobs = read_jobs(batch_path)?;
+
+    let mut failed = 0;
+    for (index, job) in jobs.iter().enumerate() {
+        let number = index + 1;
+        info!("job {number}: {:?} into {:?}", job.input, job.output);
+        match run_job(job, matches) {
+            Ok(()) => info!("job {number}: done"),
+            Err(error) => {
+                error!("job {number}: failed: {error:#}");
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!("{failed} of {} jobs failed", jobs.len());
+    }
+    info!("ran {} jobs", jobs.len());
+    Ok(())
+}
+
+fn run_job(job: &Job, matches: &ArgMatches) -> Result<(), Error> {
+    let out_dir = job.output.join("out");
+    let config = Config::for_job(
+        matches,
+        Some(&job.input),
+        job.deck.as_deref(),
+        Some(&out_dir),
+    )?;
+    convert_config_to_finished_app(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::tests::{cards, clipping, metadata, temp_dir};
+
+    #[test]
+    fn job_deck_tags_its_cards() {
+        let dir = temp_dir("batch-deck");
+        for reader in ["andy", "sam"] {
+            fs::create_dir_all(dir.join(reader)).unwrap();
+            fs::write(
+                dir.join(reader).join("My Clippings.txt"),
+                [
+                    clipping("Highlight", 10, "The cat walked over the hill"),
+                    clipping("Note", 11, "hill"),
+                ]
+                .concat(),
+            )
+            .unwrap();
+        }
+        let batch_path = dir.join("batch.json");
+        fs::write(
+            &batch_path,
+            r#"[
+                { "input": "andy/My Clippings.txt", "output": "andy", "deck": "Andy Kindle" },
+                { "input": "sam/My Clippings.txt", "output": "sam" }
+            ]"#,
+        )
+        .unwrap();
+        let matches = config::command()
+            .try_get_matches_from([
+                "anki-kindle-import",
+                "--batch",
+                batch_path.to_str().unwrap(),
+            ])
+            .unwrap();
+        run(&batch_path, &matches).unwrap();
+
+        let andy = metadata(&dir.join("andy/out"));
+        assert_eq!(cards(&andy).len(), 1);
+        assert!(cards(&andy)
+            .iter()
+            .all(|card| card.tags() == ["deck::Andy_Kindle"]));
+        let sam = metadata(&dir.join("sam/out"));
+        assert!(cards(&sam).iter().all(|card| card.tags().is_empty()));
+        assert_eq!(cards(&sam).len(), 1);
+    }
+}
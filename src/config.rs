@@ -1,37 +1,426 @@
-use std::{fs, path::PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    thread,
+};
 
-use anyhow::{Context, Error};
+use anyhow::{bail, Context, Error};
 use chrono::prelude::*;
 use chrono::serde::ts_seconds;
-use clap::{Arg, ArgAction, Command};
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command};
+use encoding_rs::{Encoding, UTF_8};
 use log::info;
 use serde::{Deserialize, Serialize};
 
+// built once per run, so the size of `Regular` doesn't matter
+#[allow(clippy::large_enum_variant)]
 pub enum Config {
     Regular {
         clippings_path: PathBuf,
         output_file_name: String,
-        date_after: Option<DateTime<Utc>>,
+        /// directory every other file is read from and written to, `out` by default
+        out_dir: PathBuf,
+        convert_options: ConvertOptions,
+        /// return an error if any warnings were raised during conversion
+        fail_on_warning: bool,
+        /// encoding the clippings file is written in
+        encoding: &'static Encoding,
+        /// encoding the markdown output is written in
+        output_encoding: OutputEncoding,
+        /// write every distinct term to `out/terms.txt`
+        list_terms: bool,
+        /// append the processed clippings to `out/archive.txt` after a successful run
+        archive: bool,
+        /// clippings files larger than this many bytes are refused
+        max_file_size: u64,
+        /// directory with recordings of terms, e.g. `hill.mp3`, to reference on card backs
+        media_dir: Option<PathBuf>,
+        /// only keep clippings from books that aren't in `out/seen-books.json`
+        new_books_only: bool,
+        /// skip books whose clippings are the same as in `out/book-hashes.json`
+        skip_unchanged_books: bool,
+        /// log the front and back of every card as it is written
+        echo_cards: bool,
+        /// only write the highlights to an annotatable file, see [crate::extract]
+        extract: bool,
+        /// only write reading statistics to `out/stats.json`, see [crate::stats]
+        stats: bool,
+        /// print the files that would be written instead of writing them
+        dry_run: bool,
+    },
+    /// Writes the markdown output again from the metadata of the run that
+    /// wrote it, e.g. after the output was lost
+    Regenerate {
+        output_file_name: String,
+        out_dir: PathBuf,
+        card_template: CardTemplate,
+        output_encoding: OutputEncoding,
+        dry_run: bool,
     },
     Validate {
         output_file_name: String,
+        out_dir: PathBuf,
+        back_spacing: usize,
+        /// fail if any card is still missing its definition or cloze
+        strict: bool,
+        card_template: CardTemplate,
+        /// what the finished cards are written as
+        format: CardFormat,
+        /// where the output's cloze numbering was allowed to start over
+        reset_cloze_per: ClozeReset,
+        dry_run: bool,
+    },
+    /// Turns the finished output into an `.apkg` that can be opened in Anki
+    Package {
+        output_file_name: String,
+        back_spacing: usize,
+        card_template: CardTemplate,
+        /// name of the deck the cards are added to
+        deck: String,
+        apkg_path: String,
+        /// add the cards that aren't in the package at `apkg_path` yet,
+        /// instead of writing a new one
+        append: bool,
+    },
+    /// Adds the finished output to a running Anki through AnkiConnect
+    Push {
+        output_file_name: String,
+        back_spacing: usize,
+        card_template: CardTemplate,
+        deck: String,
+        /// where AnkiConnect listens, `http://localhost:8765` by default
+        connect_url: String,
     },
 }
 
+/// Settings that change how clippings are turned into cards
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// number of blank lines (or `<br>`s) separating the sections of a card back
+    pub back_spacing: usize,
+    /// highlights with fewer words than this are not used as cloze sentences
+    pub min_cloze_words: usize,
+    /// collapse runs of whitespace in highlights into a single space
+    pub collapse_whitespace: bool,
+    /// drop highlights that don't have a note attached
+    pub notes_only: bool,
+    /// tag every card with `book::{Author}::{Title}`
+    pub book_tags: bool,
+    /// tag every card with the title of its book as a slug, see [crate::book_tag]
+    pub book_slug_tags: bool,
+    /// what kind of card a note line without any delimiter becomes
+    pub bare_term_as: BareTerm,
+    /// which occurrences of a term in its highlight are clozed
+    pub cloze_scope: ClozeScope,
+    /// split a single note line into several terms on this bullet marker
+    pub bullet_split: Option<String>,
+    /// tag cards with how long ago their clipping was made
+    pub age_based_scheduling: bool,
+    /// make one basic card from all the lines of a note, instead of one card per line
+    pub note_as_single_card: bool,
+    /// placeholder put on card fronts that would otherwise be empty
+    pub default_front: Option<String>,
+    /// clippings from books whose title contains any of these are dropped
+    pub exclude_books: Vec<String>,
+    /// keep the untouched highlight on cloze cards, for a separate `Context` field
+    pub cloze_context_field: bool,
+    /// turn `term: definition` note lines into basic cards
+    pub colon_defs: bool,
+    /// only keep this many words of context on each side of a cloze
+    pub cloze_window: Option<usize>,
+    /// tag added to cards made from a highlight of each color, e.g. `pink` -> `important`
+    pub color_tags: BTreeMap<String, String>,
+    /// also make a basic card asking for each clozed word from its extra content
+    pub cloze_reverse: bool,
+    /// tag cards with the year and month their clipping was made
+    pub read_date_tags: bool,
+    /// namespace put in front of every tag, e.g. `kindle::`
+    pub tag_prefix: String,
+    /// tags put on every card as they are, e.g. `kindle` and `2024-q1`
+    pub tags: Vec<String>,
+    /// the first line of a note is added to the back of the cards from its other lines
+    pub first_line_shared: bool,
+    /// clozes from consecutive lines of a note are numbered `c1`, `c2`, ... in one card
+    pub merge_cloze_lines: bool,
+    /// end the back of each card with the kindle location of its highlight, e.g. `(loc. 1234)`
+    pub cite_location: bool,
+    /// separates the terms and extra content of a cloze note line, ` .. ` by default
+    pub cloze_sep: String,
+    /// separates the term and description of a basic note line, ` ... ` by default
+    pub extra_sep: String,
+    /// chrono format of clipping dates, for kindles in a language that isn't recognized
+    pub date_format: Option<String>,
+    /// definitions by lowercased term, put on the front of basic cards that have none
+    pub definitions: BTreeMap<String, String>,
+    /// keep the note lines each card was made from, in the metadata
+    pub keep_raw: bool,
+    /// program every cloze term is piped through before it is looked up in
+    /// its sentence, see [crate::transform_term]
+    pub transform_cmd: Option<PathBuf>,
+    /// drop clippings made exactly at the start date too, see [crate::before_start]
+    pub start_date_exclusive: bool,
+    /// the full text of the book, with its whitespace collapsed, that the
+    /// sentences around each highlight are taken from, see [crate::book_context]
+    pub book_text: Option<String>,
+}
+
+/// What [crate::convert] makes of the clippings. Files the CLI reads, e.g. the
+/// books seen by previous runs for `--new-books-only`, are passed in as their
+/// contents instead.
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOptions {
+    /// what kind of file the clippings are read from
+    pub source: Source,
+    pub date_after: Option<DateTime<Utc>>,
+    pub parse_options: ParseOptions,
+    /// when set, clippings are shuffled with an rng seeded from this value
+    pub shuffle_seed: Option<u64>,
+    /// write the newest clippings first instead of in file order
+    pub reverse_order: bool,
+    /// where cloze numbering starts over at `c1`
+    pub reset_cloze_per: ClozeReset,
+    /// return an error if any cloze term isn't found in its highlight
+    pub require_cloze_match: bool,
+    /// return an error if fewer cards were made
+    pub require_min_cards: Option<usize>,
+    /// warn about cards with the same front and back as an earlier card
+    pub warn_duplicate_cards: bool,
+    /// drop cards with the same front and back as an earlier card
+    pub dedupe_cards: bool,
+    /// clippings from these books are dropped, e.g. the books seen by previous
+    /// runs for `--new-books-only`
+    pub skip_books: BTreeSet<String>,
+    /// the hashes a previous run recorded for `--skip-unchanged-books`. When
+    /// set, books whose clippings still hash the same are dropped, see
+    /// [crate::book_hashes]
+    pub previous_book_hashes: Option<BTreeMap<String, String>>,
+    /// file names of recordings of terms, e.g. `hill.mp3`, to reference on
+    /// card backs, see [crate::attach_sounds]
+    pub sounds: BTreeSet<String>,
+    /// layout of each card in the markdown output
+    pub card_template: CardTemplate,
+}
+
+/// Where the clippings come from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Source {
+    /// a kindle `My Clippings.txt`
+    #[default]
+    Kindle,
+    /// a JSON export from Readwise
+    Readwise,
+    /// a file written by `--extract`, with terms added under its highlights
+    Extract,
+    /// the annotations database of the Kindle app for macOS
+    KindleMacDb,
+}
+
+/// What `--validate` writes the finished cards as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardFormat {
+    /// `out/output.json`, with the dates the cards were made from
+    Json,
+    /// `out/output-basic.tsv` and `out/output-cloze.tsv`, for Anki's plain
+    /// text import
+    Tsv,
+}
+
+/// Layout of a card between its `----` lines in the markdown output, e.g.
+///
+/// ```txt
+/// <!-- from kindle -->
+/// {front}
+/// |-
+/// {back}
+/// ```
+///
+/// `{front}`, `|-` and `{back}` have to be on lines of their own, in that order.
+/// The lines around them are written as they are, and skipped when the output
+/// is read back.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CardTemplate {
+    before: Vec<String>,
+    after: Vec<String>,
+}
+
+impl CardTemplate {
+    pub fn parse(template: &str) -> Result<Self, Error> {
+        let lines: Vec<&str> = template.lines().collect();
+        let front = lines
+            .iter()
+            .position(|line| line.trim() == "{front}")
+            .context("card template has no `{front}` line")?;
+        if lines.get(front + 1).map(|line| line.trim()) != Some(crate::format::SECTION)
+            || lines.get(front + 2).map(|line| line.trim()) != Some("{back}")
+        {
+            bail!("card template needs `{{front}}`, `|-` and `{{back}}` on consecutive lines");
+        }
+        let before = &lines[..front];
+        let after = &lines[front + 3..];
+        // these would be read back as part of the card, or end it early
+        if let Some(line) = before.iter().chain(after).find(|line| {
+            crate::format::DELIMITERS
+                .iter()
+                .any(|marker| line.starts_with(marker))
+        }) {
+            bail!("card template line `{line}` starts with a marker of the output format");
+        }
+        Ok(Self {
+            before: before.iter().map(|line| line.to_string()).collect(),
+            after: after.iter().map(|line| line.to_string()).collect(),
+        })
+    }
+
+    /// The lines of a card, each ending in a newline
+    pub fn render(&self, front: &str, back: &str) -> String {
+        let mut out = String::new();
+        for line in &self.before {
+            out.push_str(&format!("{line}\n"));
+        }
+        out.push_str(&format!("{front}\n{}\n{back}\n", crate::format::SECTION));
+        for line in &self.after {
+            out.push_str(&format!("{line}\n"));
+        }
+        out
+    }
+
+    /// Drops the template's own lines from the lines of a card read back, if
+    /// they are still there
+    pub fn strip(&self, lines: &mut Vec<&str>) {
+        if lines.len() >= self.before.len() + self.after.len()
+            && lines.ends_with(&self.after.iter().map(String::as_str).collect::<Vec<_>>())
+        {
+            lines.truncate(lines.len() - self.after.len());
+        }
+        if lines.starts_with(&self.before.iter().map(String::as_str).collect::<Vec<_>>()) {
+            lines.drain(..self.before.len());
+        }
+    }
+}
+
+/// The card type used for a note line that is just a term
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BareTerm {
+    /// cloze the term in the preceding highlight
+    Cloze,
+    /// put the term and the preceding highlight on the back of a basic card
+    Basic,
+}
+
+/// Which occurrences of a term in its highlight are clozed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClozeScope {
+    /// only the first one
+    First,
+    /// every one, as blanks with the same number that Anki shows together
+    #[default]
+    All,
+}
+
+/// Where cloze numbering starts over at `c1`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClozeReset {
+    /// every card numbers its own clozes from `c1`
+    #[default]
+    Card,
+    /// the cards of a note continue each other's numbering
+    Note,
+    /// the cards of a book continue each other's numbering
+    Book,
+    /// numbering continues across the whole output
+    Never,
+}
+
+/// Encoding of the markdown output, for import tools that don't read UTF-8
+#[derive(Debug, Clone, Copy)]
+pub struct OutputEncoding {
+    pub encoding: &'static Encoding,
+    /// fail on characters the encoding can't represent, instead of writing
+    /// them as html character references like `&#8220;`
+    pub strict: bool,
+}
+
+impl Default for OutputEncoding {
+    fn default() -> Self {
+        Self {
+            encoding: UTF_8,
+            strict: false,
+        }
+    }
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            back_spacing: 2,
+            min_cloze_words: 0,
+            collapse_whitespace: false,
+            notes_only: false,
+            book_tags: false,
+            book_slug_tags: false,
+            bare_term_as: BareTerm::Cloze,
+            cloze_scope: ClozeScope::All,
+            bullet_split: None,
+            age_based_scheduling: false,
+            note_as_single_card: false,
+            default_front: None,
+            exclude_books: Vec::new(),
+            cloze_context_field: false,
+            colon_defs: false,
+            cloze_window: None,
+            color_tags: BTreeMap::new(),
+            cloze_reverse: false,
+            read_date_tags: false,
+            tag_prefix: String::new(),
+            tags: Vec::new(),
+            first_line_shared: false,
+            merge_cloze_lines: false,
+            cite_location: false,
+            cloze_sep: " .. ".to_string(),
+            extra_sep: " ... ".to_string(),
+            date_format: None,
+            definitions: BTreeMap::new(),
+            keep_raw: false,
+            transform_cmd: None,
+            start_date_exclusive: false,
+            book_text: None,
+        }
+    }
+}
+
+/// How log lines are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// the default `env_logger` output
+    Text,
+    /// one JSON object per line, for log aggregation
+    Json,
+}
+
+impl LogFormat {
+    /// Reads the log format from the parsed arguments. This has to happen
+    /// before the logger is initialized, which is before [Config::new].
+    pub fn from_matches(matches: &ArgMatches) -> LogFormat {
+        match matches.get_one::<String>("log-format").map(String::as_str) {
+            Some("json") => LogFormat::Json,
+            _ => LogFormat::Text,
+        }
+    }
+}
+
+/// `out/last-date.json`, the date of the newest clipping of the last run,
+/// which the next run starts from
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct LastDate {
     #[serde(with = "ts_seconds")]
-    date: DateTime<Utc>,
+    pub date: DateTime<Utc>,
 }
 
-impl Config {
-    pub fn new() -> Result<Config, Error> {
-        let output_file_name = String::from("out/output.md");
-        // ensure dir
-        std::fs::create_dir_all("out")?;
-
-        // create clap app
-        let matches = Command::new("anki-kindle-import")
+/// Builds the clap app
+pub fn command() -> Command {
+    Command::new("anki-kindle-import")
         .version("0.1.0")
         .author("Andy Li <SpicyRicecaker@gmail.com>")
         .about("Turns kindle clippings into structure easily parsible by Anki")
@@ -41,71 +430,958 @@ impl Config {
                 .action(ArgAction::Count)
                 .help("check the output file to make sure there is one highlight per one note, then compiles it")
         )
+        .arg(Arg::new("validate-strict")
+                .long("validate-strict")
+                .action(ArgAction::SetTrue)
+                .help("validate, and also fail with a list of cards that are missing a definition or a valid cloze"))
+        .group(ArgGroup::new("validation")
+                .args(["validate", "validate-strict"])
+                .multiple(true))
+        .arg(Arg::new("format")
+                .long("format")
+                .action(ArgAction::Set)
+                .value_parser(["json", "tsv"])
+                .default_value("json")
+                .requires("validation")
+                .help("what --validate writes the finished cards as: `out/output.json`, or `out/output-basic.tsv` and `out/output-cloze.tsv` for Anki's File > Import"))
+        .arg(Arg::new("apkg")
+                .long("apkg")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["validate", "validate-strict"])
+                .help("turn the finished output into `out/output.apkg`, which can be opened in Anki. Needs the `apkg` feature"))
+        .arg(Arg::new("append-to")
+                .long("append-to")
+                .action(ArgAction::Set)
+                .conflicts_with_all(["validation", "apkg", "push", "dry-run"])
+                .help("add the cards of the finished output that aren't in this .apkg yet to it, keeping the scheduling of the cards already in it. Needs the `apkg` feature"))
+        .arg(Arg::new("deck")
+                .long("deck")
+                .action(ArgAction::Set)
+                .default_value("Kindle")
+                .help("name of the deck the cards in the .apkg or pushed to Anki are added to"))
+        .arg(Arg::new("push")
+                .long("push")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["validate", "validate-strict", "apkg"])
+                .help("add the finished output to a running Anki with the AnkiConnect add-on. Needs the `http` feature"))
+        .arg(Arg::new("connect-url")
+                .long("connect-url")
+                .action(ArgAction::Set)
+                .default_value("http://localhost:8765")
+                .help("where AnkiConnect listens, for --push"))
+        .arg(Arg::new("card-template")
+                .long("card-template")
+                .action(ArgAction::Set)
+                .help("path to a file with the layout of each card in the markdown output, with `{front}`, `|-` and `{back}` on lines of their own. Pass the same file to --validate"))
         .arg(Arg::new("start-date")
                 .short('d')
                 .long("start-date")
                 .action(ArgAction::Set)
                 // .takes_value(true)
                 .help("only include clippings from the start date, inclusive"))
+        .arg(Arg::new("start-date-exclusive")
+                .long("start-date-exclusive")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("ignore-last-date")
+                .help("leave out clippings made exactly at the --start-date. The clippings made at the date in `out/last-date.json`, the newest of the last run, are always left out"))
+        .arg(Arg::new("ignore-last-date")
+                .long("ignore-last-date")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("start-date")
+                .help("don't filter clippings by the date in `out/last-date.json`"))
         .arg(Arg::new("clipping-path")
                 .short('p')
                 .long("clipping-path")
+                .visible_alias("input")
                 .action(ArgAction::Set)
                 // .takes_value(true)
-                .help("the path to kindle clippings. By default points to where Calibre exports clippings. (check README.md)"))
-        .get_matches();
+                .help("the path (or, with the `http` feature, `http(s)://` url) to kindle clippings. By default points to where Calibre exports clippings. (check README.md)"))
+        .arg(Arg::new("extract")
+                .long("extract")
+                .action(ArgAction::SetTrue)
+                .help("instead of making cards, write the highlights to `out/extract.md` to add terms under them, for --build"))
+        .arg(Arg::new("stats")
+                .long("stats")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["extract", "validation", "apkg", "push"])
+                .help("instead of making cards, write reading statistics over the whole clippings history to `out/stats.json`: highlights per book and month, average highlight length, ..."))
+        .arg(Arg::new("out-dir")
+                .long("out-dir")
+                .action(ArgAction::Set)
+                .default_value("out")
+                .help("directory every file of a run is read from and written to, e.g. a job-specific one in CI"))
+        .arg(Arg::new("from-metadata")
+                .long("from-metadata")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["validation", "apkg", "push", "stats", "extract", "build", "batch"])
+                .help("write `out/output.md` again from `out/output-metadata.json`, e.g. after losing it, instead of reading clippings. Pass the same --card-template"))
+        .arg(Arg::new("build")
+                .long("build")
+                .action(ArgAction::Set)
+                .conflicts_with_all(["clipping-path", "source", "extract"])
+                .help("make cards from a file written by --extract, after terms were added under its highlights"))
+        .arg(Arg::new("wait-for-device")
+                .long("wait-for-device")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u64))
+                .help("when looking for the clippings on a kindle, keep looking for up to this many seconds while it is being mounted"))
+        .arg(Arg::new("batch")
+                .long("batch")
+                .action(ArgAction::Set)
+                .conflicts_with_all(["clipping-path", "build", "dry-run"])
+                .help("path to a JSON list of `{ \"input\", \"output\", \"deck\" }` jobs, each run like a separate invocation with its own input, deck and output directory. Cards made by a job with a deck are tagged `deck::<deck>`"))
+        .arg(Arg::new("dry-run")
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["apkg", "push"])
+                .help("print the files that would be written, and the backups that would be made, instead of touching the filesystem"))
+        .arg(Arg::new("calibre-root")
+                .long("calibre-root")
+                .action(ArgAction::Set)
+                .help("calibre library to look for clippings fetched from a kindle in, when no kindle is mounted. Defaults to $CALIBRE_LIBRARY, then `~/Calibre Library`"))
+        .arg(Arg::new("source")
+                .long("source")
+                .action(ArgAction::Set)
+                .value_parser(["kindle", "readwise", "kindle-mac-db"])
+                .default_value("kindle")
+                .help("read kindle clippings, highlights exported from Readwise as JSON, or the annotations of the Kindle app for macOS, which needs the `kindle-mac` feature"))
+        .arg(Arg::new("max-file-size")
+                .long("max-file-size")
+                .action(ArgAction::Set)
+                .value_parser(megabytes)
+                .default_value("100")
+                .help("refuse to read clippings files larger than this many megabytes"))
+        .arg(Arg::new("back-spacing")
+                .long("back-spacing")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("2")
+                .help("number of blank lines / `<br>`s separating the term, sentence, and extra content on the back of a card"))
+        .arg(Arg::new("min-cloze-words")
+                .long("min-cloze-words")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("0")
+                .help("skip cloze notes whose highlight has fewer than this many words"))
+        .arg(Arg::new("log-format")
+                .long("log-format")
+                .action(ArgAction::Set)
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .help("print logs as human-readable text or as one JSON object per line"))
+        .arg(Arg::new("shuffle")
+                .long("shuffle")
+                .action(ArgAction::SetTrue)
+                .help("shuffle the order of the generated cards"))
+        .arg(Arg::new("seed")
+                .long("seed")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u64))
+                .requires("shuffle")
+                .help("seed used by --shuffle, so the same order can be reproduced. Random if not given"))
+        .arg(Arg::new("reverse-order")
+                .long("reverse-order")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("shuffle")
+                .help("output the newest clippings first, to review the most recent reading first"))
+        .arg(Arg::new("reset-cloze-per")
+                .long("reset-cloze-per")
+                .action(ArgAction::Set)
+                .value_parser(["card", "note", "book", "never"])
+                .default_value("card")
+                .help("where cloze numbering starts over at `c1`. Anything but `card` keeps counting across cards, e.g. `c3` on the card after one with `c1` and `c2`. Pass it to --validate too, which otherwise expects every card to start at `c1`"))
+        .arg(Arg::new("collapse-whitespace")
+                .long("collapse-whitespace")
+                .action(ArgAction::SetTrue)
+                .help("collapse doubled spaces and tabs in highlights into a single space"))
+        .arg(Arg::new("fail-on-warning")
+                .long("fail-on-warning")
+                .action(ArgAction::SetTrue)
+                .help("exit with an error if any warnings (unmatched cloze, empty note, ...) occurred"))
+        .arg(Arg::new("require-cloze-match")
+                .long("require-cloze-match")
+                .action(ArgAction::SetTrue)
+                .help("exit with an error, before writing anything, if a cloze term isn't in its highlight even by its stem, instead of making it a card to review"))
+        .arg(Arg::new("require-min-cards")
+                .long("require-min-cards")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .help("exit with an error, before writing anything, if fewer than this many cards were made, e.g. because the wrong file was read or the filters left nothing"))
+        .arg(Arg::new("notes-only")
+                .long("notes-only")
+                .action(ArgAction::SetTrue)
+                .help("only output clippings with notes, dropping standalone highlights"))
+        .arg(Arg::new("encoding")
+                .long("encoding")
+                .action(ArgAction::Set)
+                .default_value("utf-8")
+                .help("encoding of the clippings file, e.g. `windows-1252` for some older kindles"))
+        .arg(Arg::new("output-encoding")
+                .long("output-encoding")
+                .action(ArgAction::Set)
+                .default_value("utf-8")
+                .help("encoding of the markdown output, e.g. `windows-1252` for import tools that don't read UTF-8. Characters it can't represent are written as html character references, which Anki shows as the character. The metadata stays UTF-8, and --validate only reads UTF-8 output"))
+        .arg(Arg::new("strict-output-encoding")
+                .long("strict-output-encoding")
+                .action(ArgAction::SetTrue)
+                .help("fail instead of writing html character references for characters the --output-encoding can't represent"))
+        .arg(Arg::new("list-terms")
+                .long("list-terms")
+                .action(ArgAction::SetTrue)
+                .help("also write every distinct term from the notes, sorted, to `out/terms.txt`"))
+        .arg(Arg::new("archive")
+                .long("archive")
+                .action(ArgAction::SetTrue)
+                .help("after a successful run, append the processed clippings to `out/archive.txt`. The clippings file itself is left untouched"))
+        .arg(Arg::new("book-tags")
+                .long("book-tags")
+                .action(ArgAction::SetTrue)
+                .help("tag every card with `book::{Author}::{Title}` so Anki groups them by author, then title"))
+        .arg(Arg::new("book-slug-tags")
+                .long("book-slug-tags")
+                .action(ArgAction::SetTrue)
+                .help("tag every card with the title of its book, lowercased and without punctuation, e.g. `crime_punishment_vol_1` for `Crime & Punishment: Vol. 1`"))
+        .arg(Arg::new("bare-term-as")
+                .long("bare-term-as")
+                .action(ArgAction::Set)
+                .value_parser(["cloze", "basic"])
+                .default_value("cloze")
+                .help("the card type created from a note line with no cloze or extra separator"))
+        .arg(Arg::new("cloze-scope")
+                .long("cloze-scope")
+                .action(ArgAction::Set)
+                .value_parser(["all", "first"])
+                .default_value("all")
+                .help("whether every occurrence of a term in its highlight is clozed, as blanks Anki shows together, or only the first"))
+        .arg(Arg::new("date-format")
+                .long("date-format")
+                .action(ArgAction::Set)
+                .help("chrono format of the clipping dates, e.g. '%d %B %Y %H:%M:%S', for kindles in a language that isn't recognized. It's matched after the weekday is dropped and known month names are translated to English"))
+        .arg(Arg::new("wiktionary")
+                .long("wiktionary")
+                .action(ArgAction::Set)
+                .help("path to an offline Wiktionary extract in the JSON lines format of kaikki.org, to put the definition of a term on the front of its basic card"))
+        .arg(Arg::new("cloze-sep")
+                .long("cloze-sep")
+                .action(ArgAction::Set)
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .default_value(" .. ")
+                .help("separates the terms and extra content of a cloze note line. Include the spaces around it, e.g. ' >> '"))
+        .arg(Arg::new("extra-sep")
+                .long("extra-sep")
+                .action(ArgAction::Set)
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .default_value(" ... ")
+                .help("separates the term and description of a basic note line. Include the spaces around it, e.g. ' :: '"))
+        .arg(Arg::new("bullet-split")
+                .long("bullet-split")
+                .action(ArgAction::Set)
+                .help("split note lines written as a list, e.g. `- term1 - term2` with `--bullet-split -`, into one card per term"))
+        .arg(Arg::new("age-based-scheduling")
+                .long("age-based-scheduling")
+                .action(ArgAction::SetTrue)
+                .help("tag cards with the age of their clipping (`age::week`, `age::month`, `age::year`, `age::older`) so older material can be scheduled sooner"))
+        .arg(Arg::new("read-date-tags")
+                .long("read-date-tags")
+                .action(ArgAction::SetTrue)
+                .help("tag cards with the year and month their clipping was made, e.g. `read::2024` and `read::2024-05`"))
+        .arg(Arg::new("tag-prefix")
+                .long("tag-prefix")
+                .action(ArgAction::Set)
+                .default_value("")
+                .help("namespace put in front of every tag, e.g. `kindle::` to get `kindle::book::Author::Title`, so tags don't collide with your own"))
+        .arg(Arg::new("tag")
+                .long("tag")
+                .action(ArgAction::Append)
+                .help("tag every card of the run, e.g. `--tag kindle --tag 2024-q1`. Unlike the other tags it isn't put behind --tag-prefix. Can be given multiple times"))
+        .arg(Arg::new("merge-cloze-lines")
+                .long("merge-cloze-lines")
+                .action(ArgAction::SetTrue)
+                .help("put the clozes from consecutive lines of a note in one cloze note, numbered `c1`, `c2`, ..., instead of one note per line"))
+        .arg(Arg::new("first-line-shared")
+                .long("first-line-shared")
+                .action(ArgAction::SetTrue)
+                .help("treat the first line of a note as a comment shared by the terms on the lines below it, and add it to the back of each of their cards"))
+        .arg(Arg::new("note-as-single-card")
+                .long("note-as-single-card")
+                .action(ArgAction::SetTrue)
+                .help("turn each whole note into one basic card, with the highlight as context, instead of one card per line"))
+        .arg(Arg::new("default-front")
+                .long("default-front")
+                .action(ArgAction::Set)
+                .help("text put on card fronts that would otherwise be empty, e.g. `TODO: define`, so unfinished cards are easy to find"))
+        .arg(Arg::new("exclude-book")
+                .long("exclude-book")
+                .action(ArgAction::Append)
+                .help("skip clippings from books whose title contains this text. Can be given multiple times"))
+        .arg(Arg::new("cloze-context-field")
+                .long("cloze-context-field")
+                .action(ArgAction::SetTrue)
+                .help("put the untouched highlight in a separate `context` field of cloze cards, for note types with a Context field"))
+        .arg(Arg::new("colon-defs")
+                .long("colon-defs")
+                .action(ArgAction::SetTrue)
+                .help("turn `term: definition` note lines into basic cards, with the definition on the front"))
+        .arg(Arg::new("cloze-window")
+                .long("cloze-window")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .help("only keep this many words on each side of the clozed term, for long highlights"))
+        .arg(Arg::new("cloze-reverse")
+                .long("cloze-reverse")
+                .action(ArgAction::SetTrue)
+                .help("for every cloze with extra content, also make a basic card with the extra content on the front and the clozed word and sentence on the back"))
+        .arg(Arg::new("cite-location")
+                .long("cite-location")
+                .action(ArgAction::SetTrue)
+                .help("end the back of each card with the kindle location of its highlight, e.g. `(loc. 1234)`"))
+        .arg(Arg::new("keep-raw")
+                .long("keep-raw")
+                .action(ArgAction::SetTrue)
+                .help("keep the note lines each card was made from as `raw_note` in `out/output-metadata.json`, to see why a card came out the way it did"))
+        .arg(Arg::new("transform-cmd")
+                .long("transform-cmd")
+                .action(ArgAction::Set)
+                .help("program every cloze term is piped through before it is clozed, e.g. a script stripping articles. It reads the term on stdin and writes the new one to stdout. If it fails, the term is kept as it is"))
+        .arg(Arg::new("book-text")
+                .long("book-text")
+                .action(ArgAction::Set)
+                .help("plain text of the book the clippings are from, e.g. extracted from its epub. The sentences before and after each highlight are found in it and added to the back of its cloze cards"))
+        .arg(Arg::new("echo-cards")
+                .long("echo-cards")
+                .action(ArgAction::SetTrue)
+                .help("log the front and back of every card as it is written, for a quick look without opening the output"))
+        .arg(Arg::new("new-books-only")
+                .long("new-books-only")
+                .action(ArgAction::SetTrue)
+                .help("only make cards from books that no earlier run with this flag made cards from, as recorded in `out/seen-books.json`"))
+        .arg(Arg::new("skip-unchanged-books")
+                .long("skip-unchanged-books")
+                .action(ArgAction::SetTrue)
+                .help("skip books whose clippings haven't changed since the last run with this flag, recorded in `out/book-hashes.json`, so re-runs only make cards for books that did"))
+        .arg(Arg::new("warn-duplicate-cards")
+                .long("warn-duplicate-cards")
+                .action(ArgAction::SetTrue)
+                .help("warn about cards with the same front and back as an earlier one, e.g. the same word clozed in two identical highlights"))
+        .arg(Arg::new("dedupe-cards")
+                .long("dedupe-cards")
+                .action(ArgAction::SetTrue)
+                .help("like --warn-duplicate-cards, but also drop the duplicates, keeping the first card"))
+        .arg(Arg::new("media-dir")
+                .long("media-dir")
+                .action(ArgAction::Set)
+                .help("directory with recordings named after terms, e.g. `hill.mp3`. Cards with a recording get a `[sound:hill.mp3]` on the back"))
+        .arg(Arg::new("color-tags")
+                .long("color-tags")
+                .action(ArgAction::Set)
+                .help("path to a JSON file mapping highlight colors to tags, e.g. `{ \"pink\": \"important\" }`"))
+}
+
+impl Config {
+    pub fn new(matches: &ArgMatches) -> Result<Config, Error> {
+        Config::for_job(matches, None, None, None)
+    }
+
+    /// The config of one job of a `--batch`, whose input, deck and output
+    /// directory replace the ones passed on the command line. Cards made from
+    /// clippings are tagged `deck::<deck>` with the job's deck.
+    pub fn for_job(
+        matches: &ArgMatches,
+        input: Option<&Path>,
+        job_deck: Option<&str>,
+        out_dir: Option<&Path>,
+    ) -> Result<Config, Error> {
+        let deck = job_deck
+            .map(str::to_string)
+            .unwrap_or_else(|| matches.get_one::<String>("deck").unwrap().clone());
+        let out_dir = match out_dir {
+            Some(out_dir) => out_dir.to_path_buf(),
+            None => PathBuf::from(matches.get_one::<String>("out-dir").unwrap()),
+        };
+        let output_file_name = out_dir.join("output.md").to_string_lossy().to_string();
+        let dry_run = matches.get_flag("dry-run");
+        // ensure dir
+        if !dry_run {
+            std::fs::create_dir_all(&out_dir)
+                .with_context(|| format!("unable to create {out_dir:?}"))?;
+        }
+
+        let back_spacing = *matches.get_one::<usize>("back-spacing").unwrap();
+        let card_template = match matches.get_one::<String>("card-template") {
+            Some(path) => {
+                let template = fs::read_to_string(path)
+                    .with_context(|| format!("unable to read card template {path:?}"))?;
+                CardTemplate::parse(&template)
+                    .with_context(|| format!("invalid card template {path:?}"))?
+            }
+            None => CardTemplate::default(),
+        };
+
+        if matches.get_flag("push") {
+            return Ok(Config::Push {
+                output_file_name,
+                back_spacing,
+                deck,
+                connect_url: matches.get_one::<String>("connect-url").unwrap().clone(),
+                card_template,
+            });
+        }
+
+        let append_to = matches.get_one::<String>("append-to");
+        if matches.get_flag("apkg") || append_to.is_some() {
+            return Ok(Config::Package {
+                output_file_name,
+                back_spacing,
+                deck,
+                apkg_path: match append_to {
+                    Some(apkg_path) => apkg_path.clone(),
+                    None => out_dir.join("output.apkg").to_string_lossy().to_string(),
+                },
+                card_template,
+                append: append_to.is_some(),
+            });
+        }
+
+        if matches.get_flag("from-metadata") {
+            return Ok(Config::Regenerate {
+                output_file_name,
+                out_dir,
+                card_template,
+                output_encoding: output_encoding(matches)?,
+                dry_run,
+            });
+        }
 
         // check if we should validate, and continue on with the rest of the program
-        if matches.get_count("validate") > 0 {
-            Ok(Config::Validate { output_file_name })
+        if matches.get_count("validate") > 0 || matches.get_flag("validate-strict") {
+            Ok(Config::Validate {
+                output_file_name,
+                out_dir,
+                back_spacing,
+                strict: matches.get_flag("validate-strict"),
+                card_template,
+                format: match matches.get_one::<String>("format").map(String::as_str) {
+                    Some("tsv") => CardFormat::Tsv,
+                    _ => CardFormat::Json,
+                },
+                reset_cloze_per: cloze_reset(matches),
+                dry_run,
+            })
         } else {
+            let last_date_path = out_dir.join("last-date.json");
             // get optional argument if needed
-            let date_after = if let Some(date_string) = matches.get_one::<String>("start-date") {
-                Some(date_from_str(date_string)?)
-            // last-date.json is written by Anki, after last feed
-            // we probably need testing for this, because this is getting too complex
-            } else if let Ok(file) = fs::read_to_string("out/last-date.json") {
-                let last_date: LastDate = serde_json::from_str(&file)?;
-                Some(last_date.date)
+            // the date in last-date.json is that of the newest clipping already
+            // imported, so it is left out along with everything before it
+            let (date_after, after_last_date) = if let Some(date_string) =
+                matches.get_one::<String>("start-date")
+            {
+                (Some(date_from_str(date_string)?), false)
+            // last-date.json is written after every run that made cards
+            } else if matches.get_flag("ignore-last-date") || matches.get_flag("stats") {
+                info!("ignoring {last_date_path:?}, including clippings from any date");
+                (None, false)
+            } else if let Ok(file) = fs::read_to_string(&last_date_path) {
+                let last_date: LastDate = serde_json::from_str(&file)
+                    .with_context(|| format!("unable to read {last_date_path:?}"))?;
+                info!(
+                        "only including clippings after {}, the date in {last_date_path:?}. Pass --ignore-last-date to include everything",
+                        last_date.date
+                    );
+                (Some(last_date.date), true)
             } else {
-                None
+                (None, false)
             };
 
             // get clipping path & reading clipping
-            let clippings_path = if let Some(p) = matches.get_one::<String>("clipping-path") {
+            let clippings_path = if let Some(input) = input {
+                input.to_path_buf()
+            } else if let Some(p) = matches.get_one::<String>("build") {
+                PathBuf::from(p)
+            } else if let Some(p) = matches.get_one::<String>("clipping-path") {
                 PathBuf::from(p)
+            } else if matches.get_one::<String>("source").map(String::as_str)
+                == Some("kindle-mac-db")
+            {
+                kindle_mac_db_path()?
             } else {
                 // hardcoded scan for kindle directory
                 // this might be broken...I think `fetch annotations` from
                 // calibre refreshes this file or something, it may not be
                 // updated right away
-                let opt_1 = match std::env::consts::OS {
-                    "macos" => PathBuf::from("/Volumes/Kindle/documents/My Clippings.txt"),
-                    _ => {
-                        panic!("not implemented")
+                let calibre_root = matches
+                    .get_one::<String>("calibre-root")
+                    .map(PathBuf::from)
+                    .or_else(|| std::env::var_os("CALIBRE_LIBRARY").map(PathBuf::from))
+                    .or_else(|| dirs::home_dir().map(|home| home.join("Calibre Library")));
+
+                // a kindle that was just plugged in can take a moment to be mounted
+                let mut candidates = kindle_mount_paths();
+                if let Some(calibre_root) = calibre_root {
+                    candidates.extend(calibre_clippings_paths(&calibre_root));
+                }
+                let wait = Duration::from_secs(
+                    matches
+                        .get_one::<u64>("wait-for-device")
+                        .copied()
+                        .unwrap_or(0),
+                );
+                let started = Instant::now();
+                loop {
+                    if let Some(p) = newest_existing(&candidates) {
+                        break p;
+                    }
+                    if started.elapsed() >= wait {
+                        bail!("unable to find kindle clippings at any of {candidates:?}. Pass the path with --clipping-path, or wait for the kindle with --wait-for-device");
                     }
-                };
-                let mut opt_2 = dirs::home_dir().unwrap();
-                opt_2.push("/Calibre Library/Kindle/My Clippings (13)/My Clippings - Kindle.txt");
+                    thread::sleep(Duration::from_millis(500));
+                }
+            };
 
+            let shuffle_seed = if matches.get_flag("shuffle") {
+                let seed = matches
+                    .get_one::<u64>("seed")
+                    .copied()
+                    .unwrap_or_else(rand::random);
+                info!("shuffling cards with seed {seed}");
+                Some(seed)
+            } else {
+                None
+            };
+
+            let color_tags = match matches.get_one::<String>("color-tags") {
+                Some(path) => {
+                    let file = fs::read_to_string(path)
+                        .with_context(|| format!("unable to read color tags from `{path}`"))?;
+                    serde_json::from_str::<BTreeMap<String, String>>(&file)
+                        .with_context(|| format!("`{path}` is not a JSON object of color to tag"))?
+                        .into_iter()
+                        // colors are read lowercased from the clippings
+                        .map(|(color, tag)| (color.to_lowercase(), tag))
+                        .collect()
+                }
+                None => BTreeMap::new(),
+            };
 
-                [opt_1, opt_2].into_iter().find(|p| p.exists()).unwrap()
+            let definitions = match matches.get_one::<String>("wiktionary") {
+                Some(path) => crate::wiktionary::load(Path::new(path))?,
+                None => BTreeMap::new(),
+            };
+
+            let book_text = match matches.get_one::<String>("book-text") {
+                Some(path) => Some(
+                    fs::read_to_string(path)
+                        .with_context(|| format!("unable to read the book text from `{path}`"))?
+                        .split_whitespace()
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                ),
+                None => None,
+            };
+
+            let encoding_label = matches.get_one::<String>("encoding").unwrap();
+            let encoding = Encoding::for_label(encoding_label.as_bytes())
+                .with_context(|| format!("unknown encoding `{encoding_label}`"))?;
+
+            let convert_options = ConvertOptions {
+                source: match matches.get_one::<String>("source").map(String::as_str) {
+                    _ if matches.contains_id("build") => Source::Extract,
+                    Some("readwise") => Source::Readwise,
+                    Some("kindle-mac-db") => Source::KindleMacDb,
+                    _ => Source::Kindle,
+                },
+                date_after,
+                parse_options: ParseOptions {
+                    back_spacing,
+                    min_cloze_words: *matches.get_one::<usize>("min-cloze-words").unwrap(),
+                    collapse_whitespace: matches.get_flag("collapse-whitespace"),
+                    notes_only: matches.get_flag("notes-only"),
+                    book_tags: matches.get_flag("book-tags"),
+                    book_slug_tags: matches.get_flag("book-slug-tags"),
+                    bare_term_as: match matches
+                        .get_one::<String>("bare-term-as")
+                        .map(String::as_str)
+                    {
+                        Some("basic") => BareTerm::Basic,
+                        _ => BareTerm::Cloze,
+                    },
+                    cloze_scope: match matches.get_one::<String>("cloze-scope").map(String::as_str)
+                    {
+                        Some("first") => ClozeScope::First,
+                        _ => ClozeScope::All,
+                    },
+                    bullet_split: matches.get_one::<String>("bullet-split").cloned(),
+                    age_based_scheduling: matches.get_flag("age-based-scheduling"),
+                    note_as_single_card: matches.get_flag("note-as-single-card"),
+                    default_front: matches.get_one::<String>("default-front").cloned(),
+                    exclude_books: matches
+                        .get_many::<String>("exclude-book")
+                        .unwrap_or_default()
+                        .cloned()
+                        .collect(),
+                    cloze_context_field: matches.get_flag("cloze-context-field"),
+                    colon_defs: matches.get_flag("colon-defs"),
+                    cloze_window: matches.get_one::<usize>("cloze-window").copied(),
+                    color_tags,
+                    cloze_reverse: matches.get_flag("cloze-reverse"),
+                    read_date_tags: matches.get_flag("read-date-tags"),
+                    merge_cloze_lines: matches.get_flag("merge-cloze-lines"),
+                    cite_location: matches.get_flag("cite-location"),
+                    cloze_sep: matches.get_one::<String>("cloze-sep").unwrap().clone(),
+                    extra_sep: matches.get_one::<String>("extra-sep").unwrap().clone(),
+                    date_format: matches.get_one::<String>("date-format").cloned(),
+                    definitions,
+                    keep_raw: matches.get_flag("keep-raw"),
+                    transform_cmd: matches
+                        .get_one::<String>("transform-cmd")
+                        .map(PathBuf::from),
+                    start_date_exclusive: matches.get_flag("start-date-exclusive")
+                        || after_last_date,
+                    book_text,
+                    first_line_shared: matches.get_flag("first-line-shared"),
+                    tag_prefix: matches
+                        .get_one::<String>("tag-prefix")
+                        .map(|prefix| prefix.replace(' ', "_"))
+                        .unwrap_or_default(),
+                    // the deck of a batch job isn't known until its cards are
+                    // packaged or pushed, so they are tagged with it meanwhile
+                    tags: matches
+                        .get_many::<String>("tag")
+                        .unwrap_or_default()
+                        .cloned()
+                        .chain(job_deck.map(|deck| format!("deck::{deck}")))
+                        .map(|tag| tag.replace(' ', "_"))
+                        .collect(),
+                },
+                shuffle_seed,
+                reverse_order: matches.get_flag("reverse-order"),
+                reset_cloze_per: cloze_reset(matches),
+                require_cloze_match: matches.get_flag("require-cloze-match"),
+                require_min_cards: matches.get_one::<usize>("require-min-cards").copied(),
+                warn_duplicate_cards: matches.get_flag("warn-duplicate-cards"),
+                dedupe_cards: matches.get_flag("dedupe-cards"),
+                card_template,
+                // read by the CLI when the files are needed
+                skip_books: BTreeSet::new(),
+                previous_book_hashes: None,
+                sounds: BTreeSet::new(),
             };
 
             Ok(Config::Regular {
                 output_file_name,
+                out_dir,
                 clippings_path,
-                date_after,
+                convert_options,
+                fail_on_warning: matches.get_flag("fail-on-warning"),
+                encoding,
+                output_encoding: output_encoding(matches)?,
+                list_terms: matches.get_flag("list-terms"),
+                archive: matches.get_flag("archive"),
+                extract: matches.get_flag("extract"),
+                stats: matches.get_flag("stats"),
+                dry_run,
+                echo_cards: matches.get_flag("echo-cards"),
+                new_books_only: matches.get_flag("new-books-only"),
+                skip_unchanged_books: matches.get_flag("skip-unchanged-books"),
+                media_dir: matches.get_one::<String>("media-dir").map(PathBuf::from),
+                max_file_size: *matches.get_one::<u64>("max-file-size").unwrap(),
             })
         }
     }
 }
 
+/// Parses `--max-file-size` in megabytes into bytes
+fn megabytes(value: &str) -> Result<u64, String> {
+    let megabytes: u64 = value
+        .parse()
+        .map_err(|_| format!("`{value}` isn't a whole number of megabytes"))?;
+    megabytes
+        .checked_mul(1024 * 1024)
+        .ok_or_else(|| format!("{megabytes} megabytes is too large"))
+}
+
+/// Where cloze numbering starts over, from `--reset-cloze-per`
+fn cloze_reset(matches: &ArgMatches) -> ClozeReset {
+    match matches
+        .get_one::<String>("reset-cloze-per")
+        .map(String::as_str)
+    {
+        Some("note") => ClozeReset::Note,
+        Some("book") => ClozeReset::Book,
+        Some("never") => ClozeReset::Never,
+        _ => ClozeReset::Card,
+    }
+}
+
+/// The encoding of the markdown output from `--output-encoding`
+fn output_encoding(matches: &ArgMatches) -> Result<OutputEncoding, Error> {
+    let label = matches.get_one::<String>("output-encoding").unwrap();
+    let encoding = Encoding::for_label(label.as_bytes())
+        .with_context(|| format!("unknown encoding `{label}`"))?;
+    // encoding_rs only decodes utf-16 and the like, writing them as UTF-8
+    if encoding.output_encoding() != encoding {
+        bail!("unable to write output as {}", encoding.name());
+    }
+    Ok(OutputEncoding {
+        encoding,
+        strict: matches.get_flag("strict-output-encoding"),
+    })
+}
+
+/// The most recently modified of the `candidates` that exist, since a kindle
+/// and a calibre copy of its clippings can both be around, and the older one
+/// is stale
+fn newest_existing(candidates: &[PathBuf]) -> Option<PathBuf> {
+    let existing: Vec<(&PathBuf, SystemTime)> = candidates
+        .iter()
+        .filter(|path| path.exists())
+        .map(|path| {
+            let modified = fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            (path, modified)
+        })
+        .collect();
+    // the first candidate wins a tie, like it did before mtimes were compared
+    let newest = existing
+        .iter()
+        .rev()
+        .max_by_key(|(_, modified)| *modified)
+        .map(|(path, _)| (*path).clone())?;
+    if existing.len() > 1 {
+        let found: Vec<&PathBuf> = existing.iter().map(|(path, _)| *path).collect();
+        info!("found clippings at {found:?}, using the most recently modified {newest:?}");
+    }
+    Some(newest)
+}
+
+/// Where the Kindle app for macOS keeps its annotations database
+fn kindle_mac_db_path() -> Result<PathBuf, Error> {
+    let path = dirs::home_dir()
+        .context("unable to find the home directory")?
+        .join("Library/Containers/com.amazon.Lassen/Data/Library/Protected/BookData.sqlite");
+    if !path.exists() {
+        bail!("unable to find the Kindle for Mac database at {path:?}. Pass its path with --clipping-path");
+    }
+    Ok(path)
+}
+
+/// Where `My Clippings.txt` is when a kindle is mounted, for the current OS
+fn kindle_mount_paths() -> Vec<PathBuf> {
+    let clippings = PathBuf::from("documents").join("My Clippings.txt");
+    match std::env::consts::OS {
+        "macos" => vec![PathBuf::from("/Volumes/Kindle").join(&clippings)],
+        "linux" => {
+            let mut mounts = Vec::new();
+            if let Ok(user) = std::env::var("USER") {
+                mounts.push(format!("/run/media/{user}/Kindle"));
+                mounts.push(format!("/media/{user}/Kindle"));
+            }
+            mounts.push("/media/Kindle".to_string());
+            mounts.push("/mnt/Kindle".to_string());
+            mounts
+                .into_iter()
+                .map(|mount| PathBuf::from(mount).join(&clippings))
+                .collect()
+        }
+        // the kindle shows up as a removable drive, A: and B: are floppies and C: the system
+        "windows" => ('D'..='Z')
+            .map(|drive| PathBuf::from(format!("{drive}:\\")).join(&clippings))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Where calibre keeps the clippings it fetched from a kindle, e.g.
+/// `Kindle/My Clippings (13)/My Clippings - Kindle.txt` in the library, newest
+/// book id first. The number in the folder name differs per library.
+fn calibre_clippings_paths(calibre_root: &Path) -> Vec<PathBuf> {
+    let kindle = calibre_root.join("Kindle");
+    let mut folders: Vec<(u32, PathBuf)> = fs::read_dir(&kindle)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let id = name
+                .strip_prefix("My Clippings")?
+                .trim()
+                .trim_start_matches('(')
+                .trim_end_matches(')')
+                .parse::<u32>()
+                .unwrap_or(0);
+            Some((id, entry.path()))
+        })
+        .collect();
+    if folders.is_empty() {
+        // still listed, so the error says where clippings were expected
+        folders.push((0, kindle.join("My Clippings")));
+    }
+    folders.sort_by_key(|(id, _)| std::cmp::Reverse(*id));
+    folders
+        .into_iter()
+        .map(|(_, folder)| folder.join("My Clippings - Kindle.txt"))
+        .collect()
+}
+
 fn date_from_str(date_str: &str) -> Result<DateTime<Utc>, Error> {
     let naive_time = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
     let naive_date = NaiveDate::parse_from_str(date_str, "%m-%d-%Y")
         .with_context(|| "error parsing the start date. Valid format is month-day-year")?;
     let naive_date_time = NaiveDateTime::new(naive_date, naive_time);
     info!("using clippings past start date: {}", naive_date_time);
-    Ok(Local.from_local_datetime(&naive_date_time).unwrap().into())
+    Ok(crate::local_to_utc(naive_date_time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_file_size_is_in_megabytes() {
+        assert_eq!(megabytes("100"), Ok(100 * 1024 * 1024));
+        assert!(megabytes("lots").is_err());
+    }
+
+    #[test]
+    fn max_file_size_too_large_is_an_error() {
+        assert!(megabytes(&u64::MAX.to_string()).is_err());
+        let error = command()
+            .try_get_matches_from(["anki-kindle-import", "--max-file-size", "99999999999999999"])
+            .unwrap_err();
+        assert_eq!(error.kind(), clap::error::ErrorKind::ValueValidation);
+    }
+
+    /// The start date of a regular run in `out_dir`, with `args`
+    fn date_after(out_dir: &Path, args: &[&str]) -> Option<DateTime<Utc>> {
+        let clippings_path = out_dir.join("My Clippings.txt");
+        fs::write(&clippings_path, "").unwrap();
+        let matches = command()
+            .try_get_matches_from(
+                [
+                    "anki-kindle-import",
+                    "--clipping-path",
+                    clippings_path.to_str().unwrap(),
+                    "--out-dir",
+                    out_dir.to_str().unwrap(),
+                ]
+                .into_iter()
+                .chain(args.iter().copied()),
+            )
+            .unwrap();
+        match Config::new(&matches).unwrap() {
+            Config::Regular {
+                convert_options, ..
+            } => convert_options.date_after,
+            _ => panic!("expected a regular run"),
+        }
+    }
+
+    #[test]
+    fn ignore_last_date_bypasses_last_date_json() {
+        let out_dir = crate::tests::temp_dir("ignore-last-date");
+        let last_date = LastDate {
+            date: Utc.with_ymd_and_hms(2018, 11, 24, 11, 0, 0).unwrap(),
+        };
+        fs::write(
+            out_dir.join("last-date.json"),
+            serde_json::to_string(&last_date).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(date_after(&out_dir, &[]), Some(last_date.date));
+        assert_eq!(date_after(&out_dir, &["--ignore-last-date"]), None);
+    }
+
+    #[test]
+    fn wait_for_device_finds_clippings_that_appear_late() {
+        let dir = crate::tests::temp_dir("wait-for-device");
+        let clippings_path = dir
+            .join("Kindle")
+            .join("My Clippings")
+            .join("My Clippings - Kindle.txt");
+        let args = |wait: &str| {
+            command()
+                .try_get_matches_from([
+                    "anki-kindle-import",
+                    "--calibre-root",
+                    dir.to_str().unwrap(),
+                    "--out-dir",
+                    dir.join("out").to_str().unwrap(),
+                    "--wait-for-device",
+                    wait,
+                ])
+                .unwrap()
+        };
+        assert!(Config::new(&args("0")).is_err());
+
+        // mounted a moment after the run starts
+        let mount = thread::spawn({
+            let clippings_path = clippings_path.clone();
+            move || {
+                thread::sleep(Duration::from_millis(700));
+                fs::create_dir_all(clippings_path.parent().unwrap()).unwrap();
+                fs::write(&clippings_path, "").unwrap();
+            }
+        });
+        match Config::new(&args("10")).unwrap() {
+            Config::Regular {
+                clippings_path: found,
+                ..
+            } => assert_eq!(found, clippings_path),
+            _ => panic!("expected a regular run"),
+        }
+        mount.join().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn calibre_clippings_are_under_the_home_directory() {
+        let home = crate::tests::temp_dir("calibre-home");
+        let calibre_root = home.join("Calibre Library");
+        for folder in ["My Clippings (2)", "My Clippings (13)", "Other"] {
+            fs::create_dir_all(calibre_root.join("Kindle").join(folder)).unwrap();
+        }
+        let paths = calibre_clippings_paths(&calibre_root);
+        assert_eq!(
+            paths,
+            [
+                home.join("Calibre Library/Kindle/My Clippings (13)/My Clippings - Kindle.txt"),
+                home.join("Calibre Library/Kindle/My Clippings (2)/My Clippings - Kindle.txt"),
+            ]
+        );
+        assert!(paths.iter().all(|path| path.starts_with(&home)));
+
+        // nothing imported yet still says where clippings are expected
+        fs::remove_dir_all(calibre_root.join("Kindle")).unwrap();
+        assert_eq!(
+            calibre_clippings_paths(&calibre_root),
+            [home.join("Calibre Library/Kindle/My Clippings/My Clippings - Kindle.txt")]
+        );
+    }
+
+    #[test]
+    fn newest_of_several_clippings_is_chosen() {
+        let dir = crate::tests::temp_dir("newest-clippings");
+        let kindle = dir.join("kindle.txt");
+        let calibre = dir.join("calibre.txt");
+        let missing = dir.join("missing.txt");
+        let now = SystemTime::now();
+        for (path, age) in [(&kindle, 3600), (&calibre, 60)] {
+            fs::File::create(path)
+                .unwrap()
+                .set_modified(now - Duration::from_secs(age))
+                .unwrap();
+        }
+        let candidates = [missing.clone(), kindle.clone(), calibre.clone()];
+        assert_eq!(newest_existing(&candidates), Some(calibre.clone()));
+
+        // the first one wins a tie
+        fs::File::options()
+            .write(true)
+            .open(&kindle)
+            .unwrap()
+            .set_modified(now - Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(newest_existing(&candidates), Some(kindle));
+        assert_eq!(newest_existing(&[missing]), None);
+    }
 }
@@ -0,0 +1,180 @@
+//! Two-pass workflow: `--extract` writes every highlight to an editable file
+//! with room for terms under it, and `--build` turns the annotated file into
+//! cards. Each highlight in the file looks like
+//!
+//! ```txt
+//! ======== Moby Dick | Herman Melville | 2018-11-24T11:31:30+00:00
+//! The cat walked over the hill
+//! -------- terms
+//! hill .. a small mountain
+//! walked
+//! ```
+//!
+//! where the lines under `-------- terms` are written the same way as kindle notes.
+use anyhow::{bail, Context, Error};
+use chrono::prelude::*;
+
+use crate::config::ParseOptions;
+use crate::{before_start, note_cards, split_bullets, Clipping, Warnings};
+
+const HEADER: &str = "======== ";
+const TERMS: &str = "-------- terms";
+
+/// Writes the highlights among `entries` in the annotatable format. Notes made
+/// on the kindle are left out, since they already say what to make cards from.
+pub fn to_extract(entries: &[Clipping]) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        if let Clipping::Highlight {
+            book,
+            author,
+            date,
+            sentence,
+            ..
+        } = entry
+        {
+            out.push_str(&format!(
+                "{HEADER}{book} | {author} | {}\n{sentence}\n{TERMS}\n\n",
+                date.to_rfc3339()
+            ));
+        }
+    }
+    out
+}
+
+/// Reads a file written by [to_extract] back, after terms have been added under
+/// its highlights. Highlights with terms become [Clipping::Note]s.
+pub fn parse_from_extract(
+    extract_txt: &str,
+    date_after: Option<DateTime<Utc>>,
+    options: &ParseOptions,
+    warnings: &mut Warnings,
+) -> Result<Vec<Clipping>, Error> {
+    let mut entries = Vec::new();
+
+    let mut lines = extract_txt.lines().peekable();
+    while let Some(header) = lines.next() {
+        if header.trim().is_empty() {
+            continue;
+        }
+        let header = header
+            .strip_prefix(HEADER)
+            .with_context(|| format!("expected a `{HEADER}` header, found `{header}`"))?;
+        // split from the right, so a title with ` | ` in it stays whole
+        let mut fields = header.rsplitn(3, " | ");
+        let (Some(date), Some(author), Some(book)) = (fields.next(), fields.next(), fields.next())
+        else {
+            bail!("expected `book | author | date` in the header `{header}`");
+        };
+        let date: DateTime<Utc> = DateTime::parse_from_rfc3339(date)
+            .with_context(|| format!("unable to parse the date `{date}` of `{book}`"))?
+            .into();
+
+        let mut sentence = Vec::new();
+        let mut note_lines = Vec::new();
+        let mut in_terms = false;
+        while let Some(line) = lines.next_if(|line| !line.starts_with(HEADER)) {
+            if line == TERMS {
+                in_terms = true;
+            } else if in_terms {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match &options.bullet_split {
+                    Some(marker) => note_lines.extend(split_bullets(line, marker)),
+                    None => note_lines.push(line.to_string()),
+                }
+            } else {
+                sentence.push(line);
+            }
+        }
+
+        let excluded = options
+            .exclude_books
+            .iter()
+            .any(|excluded| book.contains(excluded.as_str()));
+        if before_start(date, date_after, options) || excluded {
+            continue;
+        }
+
+        entries.push(Clipping::Highlight {
+            book: book.to_string(),
+            author: author.to_string(),
+            date,
+            sentence: sentence.join("\n"),
+            color: None,
+            page: None,
+            location: None,
+        });
+        if note_lines.is_empty() {
+            continue;
+        }
+        let cards = note_cards(
+            &note_lines,
+            &entries,
+            (book, author, date),
+            options,
+            warnings,
+        )?;
+        if cards.is_empty() {
+            warnings.push(format!("terms in `{book}` from {date} produced no cards"));
+        }
+        entries.pop();
+        entries.push(Clipping::Note {
+            book: book.to_string(),
+            author: author.to_string(),
+            date,
+            cards,
+            page: None,
+            location: None,
+        });
+    }
+
+    if options.notes_only {
+        entries.retain(|entry| matches!(entry, Clipping::Note { .. }));
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{cards, clipping, parse, sentences};
+    use crate::Card;
+
+    #[test]
+    fn extract_edit_build_round_trips() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Highlight", 12, "Call me Ishmael"),
+        ]
+        .concat();
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        let extract = to_extract(&entries);
+
+        let annotated = extract.replacen(
+            &format!("{TERMS}\n"),
+            &format!("{TERMS}\nhill .. a small mountain\n"),
+            1,
+        );
+        let mut warnings = Warnings::default();
+        let built =
+            parse_from_extract(&annotated, None, &ParseOptions::default(), &mut warnings).unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(sentences(&built), ["Call me Ishmael"]);
+        assert_eq!(built[0].date(), entries[0].date());
+        assert_eq!(built[1].date(), entries[1].date());
+        let [Card::Cloze(cloze)] = cards(&built)[..] else {
+            panic!("expected a cloze, got {built:?}");
+        };
+        assert!(cloze.text.ends_with("The cat walked over the {{c1::hill}}"));
+        assert!(cloze.back_extra.ends_with("a small mountain"));
+
+        // nothing annotated makes no cards
+        let unannotated =
+            parse_from_extract(&extract, None, &ParseOptions::default(), &mut warnings).unwrap();
+        assert_eq!(sentences(&unannotated).len(), 2);
+        assert!(cards(&unannotated).is_empty());
+    }
+}
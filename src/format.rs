@@ -0,0 +1,85 @@
+//! The delimiters of the markdown output, which `--validate` reads back after
+//! definitions were added to it:
+//!
+//! ```txt
+//! ========
+//! The cat walked over the hill
+//! ========
+//! ----
+//! The cat walked over the {{c1::hill}}
+//! |-
+//! a small mountain
+//! |> The cat walked over the hill
+//! |# familiarity::3
+//! ----
+//! ```
+//!
+//! A line of a highlight or card that starts with a delimiter is written with
+//! a `\` in front of it, which is dropped again when it's read back, so e.g. a
+//! highlight with a `========` line doesn't end early.
+
+/// Starts and ends a highlight
+pub const SENTENCE: &str = "========";
+/// Starts and ends a card
+pub const CARD: &str = "----";
+/// Separates the front of a card from its back, and the back from its extra
+/// section
+pub const SECTION: &str = "|-";
+/// Starts a line of space-separated tags of a card
+pub const TAGS: &str = "|#";
+/// Starts a line of the untouched highlight of a cloze card
+pub const CONTEXT: &str = "|>";
+
+/// Every delimiter, which content lines can't start with unescaped
+pub const DELIMITERS: [&str; 5] = [SENTENCE, CARD, SECTION, TAGS, CONTEXT];
+
+const ESCAPE: char = '\\';
+
+/// Whether `line`, without any escapes in front, starts with a delimiter
+fn needs_escape(line: &str) -> bool {
+    let line = line.trim_start_matches(ESCAPE);
+    DELIMITERS
+        .iter()
+        .any(|delimiter| line.starts_with(delimiter))
+}
+
+/// Escapes each line of `text` that would be read as a delimiter. Lines that
+/// are escaped already get another `\`, so [unescape] gives them back as they
+/// were.
+pub fn escape(text: &str) -> String {
+    text.split('\n')
+        .map(|line| {
+            if needs_escape(line) {
+                format!("{ESCAPE}{line}")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Drops the `\` that [escape] put in front of `line`
+pub fn unescape(line: &str) -> &str {
+    match line.strip_prefix(ESCAPE) {
+        Some(rest) if needs_escape(rest) => rest,
+        _ => line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delimiter_lines_round_trip_through_escaping() {
+        let text = "Part one\n========\n\\----\n|- not a section\nC:\\path\nthe end";
+        let escaped = escape(text);
+        assert_eq!(
+            escaped,
+            "Part one\n\\========\n\\\\----\n\\|- not a section\nC:\\path\nthe end"
+        );
+        let unescaped: Vec<&str> = escaped.split('\n').map(unescape).collect();
+        assert_eq!(unescaped.join("\n"), text);
+    }
+}
@@ -0,0 +1,194 @@
+//! Annotations made in the Kindle app for macOS, which keeps them in a sqlite
+//! database instead of a `My Clippings.txt`. The tables read are
+//!
+//! ```txt
+//! ZBOOK        Z_PK, ZDISPLAYTITLE, ZDISPLAYAUTHOR
+//! ZANNOTATION  ZBOOK, ZTYPE (`highlight` or `note`), ZTEXT, ZCREATIONDATE
+//! ```
+//!
+//! where `ZCREATIONDATE` is a Core Data timestamp, in seconds since 2001.
+use std::path::Path;
+
+use anyhow::{Context, Error};
+use chrono::prelude::*;
+use rusqlite::{Connection, OpenFlags};
+
+use crate::config::ParseOptions;
+use crate::{before_start, collapse_whitespace, note_cards, split_bullets, Clipping, Warnings};
+
+/// Core Data counts from 2001-01-01, this many seconds after the unix epoch
+const CORE_DATA_EPOCH: i64 = 978_307_200;
+
+/// Oldest first, like a clippings file, so each note follows the highlight it
+/// was made on
+const ANNOTATIONS: &str = "SELECT ZBOOK.ZDISPLAYTITLE, ZBOOK.ZDISPLAYAUTHOR, ZANNOTATION.ZTYPE, ZANNOTATION.ZTEXT, ZANNOTATION.ZCREATIONDATE
+    FROM ZANNOTATION JOIN ZBOOK ON ZANNOTATION.ZBOOK = ZBOOK.Z_PK
+    ORDER BY ZANNOTATION.ZCREATIONDATE";
+
+/// Reads the annotations in the database at `db_path` into the same clippings
+/// a kindle file produces. Notes are turned into cards just like kindle notes.
+pub fn parse_from_db(
+    db_path: &Path,
+    date_after: Option<DateTime<Utc>>,
+    options: &ParseOptions,
+    warnings: &mut Warnings,
+) -> Result<Vec<Clipping>, Error> {
+    // the app may be running, so never write to its database
+    let db = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("unable to open the Kindle database {db_path:?}"))?;
+    let mut statement = db
+        .prepare(ANNOTATIONS)
+        .with_context(|| format!("{db_path:?} isn't a Kindle for Mac database"))?;
+    let rows = statement.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, Option<String>>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, Option<String>>(3)?,
+            row.get::<_, f64>(4)?,
+        ))
+    })?;
+
+    let mut entries = Vec::new();
+    for row in rows {
+        let (book, author, kind, text, created) = row?;
+        if options
+            .exclude_books
+            .iter()
+            .any(|excluded| book.contains(excluded.as_str()))
+        {
+            continue;
+        }
+        let author = author.unwrap_or_default();
+        let text = text.unwrap_or_default();
+        let Some(date) = Utc
+            .timestamp_opt(CORE_DATA_EPOCH + created as i64, 0)
+            .single()
+        else {
+            warnings.push(format!(
+                "skipping annotation in `{book}` with the invalid date {created}"
+            ));
+            continue;
+        };
+        if before_start(date, date_after, options) {
+            continue;
+        }
+
+        match kind.as_str() {
+            "highlight" => {
+                let mut sentence = text;
+                if options.collapse_whitespace {
+                    sentence = collapse_whitespace(&sentence);
+                }
+                entries.push(Clipping::Highlight {
+                    book,
+                    author,
+                    date,
+                    sentence,
+                    color: None,
+                    page: None,
+                    location: None,
+                });
+            }
+            "note" => {
+                let mut note_lines = Vec::new();
+                for line in text.lines().filter(|line| !line.trim().is_empty()) {
+                    match &options.bullet_split {
+                        Some(marker) => note_lines.extend(split_bullets(line, marker)),
+                        None => note_lines.push(line.to_string()),
+                    }
+                }
+                let cards = note_cards(
+                    &note_lines,
+                    &entries,
+                    (&book, &author, date),
+                    options,
+                    warnings,
+                )?;
+                if cards.is_empty() {
+                    warnings.push(format!("note in `{book}` from {date} produced no cards"));
+                }
+                // the note replaces the highlight it was made on
+                if matches!(entries.last(), Some(Clipping::Highlight { .. })) {
+                    entries.pop();
+                }
+                entries.push(Clipping::Note {
+                    book,
+                    author,
+                    date,
+                    cards,
+                    page: None,
+                    location: None,
+                });
+            }
+            // bookmarks have nothing to make cards from
+            _ => {}
+        }
+    }
+
+    if options.notes_only {
+        entries.retain(|entry| matches!(entry, Clipping::Note { .. }));
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::tests::{cards, temp_dir};
+    use crate::Card;
+
+    /// A database with the tables the app uses, and the annotations of one book
+    fn fixture(name: &str) -> PathBuf {
+        let db_path = temp_dir(name).join("BookData.sqlite");
+        let db = Connection::open(&db_path).unwrap();
+        db.execute_batch(
+            "CREATE TABLE ZBOOK (Z_PK INTEGER PRIMARY KEY, ZDISPLAYTITLE TEXT, ZDISPLAYAUTHOR TEXT);
+            CREATE TABLE ZANNOTATION (Z_PK INTEGER PRIMARY KEY, ZBOOK INTEGER, ZTYPE TEXT, ZTEXT TEXT, ZCREATIONDATE REAL);
+            INSERT INTO ZBOOK VALUES (1, 'Moby Dick', 'Herman Melville');
+            INSERT INTO ZANNOTATION VALUES (1, 1, 'note', 'hill .. a small mountain', 564746460.5);
+            INSERT INTO ZANNOTATION VALUES (2, 1, 'highlight', 'The cat walked over the hill', 564746400.0);
+            INSERT INTO ZANNOTATION VALUES (3, 1, 'bookmark', NULL, 564746500.0);
+            INSERT INTO ZANNOTATION VALUES (4, 1, 'highlight', 'Call me Ishmael', 564746600.0);",
+        )
+        .unwrap();
+        db_path
+    }
+
+    #[test]
+    fn reads_highlights_and_notes_from_the_database() {
+        let db_path = fixture("kindle-mac");
+        let mut warnings = Warnings::default();
+        let entries =
+            parse_from_db(&db_path, None, &ParseOptions::default(), &mut warnings).unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(entries.len(), 2);
+        // the note, 564746460.5 seconds after 2001-01-01
+        assert_eq!(
+            entries[0].date(),
+            Utc.with_ymd_and_hms(2018, 11, 24, 10, 1, 0).unwrap()
+        );
+        let [Card::Cloze(cloze)] = cards(&entries)[..] else {
+            panic!("expected a cloze, got {entries:?}");
+        };
+        assert!(cloze.text.ends_with("The cat walked over the {{c1::hill}}"));
+        assert!(matches!(
+            &entries[1],
+            Clipping::Highlight { book, author, sentence, .. }
+                if book == "Moby Dick" && author == "Herman Melville" && sentence == "Call me Ishmael"
+        ));
+
+        let date_after = Utc.with_ymd_and_hms(2018, 11, 24, 10, 2, 0).unwrap();
+        let entries = parse_from_db(
+            &db_path,
+            Some(date_after),
+            &ParseOptions::default(),
+            &mut warnings,
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 1);
+    }
+}
@@ -1,17 +1,43 @@
+#[cfg(feature = "http")]
+pub mod ankiconnect;
+#[cfg(feature = "apkg")]
+pub mod apkg;
+pub mod batch;
 pub mod config;
+pub mod extract;
+pub mod format;
+#[cfg(feature = "kindle-mac")]
+pub mod kindle_mac;
+mod locale;
+pub mod readwise;
+pub mod stats;
+pub mod wiktionary;
 
-use std::path::Path;
-use std::{cmp::Ordering, fs};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+use std::{
+    cmp::Ordering,
+    fs::{self, File},
+};
 
 use anyhow::{bail, Context, Error};
 
 use chrono::prelude::*;
 use chrono::serde::ts_seconds;
+use chrono::LocalResult;
 
-use log::trace;
+use encoding_rs::{Encoding, UTF_8};
+use log::{info, trace, warn};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 use regex::Regex;
 
-use config::Config;
+use config::{
+    BareTerm, CardFormat, CardTemplate, ClozeReset, ClozeScope, Config, ConvertOptions, LastDate,
+    OutputEncoding, ParseOptions, Source,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -22,6 +48,15 @@ pub enum Clipping {
         #[serde(with = "ts_seconds")]
         date: DateTime<Utc>,
         sentence: String,
+        /// highlight color, e.g. `yellow` or `pink`, on kindles that record it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        color: Option<String>,
+        /// page of the book, on kindles and books that have them
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        page: Option<u32>,
+        /// kindle location, the start of the range for a highlight
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        location: Option<u32>,
     },
     Note {
         book: String,
@@ -29,7 +64,42 @@ pub enum Clipping {
         #[serde(with = "ts_seconds")]
         date: DateTime<Utc>,
         cards: Vec<Card>,
+        /// page of the book, on kindles and books that have them
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        page: Option<u32>,
+        /// kindle location, the start of the range for a highlight
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        location: Option<u32>,
     },
+    /// Where reading stopped. Makes no cards, but is kept in the metadata
+    Bookmark {
+        book: String,
+        author: String,
+        #[serde(with = "ts_seconds")]
+        date: DateTime<Utc>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        location: Option<u32>,
+    },
+}
+
+impl Clipping {
+    /// When the clipping was added on the kindle
+    pub fn date(&self) -> DateTime<Utc> {
+        match self {
+            Clipping::Highlight { date, .. } => *date,
+            Clipping::Note { date, .. } => *date,
+            Clipping::Bookmark { date, .. } => *date,
+        }
+    }
+
+    /// Title of the book the clipping was made in
+    pub fn book(&self) -> &str {
+        match self {
+            Clipping::Highlight { book, .. } => book,
+            Clipping::Note { book, .. } => book,
+            Clipping::Bookmark { book, .. } => book,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -38,6 +108,205 @@ pub enum Card {
     Basic(Basic),
 }
 
+impl Card {
+    /// Anki tags attached to the card
+    pub fn tags(&self) -> &[String] {
+        match self {
+            Card::Cloze(cloze) => &cloze.tags,
+            Card::Basic(basic) => &basic.tags,
+        }
+    }
+
+    /// The card's two fields without the blank-line padding that the markdown
+    /// workflow relies on. Exporters for formats other than the markdown
+    /// (TSV, Quizlet, Mochi, ...) should emit these instead of the raw fields.
+    pub fn export_fields(&self) -> (&str, &str) {
+        match self {
+            Card::Cloze(Cloze {
+                text, back_extra, ..
+            }) => (text.trim(), back_extra.trim()),
+            Card::Basic(Basic { front, back, .. }) => (front.trim(), back.trim()),
+        }
+    }
+
+    /// The words being studied: the clozed words of a cloze card, or the first
+    /// line of the back of a basic card
+    pub fn terms(&self) -> Vec<String> {
+        match self {
+            Card::Cloze(Cloze { text, .. }) => {
+                // called for every card, so the regex is only compiled once
+                static RE_CLOZE: OnceLock<Regex> = OnceLock::new();
+                let re_cloze =
+                    RE_CLOZE.get_or_init(|| Regex::new(r"\{\{c\d+::(?P<term>.*?)\}\}").unwrap());
+                re_cloze
+                    .captures_iter(text)
+                    .map(|captures| captures["term"].to_string())
+                    .collect()
+            }
+            Card::Basic(Basic { back, .. }) => back
+                .lines()
+                .next()
+                .map(str::trim)
+                .filter(|term| !term.is_empty())
+                .map(String::from)
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// Puts placeholder text where the user is expected to add a definition:
+    /// the empty front of a basic card, or the blank lines in front of a cloze
+    fn fill_empty_front(&mut self, default_front: &str) {
+        match self {
+            Card::Basic(basic) if basic.front.trim().is_empty() => {
+                basic.front = default_front.to_string();
+            }
+            Card::Cloze(cloze) if cloze.text.starts_with('\n') => {
+                cloze.text.insert_str(0, default_front);
+            }
+            _ => {}
+        }
+    }
+
+    /// Puts the definition of a basic card's term, the first line of its back,
+    /// on its front if the front is empty
+    fn fill_definition(&mut self, definitions: &BTreeMap<String, String>) {
+        let Card::Basic(basic) = self else {
+            return;
+        };
+        if !basic.front.trim().is_empty() {
+            return;
+        }
+        let term = basic.back.lines().next().unwrap_or_default().trim();
+        if let Some(definition) = definitions.get(&term.to_lowercase()) {
+            basic.front = definition.clone();
+        }
+    }
+
+    /// The reverse of a cloze card: a basic card asking for the clozed words
+    /// from the extra content, with the words and their sentence on the back.
+    /// Clozes without extra content have nothing to ask with.
+    fn reversed(&self, sentence: &str, back_spacing: usize) -> Option<Card> {
+        match self {
+            Card::Cloze(cloze) if !cloze.back_extra.trim().is_empty() => Some(Card::Basic(Basic {
+                front: cloze.back_extra.trim().to_string(),
+                back: format!(
+                    "{}{}{sentence}",
+                    self.terms().join(", "),
+                    "\n".repeat(back_spacing)
+                ),
+                tags: Vec::new(),
+                raw_note: cloze.raw_note.clone(),
+                extra: None,
+            })),
+            _ => None,
+        }
+    }
+
+    /// Adds a section to the end of the back of the card, after `spacing` newlines
+    fn push_back_section(&mut self, section: &str, spacing: usize) {
+        let back = match self {
+            Card::Cloze(cloze) => &mut cloze.back_extra,
+            Card::Basic(basic) => &mut basic.back,
+        };
+        back.push_str(&"\n".repeat(spacing));
+        back.push_str(section);
+    }
+
+    fn tags_mut(&mut self) -> &mut Vec<String> {
+        match self {
+            Card::Cloze(cloze) => &mut cloze.tags,
+            Card::Basic(basic) => &mut basic.tags,
+        }
+    }
+
+    fn raw_note_mut(&mut self) -> &mut Option<String> {
+        match self {
+            Card::Cloze(cloze) => &mut cloze.raw_note,
+            Card::Basic(basic) => &mut basic.raw_note,
+        }
+    }
+}
+
+/// Records a note line a card was made from, after any before it
+fn push_raw_note(raw_note: &mut Option<String>, line: &str) {
+    match raw_note {
+        Some(raw_note) => {
+            raw_note.push('\n');
+            raw_note.push_str(line);
+        }
+        None => *raw_note = Some(line.to_string()),
+    }
+}
+
+/// Makes a string usable as (part of) an Anki tag, since Anki separates tags by spaces
+fn sanitize_tag(tag: &str) -> String {
+    tag.split_whitespace().collect::<Vec<_>>().join("_")
+}
+
+/// Hierarchical tag grouping a card by author, then title, in Anki's tag
+/// browser. Books without an author go straight under `book`.
+fn book_author_tag(book: &str, author: &str) -> String {
+    if author.is_empty() {
+        return format!("book::{}", sanitize_tag(book));
+    }
+    format!("book::{}::{}", sanitize_tag(author), sanitize_tag(book))
+}
+
+/// Flat tag naming a book by its title, lowercased with accents dropped and
+/// anything but letters and digits turned into single underscores, e.g.
+/// `crime_punishment_vol_1` for `Crime & Punishment: Vol. 1`. Letters of other
+/// scripts, like Japanese, are kept.
+pub fn book_tag(book: &str) -> String {
+    let mut tag = String::new();
+    for c in book.chars().flat_map(char::to_lowercase) {
+        let c = strip_accent(c);
+        if c.is_alphanumeric() {
+            tag.push(c);
+        } else if !tag.is_empty() && !tag.ends_with('_') {
+            tag.push('_');
+        }
+    }
+    tag.trim_end_matches('_').to_string()
+}
+
+/// The unaccented letter of an accented latin one, e.g. `e` for `é`
+fn strip_accent(c: char) -> char {
+    match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' => 'a',
+        'ç' => 'c',
+        'è' | 'é' | 'ê' | 'ë' => 'e',
+        'ì' | 'í' | 'î' | 'ï' => 'i',
+        'ñ' => 'n',
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' => 'o',
+        'ù' | 'ú' | 'û' | 'ü' => 'u',
+        'ý' | 'ÿ' => 'y',
+        _ => c,
+    }
+}
+
+/// Scheduling hint for a card made from a clipping added on `date`, so older
+/// material can be found (and surfaced sooner) in Anki
+fn age_tag(date: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let bucket = match (now - date).num_days() {
+        days if days < 7 => "week",
+        days if days < 30 => "month",
+        days if days < 365 => "year",
+        _ => "older",
+    };
+    format!("age::{bucket}")
+}
+
+/// `read::2024` and `read::2024-05` for a clipping added on `date`, so cards
+/// can be filtered by when they were read
+fn read_date_tags(date: DateTime<Utc>) -> [String; 2] {
+    let date = date.with_timezone(&Local);
+    [
+        date.format("read::%Y").to_string(),
+        date.format("read::%Y-%m").to_string(),
+    ]
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 struct Output {
     cards: Vec<Card>,
@@ -47,387 +316,5046 @@ struct Output {
     end_date: DateTime<Utc>,
 }
 
+/// How many of each kind of clipping and card a run produced
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ClippingCounts {
+    pub highlights: usize,
+    pub notes: usize,
+    #[serde(default)]
+    pub bookmarks: usize,
+    pub cloze_cards: usize,
+    pub basic_cards: usize,
+}
+
+impl ClippingCounts {
+    pub fn from_entries(entries: &[Clipping]) -> Self {
+        let mut counts = Self::default();
+        for entry in entries {
+            match entry {
+                Clipping::Highlight { .. } => counts.highlights += 1,
+                Clipping::Bookmark { .. } => counts.bookmarks += 1,
+                Clipping::Note { cards, .. } => {
+                    counts.notes += 1;
+                    for card in cards {
+                        match card {
+                            Card::Cloze(_) => counts.cloze_cards += 1,
+                            Card::Basic(_) => counts.basic_cards += 1,
+                        }
+                    }
+                }
+            }
+        }
+        counts
+    }
+}
+
+/// Summary of a run's inputs and outputs, written to `out/manifest.json` for
+/// debugging and auditing scheduled imports
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Manifest {
+    pub input: String,
+    pub date_after: Option<DateTime<Utc>>,
+    pub counts: ClippingCounts,
+    pub outputs: Vec<String>,
+    pub created: DateTime<Utc>,
+    /// device or account the clippings came from, if the file says so
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Basic {
     front: String,
     back: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    /// the note line the card was made from, with `--keep-raw`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    raw_note: Option<String>,
+    /// a third `|-` section of a finished card, see [with_extra]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    extra: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Cloze {
     text: String,
     back_extra: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    /// the untouched highlight, for note types with a separate `Context` field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    context: Option<String>,
+    /// the note lines the card was made from, with `--keep-raw`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    raw_note: Option<String>,
+    /// a third `|-` section of a finished card, see [with_extra]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    extra: Option<String>,
+}
+
+/// Collects the warnings raised while turning clippings into cards, so that
+/// they can be counted once conversion is over
+#[derive(Debug, Default)]
+pub struct Warnings {
+    messages: Vec<String>,
+    /// cloze terms that weren't found in their sentence, as `(term, sentence)`
+    unmatched: Vec<(String, String)>,
+}
+
+impl Warnings {
+    /// Logs the warning and remembers it
+    pub fn push(&mut self, message: String) {
+        warn!("{message}");
+        self.messages.push(message);
+    }
+
+    /// Warns about a cloze term that isn't in its sentence, and remembers it
+    /// for `out/unmatched.csv`
+    fn push_unmatched(&mut self, term: &str, sentence: &str) {
+        self.push(format!("no match for `{term}` in sentence `{sentence}`"));
+        self.unmatched
+            .push((term.to_string(), sentence.to_string()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &String> {
+        self.messages.iter()
+    }
+
+    pub fn unmatched(&self) -> &[(String, String)] {
+        &self.unmatched
+    }
 }
 
 impl Cloze {
-    fn from_sentence_and_list(sentence: &str, value: &str) -> Result<Self, anyhow::Error> {
-        let mut split = value.split(" .. ");
-        // the term in question will be the first split
-        let term = split
-            .next()
-            .context("unable to find first term in cloze")?
-            .trim();
+    /// Clozes the terms of a note line in the sentence, numbering them from
+    /// `first_index`
+    fn from_sentence_and_list(
+        sentence: &str,
+        value: &str,
+        first_index: usize,
+        options: &ParseOptions,
+        warnings: &mut Warnings,
+    ) -> Result<Self, anyhow::Error> {
+        let mut split: Vec<&str> = value.split(&options.cloze_sep).map(str::trim).collect();
+        // every split is a term to cloze, except the last one which is extra
+        // content, e.g. `walked .. hill .. extra` clozes both `walked` and `hill`
+        let extra = if split.len() > 1 { split.pop() } else { None };
+        if split[0].is_empty() {
+            bail!("unable to find first term in cloze");
+        }
+
+        let mut clozed_content = sentence.to_string();
+        let mut lemmas = Vec::new();
+        for (index, term) in split.into_iter().enumerate() {
+            // `walked=walk` clozes `walked` and shows the lemma `walk` on the back
+            let term = match term.split_once('=') {
+                Some((term, lemma)) => {
+                    lemmas.push(lemma.trim());
+                    term.trim()
+                }
+                None => term,
+            };
+            let term = transform_term(term, options, warnings);
+            let term = term.as_str();
 
-        // attempt to find the term in the previous term, which should be a highlight
-        trace!("replacing `{}` in `{}`", term, sentence);
-        let re_term = Regex::new(&format!("(?i)(?P<term>{term})"))?;
+            // attempt to find the term in the previous term, which should be a highlight
+            trace!("replacing `{}` in `{}`", term, sentence);
+            let re_term = cloze_term_regex(term, sentence)?;
 
-        if !re_term.is_match(sentence) {
-            trace!("no match for {term} in sentence {sentence}");
+            if !re_term.is_match(sentence) {
+                warnings.push_unmatched(term, sentence);
+            }
+
+            clozed_content = cloze_matches(
+                &re_term,
+                &clozed_content,
+                &format!("{{{{c{}::$term}}}}", first_index + index),
+                options.cloze_scope,
+            );
         }
+        if clozed_content.contains("{{c") && !has_context(&clozed_content) {
+            // e.g. a one-word highlight clozed by that word, which would be a
+            // card asking for the answer with nothing to go on
+            warnings.push(format!(
+                "cloze `{value}` hides all of `{sentence}`, making a basic card instead"
+            ));
+            clozed_content = sentence.to_string();
+        }
+        let spacing = "\n".repeat(options.back_spacing);
 
-        let clozed_content = re_term.replace_all(sentence, "{{c1::$term}}").to_string();
+        let back_extra = lemmas
+            .into_iter()
+            .chain(extra)
+            // TODO we add newlines to back because the
+            // reading will be on the back.
+            .map(|section| format!("{spacing}{section}"))
+            .collect::<String>();
 
         Ok(Self {
-            // TODO we add two newlines to cloze content because
+            // TODO we add newlines to cloze content because
             // we also want to be able to manually add word definitions to
             // the front
-            text: format!("\n\n{clozed_content}"),
-            back_extra: if let Some(extra) = split.next() {
-                // TODO we add two newlines to back because the
-                // reading will be on the back.
-                format!("\n\n{}", extra.trim())
-            } else {
-                String::new()
-            },
+            text: format!("{spacing}{clozed_content}"),
+            back_extra,
+            tags: Vec::new(),
+            raw_note: None,
+            extra: None,
+            context: None,
         })
     }
-}
-
-/// Function which takes in input from the raw clippings file and returns clippings
-pub fn parse_from_txt(
-    clippings_txt: String,
-    date_after: Option<DateTime<Utc>>,
-) -> Result<Vec<Clipping>, Error> {
-    // store all entries
-    let mut entries = Vec::new();
-
-    let re_author_book = Regex::new(r"(?P<book>.+) \((?P<author>.+)\)").unwrap();
-    let re_date = Regex::new(
-        r"- Your (?P<highlight_or_note>.+) on .+ (\| .+ )?\| Added on .+, (?P<date>.+,.+)",
-    )?;
 
-    let mut iter = clippings_txt.lines();
-    while let Some(line_1) = iter.next() {
-        // trace!("{}", line_1);
-        // first line is always the book and author
-        let (book, author) = {
-            println!("{line_1}");
-            let captures = re_author_book.captures(line_1).unwrap();
-            (captures["book"].to_string(), captures["author"].to_string())
-        };
-        let line_2 = iter.next().unwrap();
-        let (highlight_or_note, date) = {
-            let captures = re_date.captures(line_2).unwrap();
-            (
-                captures["highlight_or_note"].to_string(),
-                captures["date"].to_string(),
-            )
+    /// Adds the term of a grouped note line to this cloze as another `c1` blank,
+    /// so Anki hides it on the same card. Lemmas and extra content are added to
+    /// the back like in [Cloze::from_sentence_and_list].
+    fn group_with(
+        &mut self,
+        value: &str,
+        options: &ParseOptions,
+        warnings: &mut Warnings,
+    ) -> Result<(), anyhow::Error> {
+        let mut split = value.split(&options.cloze_sep).map(str::trim);
+        let term = split.next().unwrap_or_default();
+        let (term, lemma) = match term.split_once('=') {
+            Some((term, lemma)) => (term.trim(), Some(lemma.trim())),
+            None => (term, None),
         };
-        // e.g. November 24, 2018 11:31:30 AM
-        let naive = NaiveDateTime::parse_from_str(&date, "%B %d, %Y %-I:%M:%S %p").unwrap();
-        let date: DateTime<Utc> = Local.from_local_datetime(&naive).unwrap().into();
+        if term.is_empty() {
+            bail!("unable to find term to group in `{value}`");
+        }
+        let term = transform_term(term, options, warnings);
+        let term = term.as_str();
 
-        if let Some(date_after) = date_after {
-            if date <= date_after {
-                // skip until next ====
-                for line in iter.by_ref() {
-                    if line.contains("==========") {
-                        break;
-                    }
-                }
-                continue;
-            }
+        trace!("grouping `{}` into `{}`", term, self.text);
+        let re_term = cloze_term_regex(term, &self.text)?;
+        if !re_term.is_match(&self.text) {
+            warnings.push_unmatched(term, self.text.trim());
         }
+        self.text = cloze_matches(&re_term, &self.text, "{{c1::$term}}", options.cloze_scope);
 
-        // always two newlines
-        iter.next().unwrap();
+        let spacing = "\n".repeat(options.back_spacing);
+        for section in lemma.into_iter().chain(split) {
+            self.back_extra.push_str(&format!("{spacing}{section}"));
+        }
+        Ok(())
+    }
 
-        // dbg!(iter.clone().map(|l|l.to_string()).collect::<Vec<String>>());
+    /// Clozes the terms of another note line in this cloze, numbered after the
+    /// ones already in it, e.g. `c2` after `c1`. Its extra content is added to
+    /// the back after the existing extra content.
+    fn merge_line(
+        &mut self,
+        value: &str,
+        options: &ParseOptions,
+        warnings: &mut Warnings,
+    ) -> Result<(), anyhow::Error> {
+        static RE_INDEX: OnceLock<Regex> = OnceLock::new();
+        let re_index = RE_INDEX.get_or_init(|| Regex::new(r"\{\{c(?P<index>\d+)::").unwrap());
+        let next_index = re_index
+            .captures_iter(&self.text)
+            .filter_map(|captures| captures["index"].parse::<usize>().ok())
+            .max()
+            .unwrap_or(0)
+            + 1;
 
-        match highlight_or_note.as_str() {
-            "Highlight" => {
-                let mut content = Vec::new();
-                // grab everything until the next `======`
-                for line in iter.by_ref() {
-                    if line.contains("==========") {
-                        break;
-                    }
-                    content.push(line);
-                }
-                let sentence = content.join("\n");
-                entries.push(Clipping::Highlight {
-                    book,
-                    author,
-                    date,
-                    sentence,
-                });
-            }
-            "Note" => {
-                let mut terms = Vec::new();
-
-                // grab every term until the next `======`
-                for line in iter.by_ref() {
-                    //    dbg!(line);
-                    if line.contains("==========") {
-                        break;
-                    }
-                    // at this point we can either split by ` ... ` or ` .. `.
-                    // if it's cloze
-                    let note = if line.contains(" .. ") {
-                        let Some(Clipping::Highlight { sentence, ..}) = entries.last() else {
-                            trace!("empty list, nothing for cloze to pull from");
-                            continue;
-                        };
-                        Card::Cloze(Cloze::from_sentence_and_list(sentence, line)?)
-                    } else if line.contains(" ... ") {
-                        let back: Vec<String> =
-                            line.split(" ... ").map(|s| s.to_string()).collect();
-
-                        match back.len().cmp(&2) {
-                            Ordering::Less => return Err(Error::msg(
-                                "no description provided for basic term when using `...` operator",
-                            )),
-                            Ordering::Equal | Ordering::Greater => {}
-                        }
+        let sentence = self.text.trim_start_matches('\n');
+        let merged = Cloze::from_sentence_and_list(sentence, value, next_index, options, warnings)?;
+        self.text = merged.text;
+        self.back_extra.push_str(&merged.back_extra);
+        Ok(())
+    }
 
-                        Card::Basic(Basic {
-                            front: String::new(),
-                            back: back.join("\n"),
-                        })
-                    } else {
-                        let Some(Clipping::Highlight { sentence, ..}) = entries.last() else {
-                            trace!("empty list, nothing for cloze to pull from");
-                            continue;
-                        };
-                        Card::Cloze(Cloze::from_sentence_and_list(sentence, line)?)
-                    };
-                    terms.push(note);
-                }
-                entries.pop();
-                entries.push(Clipping::Note {
-                    book,
-                    author,
-                    date,
-                    cards: terms,
-                });
-            }
-            "Bookmark" => {
-                // fast forward and consume until either EOF or the next `=========`
-                for line in iter.by_ref() {
-                    if line.contains("==========") {
-                        break;
-                    }
-                }
-            }
-            _ => {
-                panic!("unexpected type of kindle annotation");
+    /// A cloze where none of the terms were found in the sentence has nothing
+    /// to hide, which Anki rejects. Such clozes become basic cards with the term
+    /// and sentence on the back, tagged `needs-review` so they can be fixed in Anki.
+    fn or_demoted(self, line: &str, cloze_sep: &str) -> Card {
+        if self.text.contains("{{c") {
+            return Card::Cloze(self);
+        }
+        let term = line.split(cloze_sep).next().unwrap_or(line);
+        let term = term.split_once('=').map_or(term, |(term, _)| term).trim();
+        // the unclozed text still starts with the padding, which separates it from the term
+        let mut tags = self.tags;
+        tags.push("needs-review".to_string());
+        Card::Basic(Basic {
+            front: String::new(),
+            back: format!("{term}{}{}", self.text, self.back_extra),
+            tags,
+            raw_note: self.raw_note,
+            extra: self.extra,
+        })
+    }
+}
+
+/// Whether a clozed text still has words outside of its deletions
+fn has_context(clozed: &str) -> bool {
+    static RE_DELETION: OnceLock<Regex> = OnceLock::new();
+    let re_deletion = RE_DELETION.get_or_init(|| Regex::new(r"\{\{c\d+::.*?\}\}").unwrap());
+    re_deletion
+        .replace_all(clozed, "")
+        .chars()
+        .any(char::is_alphanumeric)
+}
+
+/// Finds the highlight sentence a cloze note line should be built from, which is
+/// always the last parsed entry. Highlights shorter than
+/// [ParseOptions::min_cloze_words] are rejected since they make poor cloze cards.
+fn cloze_source<'a>(
+    entries: &'a [Clipping],
+    line: &str,
+    options: &ParseOptions,
+    warnings: &mut Warnings,
+) -> Option<&'a str> {
+    let Some(Clipping::Highlight { sentence, .. }) = entries.last() else {
+        warnings.push(format!(
+            "skipping cloze `{line}`: no highlight before it to pull a sentence from"
+        ));
+        return None;
+    };
+    let words = sentence.split_whitespace().count();
+    if words < options.min_cloze_words {
+        warnings.push(format!(
+            "skipping cloze `{line}`: highlight `{sentence}` has {words} word(s), fewer than the minimum of {}",
+            options.min_cloze_words
+        ));
+        return None;
+    }
+    Some(sentence)
+}
+
+/// Clozes a note line in the highlight. With [ParseOptions::merge_cloze_lines]
+/// the line is added to the cloze made from the line before instead, if there
+/// is one, and no new card is made.
+fn cloze_line(
+    sentence: &str,
+    line: &str,
+    previous: Option<&mut Card>,
+    options: &ParseOptions,
+    warnings: &mut Warnings,
+) -> Result<Option<Card>, Error> {
+    if options.merge_cloze_lines {
+        if let Some(Card::Cloze(previous)) = previous {
+            previous.merge_line(line, options, warnings)?;
+            if options.keep_raw {
+                push_raw_note(&mut previous.raw_note, line);
             }
-        };
-        // next line is always (notesorhighlight | location | date)
+            return Ok(None);
+        }
     }
-    // dbg!("hello world", &entries);
-    // if let Some(date_inclusive_after) = date_inclusive_after {
-    //     entries = entries
-    //             Clipping::Highlight { date, .. } => date >= &date_inclusive_after,
-    //             Clipping::Note { date, .. } => date >= &date_inclusive_after,
-    //         })
-    //         .collect();
-    // }
-    Ok(entries)
+    Ok(Some(
+        Cloze::from_sentence_and_list(sentence, line, 1, options, warnings)?
+            .or_demoted(line, &options.cloze_sep),
+    ))
 }
 
-pub fn convert_config_to_finished_app(config: Config) -> Result<(), Error> {
-    match config {
-        Config::Regular {
-            clippings_path,
-            output_file_name,
-            date_after,
-        } => {
-            let clippings_txt = fs::read_to_string(clippings_path)
-                .with_context(|| "unable to read clippings path")?;
+/// Back of a basic card: the term, with the preceding highlight (if any) as its
+/// example sentence
+fn term_with_sentence(term: &str, entries: &[Clipping], options: &ParseOptions) -> String {
+    match entries.last() {
+        Some(Clipping::Highlight { sentence, .. }) => {
+            format!("{term}{}{sentence}", "\n".repeat(options.back_spacing))
+        }
+        _ => term.to_string(),
+    }
+}
 
-            let entries = parse_from_txt(clippings_txt, date_after)?;
+/// Trims a cloze down to `window` words on either side of its deletions, with
+/// an ellipsis wherever words were cut
+fn window_cloze(text: &str, window: usize) -> String {
+    // keep the padding in front of the sentence as it is
+    let sentence = text.trim_start_matches('\n');
+    let padding = &text[..text.len() - sentence.len()];
 
-            let out = {
-                // experimental markdown export
-                let mut out_string = String::new();
+    let words: Vec<&str> = sentence.split_whitespace().collect();
+    let (Some(first), Some(last)) = (
+        words.iter().position(|word| word.contains("{{c")),
+        words.iter().rposition(|word| word.contains("}}")),
+    ) else {
+        return text.to_string();
+    };
+    let start = first.saturating_sub(window);
+    let end = (last + window + 1).min(words.len());
 
-                // separate entries into
-                for entry in &entries {
-                    match entry {
-                        // if it's a highlight, don't even add a bullet, just insert the sentence
-                        Clipping::Highlight { sentence, .. } => {
-                            out_string.push_str(&format!("========\n{sentence}\n========\n"));
-                        }
-                        // otherwise, for notes,
-                        Clipping::Note { cards, .. } => {
-                            for card in cards {
-                                match card {
-                                    Card::Cloze(Cloze { text, back_extra }) => {
-                                        out_string.push_str(&format!(
-                                            "----\n{text}\n|-\n{back_extra}\n----\n"
-                                        ));
-                                    }
-                                    Card::Basic(Basic { front, back }) => {
-                                        out_string.push_str(&format!(
-                                            "----\n{front}\n|-\n{back}\n----\n"
-                                        ));
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                out_string
-            };
+    let mut windowed = words[start..end].join(" ");
+    if start > 0 {
+        windowed = format!("... {windowed}");
+    }
+    if end < words.len() {
+        windowed = format!("{windowed} ...");
+    }
+    format!("{padding}{windowed}")
+}
+
+/// Collapses runs of spaces and tabs into a single space on every line, keeping
+/// the line breaks themselves intact
+pub(crate) fn collapse_whitespace(text: &str) -> String {
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
-            write(out, output_file_name)?;
+/// Splits a note line written as a bulleted list (e.g. `- term1 - term2`) into
+/// one term per bullet. The marker only counts when it stands alone between
+/// spaces, so hyphenated words survive.
+pub(crate) fn split_bullets(line: &str, marker: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    for word in line.split(' ') {
+        if word == marker {
+            if !current.is_empty() {
+                terms.push(current.join(" "));
+                current.clear();
+            }
+        } else if !word.is_empty() {
+            current.push(word);
+        }
+    }
+    if !current.is_empty() {
+        terms.push(current.join(" "));
+    }
+    terms
+}
 
-            write(
-                serde_json::to_string(&entries)?,
-                "out/output-metadata.json".to_string(),
-            )?;
+/// Endings dropped from a cloze term that isn't in its sentence as it is, and
+/// what replaces them, e.g. `walked` is looked for as `walk`
+const SUFFIXES: [(&str, &str); 7] = [
+    ("ies", "y"),
+    ("ied", "y"),
+    ("ing", ""),
+    ("ed", ""),
+    ("es", ""),
+    ("s", ""),
+    ("ly", ""),
+];
+
+/// Finds a cloze term in `text`, capturing what to cloze as `term`. A single
+/// word that isn't in the text as it is is looked for by its stem instead, so
+/// `walked` clozes `walking` in `I was walking`. Falls back to the term as it
+/// is, which matches nothing.
+fn cloze_term_regex(term: &str, text: &str) -> Result<Regex, regex::Error> {
+    let literal = Regex::new(&format!("(?i)(?P<term>{term})"))?;
+    if literal.is_match(text) || term.contains(char::is_whitespace) {
+        return Ok(literal);
+    }
+    let term = term.to_lowercase();
+    for (suffix, replacement) in SUFFIXES {
+        let Some(stem) = term.strip_suffix(suffix) else {
+            continue;
+        };
+        let stem = format!("{stem}{replacement}");
+        // `running` is looked for as `runn`, then `run`
+        let mut stems = vec![stem.clone()];
+        let mut chars = stem.chars().rev();
+        if let (Some(last), Some(before)) = (chars.next(), chars.next()) {
+            if last == before && !"aeiou".contains(last) {
+                stems.push(stem[..stem.len() - last.len_utf8()].to_string());
+            }
         }
-        Config::Validate { output_file_name } => {
-            validate(output_file_name)?;
+        for stem in stems.iter().filter(|stem| stem.chars().count() >= 3) {
+            let re_stem = Regex::new(&format!(r"(?i)\b(?P<term>{}\w*)", regex::escape(stem)))?;
+            if re_stem.is_match(text) {
+                trace!("found `{term}` by its stem `{stem}`");
+                return Ok(re_stem);
+            }
         }
     }
-
-    Ok(())
+    Ok(literal)
 }
 
-pub fn write(out: String, output_file_name: String) -> Result<(), Error> {
-    // check if file already exists
-    let out_path = Path::new(&output_file_name);
-    if out_path.exists() {
-        // copy file to `out.json (old)`
-        let copy = "out/output-copy.md";
-        fs::copy(out_path, copy).with_context(|| {
-            format!(
-                "unable to copy from {:#?} to {:#?} for some reason",
-                out_path, copy
-            )
-        })?;
-        println!("overwrote old {:?} (backed up to `{:?}`)", out_path, copy);
+/// Splits the `@N ` familiarity rating, `@1` to `@5`, from the start of a note
+/// line. Anything else, like `@home`, is left as part of the line.
+fn split_familiarity(line: &str) -> (Option<char>, &str) {
+    let mut chars = line.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('@'), Some(level @ '1'..='5'), Some(' ')) => (Some(level), chars.as_str()),
+        _ => (None, line),
     }
-    // copy to something
-    fs::write(&output_file_name, out)
-        .with_context(|| "Unable to write to final output file `out.json/yml` for some reason.")?;
+}
 
-    Ok(())
+/// Pipes a cloze term through the `--transform-cmd`, if there is one. The term
+/// is kept as it is when the command fails or prints nothing.
+pub fn transform_term(term: &str, options: &ParseOptions, warnings: &mut Warnings) -> String {
+    let Some(transform_cmd) = &options.transform_cmd else {
+        return term.to_string();
+    };
+    let output = Command::new(transform_cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(term.as_bytes())?;
+            }
+            child.wait_with_output()
+        });
+    let transformed = match output {
+        Ok(output) if output.status.success() => String::from_utf8(output.stdout).ok(),
+        Ok(output) => {
+            warnings.push(format!(
+                "{transform_cmd:?} failed on `{term}` with {}, keeping it as it is",
+                output.status
+            ));
+            return term.to_string();
+        }
+        Err(error) => {
+            warnings.push(format!(
+                "unable to run {transform_cmd:?} on `{term}`: {error}, keeping it as it is"
+            ));
+            return term.to_string();
+        }
+    };
+    match transformed.as_deref().map(str::trim) {
+        Some(transformed) if !transformed.is_empty() => transformed.to_string(),
+        _ => {
+            warnings.push(format!(
+                "{transform_cmd:?} printed no term for `{term}`, keeping it as it is"
+            ));
+            term.to_string()
+        }
+    }
 }
 
-/// Validates if notes have one highlight and one (or more) terms. This doesn't
-/// work for cloze types, so it's kinda useless rn
-fn validate(output_file_name: String) -> Result<(), Error> {
-    // parse the file in to JSON
-    // construct array of cards
-    let mut cards: Vec<Card> = Vec::new();
-    let string = fs::read_to_string(&output_file_name).unwrap();
+/// Turns the lines of a note into cards, pulling sentences from the highlight
+/// before it, which is the last of `entries`
+pub(crate) fn note_cards(
+    note_lines: &[String],
+    entries: &[Clipping],
+    (book, author, date): (&str, &str, DateTime<Utc>),
+    options: &ParseOptions,
+    warnings: &mut Warnings,
+) -> Result<Vec<Card>, Error> {
+    let mut terms = Vec::new();
+    let mut note_lines = note_lines;
+    if options.note_as_single_card && !note_lines.is_empty() {
+        // the whole note is one free-form thought, with the
+        // highlight as its context, instead of a list of terms
+        let mut back = note_lines.join("\n");
+        if let Some(Clipping::Highlight { sentence, .. }) = entries.last() {
+            back = format!("{back}{}{sentence}", "\n".repeat(options.back_spacing));
+        }
+        terms.push(Card::Basic(Basic {
+            front: String::new(),
+            back,
+            tags: Vec::new(),
+            raw_note: options.keep_raw.then(|| note_lines.join("\n")),
+            extra: None,
+        }));
+        note_lines = &[];
+    }
 
-    let mut lines = string.lines().enumerate();
+    // the first line is a comment on all of the terms below it
+    let mut shared = None;
+    if options.first_line_shared && note_lines.len() > 1 {
+        shared = Some(note_lines[0].trim());
+        note_lines = &note_lines[1..];
+    }
 
-    let mut sentence = String::new();
-    // get next line
-    while let Some((number, line)) = lines.next() {
-        match line {
-            // match the line to either "========" to signal a sentence, or
-            "========" => {
-                sentence.clear();
-                let mut buffer: Vec<&str> = Vec::new();
-                // consume until next "========"
-                for (_, line) in lines.by_ref() {
-                    if line != "========" {
-                        buffer.push(line);
-                    } else {
-                        break;
+    for line in note_lines {
+        let raw_line = line.as_str();
+        let line = raw_line;
+        // `+ term` adds another blank to the cloze on the line before, so both
+        // are hidden on the same card instead of becoming a card each
+        let line = match line.strip_prefix("+ ") {
+            Some(grouped) => {
+                if let Some(Card::Cloze(cloze)) = terms.last_mut() {
+                    cloze.group_with(grouped, options, warnings)?;
+                    if options.keep_raw {
+                        push_raw_note(&mut cloze.raw_note, raw_line);
                     }
+                    continue;
                 }
-                sentence = buffer.join("<br>");
+                warnings.push(format!(
+                    "`{line}` has no cloze on the line before it to group with, making it its own card"
+                ));
+                grouped
             }
-            // "----" to signal a card built off that sentence
-            "----" => {
-                let mut buffer: Vec<&str> = Vec::new();
-                // consume until next "========"
-                for (_, line) in lines.by_ref() {
-                    if line != "----" {
-                        buffer.push(line);
-                    } else {
-                        break;
-                    }
-                }
-                // dbg!(&buffer);
-
-                // let total_content: String = buffer.join("<br>");
-                let idx = buffer.iter().position(|&s| s == "|-").context(format!("error finding |- in card lol {:?}", buffer))?;
+            None => line,
+        };
+        let (familiarity, line) = split_familiarity(line);
+        let colon_def = if options.colon_defs {
+            line.split_once(": ")
+        } else {
+            None
+        };
+        // `walked=walk` is always a cloze with the lemma on the back, so its
+        // `=` is looked for before the separators decide the kind of card, and
+        // a ` ... ` after it starts the extra content like a ` .. ` would
+        let term = line.split(&options.cloze_sep).next().unwrap_or(line);
+        let term = term.split(&options.extra_sep).next().unwrap_or(term);
+        let has_lemma = term.contains('=') && colon_def.is_none();
+        let lemma_line;
+        let line = if has_lemma {
+            lemma_line = line.replacen(&options.extra_sep, &options.cloze_sep, 1);
+            lemma_line.as_str()
+        } else {
+            line
+        };
+        // at this point we can either split by the extra separator, ` ... `
+        // by default, or the cloze separator, ` .. ` by default.
+        // if it's cloze
+        let mut note = if let Some((term, definition)) = colon_def {
+            // `term: definition` is already a finished basic card
+            Card::Basic(Basic {
+                front: definition.trim().to_string(),
+                back: term_with_sentence(term.trim(), entries, options),
+                tags: Vec::new(),
+                raw_note: None,
+                extra: None,
+            })
+        } else if has_lemma || line.contains(&options.cloze_sep) {
+            let Some(sentence) = cloze_source(entries, line, options, warnings) else {
+                continue;
+            };
+            let Some(card) = cloze_line(sentence, line, terms.last_mut(), options, warnings)?
+            else {
+                continue;
+            };
+            card
+        } else if line.contains(&options.extra_sep) {
+            // `hill ... ` with nothing after the separator has no description
+            let back: Vec<String> = line
+                .split(&options.extra_sep)
+                .filter(|s| !s.trim().is_empty())
+                .map(|s| s.to_string())
+                .collect();
 
-                let front = buffer[0..idx].to_vec().join("<br>");
-                let back = buffer[idx+1..].to_vec().join("<br>");
+            match back.len().cmp(&2) {
+                Ordering::Less => {
+                    return Err(Error::msg(format!(
+                        "no description provided for basic term when using `...` operator, in note line `{line}` of `{book}` from {date}"
+                    )))
+                }
+                Ordering::Equal | Ordering::Greater => {}
+            }
 
-                // first check for the presence of any cloze beginnings
-                if front.contains("{{c1::") {
-                    // insert it as a cloze, without the sentence
-                    cards.push(Card::Cloze(Cloze {
-                        text: front,
-                        back_extra: back,
-                    }));
-                } else {
-                    // separate the first line of back (the word) from the rest of the content
-                    let mut lines = back.lines();
-                    let term = lines
-                        .next()
-                        .with_context(|| format!("no term provided for {}", string))?
-                        .trim();
-                    let rest: String = lines.collect::<String>().trim().to_string();
+            Card::Basic(Basic {
+                front: String::new(),
+                back: back.join("\n"),
+                tags: Vec::new(),
+                raw_note: None,
+                extra: None,
+            })
+        } else if options.bare_term_as == BareTerm::Basic {
+            Card::Basic(Basic {
+                front: String::new(),
+                back: term_with_sentence(line.trim(), entries, options),
+                tags: Vec::new(),
+                raw_note: None,
+                extra: None,
+            })
+        } else {
+            let Some(sentence) = cloze_source(entries, line, options, warnings) else {
+                continue;
+            };
+            let Some(card) = cloze_line(sentence, line, terms.last_mut(), options, warnings)?
+            else {
+                continue;
+            };
+            card
+        };
+        if let Some(familiarity) = familiarity {
+            note.tags_mut().push(format!("familiarity::{familiarity}"));
+        }
+        if options.keep_raw {
+            push_raw_note(note.raw_note_mut(), raw_line);
+        }
+        terms.push(note);
+    }
+    if let Some(shared) = shared {
+        for card in &mut terms {
+            card.push_back_section(shared, options.back_spacing);
+        }
+    }
+    let (highlight, color_tag, location) = match entries.last() {
+        Some(Clipping::Highlight {
+            sentence,
+            color,
+            location,
+            ..
+        }) => (
+            Some(sentence.clone()),
+            color
+                .as_ref()
+                .and_then(|color| options.color_tags.get(color)),
+            *location,
+        ),
+        _ => (None, None, None),
+    };
+    if let (true, Some(location)) = (options.cite_location, location) {
+        for card in &mut terms {
+            card.push_back_section(&format!("(loc. {location})"), 1);
+        }
+    }
+    if options.cloze_reverse {
+        terms = terms
+            .into_iter()
+            .flat_map(|card| {
+                let reversed = card.reversed(
+                    highlight.as_deref().unwrap_or_default(),
+                    options.back_spacing,
+                );
+                std::iter::once(card).chain(reversed)
+            })
+            .collect();
+    }
+    for card in &mut terms {
+        if let (Some(window), Card::Cloze(cloze)) = (options.cloze_window, &mut *card) {
+            cloze.text = window_cloze(&cloze.text, window);
+        }
+        if options.cloze_context_field {
+            if let Card::Cloze(cloze) = card {
+                cloze.context = highlight.clone();
+            }
+        }
+        if let (Some(book_text), Some(highlight), Card::Cloze(_)) =
+            (&options.book_text, &highlight, &*card)
+        {
+            match book_context(highlight, book_text) {
+                Some(context) => card.push_back_section(&context, options.back_spacing),
+                None => warnings.push(format!("`{highlight}` isn't in the book text")),
+            }
+        }
+        card.fill_definition(&options.definitions);
+        if let Some(default_front) = &options.default_front {
+            card.fill_empty_front(default_front);
+        }
+        if let Some(color_tag) = color_tag {
+            card.tags_mut().push(sanitize_tag(color_tag));
+        }
+        if options.book_tags {
+            card.tags_mut().push(book_author_tag(book, author));
+        }
+        if options.book_slug_tags {
+            let tag = book_tag(book);
+            if !tag.is_empty() {
+                card.tags_mut().push(tag);
+            }
+        }
+        if options.age_based_scheduling {
+            card.tags_mut().push(age_tag(date, Utc::now()));
+        }
+        if options.read_date_tags {
+            card.tags_mut().extend(read_date_tags(date));
+        }
+        // after every other tag, so none of them is missed
+        for tag in card.tags_mut() {
+            tag.insert_str(0, &options.tag_prefix);
+        }
+        // the user's own tags, which need no namespace
+        for tag in &options.tags {
+            if !card.tags().contains(tag) {
+                card.tags_mut().push(tag.clone());
+            }
+        }
+    }
+    Ok(terms)
+}
 
-                    if rest.is_empty() {
-                        cards.push(Card::Basic(Basic {
-                            front,
-                            back: format!("{}<br><br>{}", term, sentence),
-                        }));
-                    } else {
-                        cards.push(Card::Basic(Basic {
-                            front,
-                            back: format!("{}<br><br>{}<br><br>{}", term, sentence, rest),
-                        }));
-                    }
-                }
+/// Splits the `Device: ...` / `Account: ...` lines some clippings files start
+/// with from the clippings after them, joining them into one description of
+/// where the file came from
+fn split_device_header(clippings_txt: &str) -> (Option<String>, &str) {
+    static RE_HEADER: OnceLock<Regex> = OnceLock::new();
+    let re_header =
+        RE_HEADER.get_or_init(|| Regex::new(r"^\u{feff}?(?P<line>(Device|Account): .+)$").unwrap());
+
+    let mut device = Vec::new();
+    let mut rest = clippings_txt;
+    while let Some((line, after)) = rest.split_once('\n') {
+        let Some(captures) = re_header.captures(line.trim_end()) else {
+            break;
+        };
+        device.push(captures["line"].to_string());
+        rest = after;
+    }
+    ((!device.is_empty()).then(|| device.join("; ")), rest)
+}
+
+/// Whether a clipping made at `date` is older than the start date. The start
+/// date is inclusive, so a clipping made exactly at midnight of it is kept,
+/// unless `--start-date-exclusive` or the date is the newest clipping of the
+/// last run, from `out/last-date.json`.
+pub(crate) fn before_start(
+    date: DateTime<Utc>,
+    date_after: Option<DateTime<Utc>>,
+    options: &ParseOptions,
+) -> bool {
+    match date_after {
+        Some(date_after) if options.start_date_exclusive => date <= date_after,
+        Some(date_after) => date < date_after,
+        None => false,
+    }
+}
+
+/// Converts a time on the local clock to UTC without panicking around daylight
+/// saving changes. A time that happens twice resolves to the first instant, and
+/// a time skipped by the clocks moving forward resolves to the first instant
+/// after the gap.
+pub(crate) fn local_to_utc(naive: NaiveDateTime) -> DateTime<Utc> {
+    resolve_local_time(naive, |naive| {
+        Local.from_local_datetime(naive).map(DateTime::<Utc>::from)
+    })
+}
+
+/// [local_to_utc] with the timezone lookup passed in, so daylight saving
+/// changes can be tested whatever the timezone of the machine
+fn resolve_local_time(
+    naive: NaiveDateTime,
+    from_local: impl Fn(&NaiveDateTime) -> LocalResult<DateTime<Utc>>,
+) -> DateTime<Utc> {
+    let step = chrono::Duration::minutes(15);
+    let mut candidate = naive;
+    // no timezone skips more than a day
+    for _ in 0..96 {
+        match from_local(&candidate) {
+            LocalResult::Single(date) => return date,
+            LocalResult::Ambiguous(earliest, _) => return earliest,
+            LocalResult::None => match candidate.checked_add_signed(step) {
+                Some(next) => candidate = next,
+                None => break,
+            },
+        }
+    }
+    // only reachable at the very edge of the representable dates
+    Utc.from_utc_datetime(&naive)
+}
+
+/// A clipping that can't be made sense of, with the line of the clippings file
+/// the problem is on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// the book and author line, or the line with the kind and date, is cut
+    /// short or not in a recognized shape
+    MalformedHeader {
+        line: usize,
+        raw: String,
+    },
+    UnrecognizedDate {
+        line: usize,
+        raw: String,
+    },
+    /// neither a highlight, a note nor a bookmark
+    UnknownAnnotationType {
+        line: usize,
+        kind: String,
+    },
+    /// a note line that can't be turned into a card
+    InvalidNote {
+        line: usize,
+        message: String,
+    },
+}
+
+impl ParseError {
+    /// The 1-based line of the clippings file the problem is on
+    pub fn line(&self) -> usize {
+        match self {
+            ParseError::MalformedHeader { line, .. }
+            | ParseError::UnrecognizedDate { line, .. }
+            | ParseError::UnknownAnnotationType { line, .. }
+            | ParseError::InvalidNote { line, .. } => *line,
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::MalformedHeader { line, raw } => {
+                write!(f, "line {line}: malformed clipping header `{raw}`")
             }
-            _ => {
-                bail!(
-                    "invalid card sequence detected `{}` at line number {}",
-                    line,
-                    number
+            ParseError::UnrecognizedDate { line, raw } => write!(
+                f,
+                "line {line}: unable to parse the date `{raw}`. Pass its format with --date-format"
+            ),
+            ParseError::UnknownAnnotationType { line, kind } => {
+                write!(
+                    f,
+                    "line {line}: unexpected type of kindle annotation `{kind}`"
                 )
             }
+            ParseError::InvalidNote { line, message } => write!(f, "line {line}: {message}"),
         }
     }
+}
 
-    let metadata: Vec<Clipping> =
-        serde_json::from_str(&fs::read_to_string("out/output-metadata.json")?)?;
+impl std::error::Error for ParseError {}
 
-    let output = Output {
-        cards,
-        begin_date: match metadata
-            .first()
-            .context("no first element in output-metadata.json")?
-        {
-            Clipping::Highlight { date, .. } => *date,
-            Clipping::Note { date, .. } => *date,
-        },
-        end_date: match metadata
-            .last()
-            .context("no last element in output-metadata.json")?
-        {
-            Clipping::Highlight { date, .. } => *date,
-            Clipping::Note { date, .. } => *date,
-        },
-    };
+/// Parses clippings with the default [ParseOptions], stopping at the first
+/// clipping that can't be made sense of instead of skipping it. Malformed input
+/// of any kind is returned as an error, never a panic, which makes this the
+/// entry point for fuzzing (see `fuzz/`).
+pub fn try_parse(clippings_txt: &str) -> Result<Vec<Clipping>, ParseError> {
+    parse_clippings(
+        clippings_txt,
+        None,
+        &ParseOptions::default(),
+        &mut Warnings::default(),
+        true,
+    )
+}
 
-    fs::write("out/output.json", serde_json::to_string(&output).unwrap()).with_context(|| {
-        "Unable to write to final output file from cards .md to `out.json` for some reason."
+/// Parses the lines of one clipping, between two `==========`s, adding it to
+/// `entries`. A note replaces the highlight before it, which it pulls its
+/// sentence from. `line_number` is the line of the file the block starts on.
+fn parse_block(
+    (line_number, block): (usize, &[&str]),
+    entries: &mut Vec<Clipping>,
+    date_after: Option<DateTime<Utc>>,
+    options: &ParseOptions,
+    warnings: &mut Warnings,
+) -> Result<(), ParseError> {
+    static RE_AUTHOR_BOOK: OnceLock<Regex> = OnceLock::new();
+    static RE_HEADER: OnceLock<Regex> = OnceLock::new();
+    static RE_COLOR: OnceLock<Regex> = OnceLock::new();
+    static RE_PAGE: OnceLock<Regex> = OnceLock::new();
+    static RE_LOCATION: OnceLock<Regex> = OnceLock::new();
+    // the author is the last parenthesis, so `Dune (Book 1) (Frank Herbert)` is
+    // still a book by Frank Herbert
+    let re_author_book = RE_AUTHOR_BOOK
+        .get_or_init(|| Regex::new(r"^(?P<book>.+) \((?P<author>[^()]+)\)$").unwrap());
+    // the date is always after the last `|`, in whatever language the kindle uses
+    let re_header =
+        RE_HEADER.get_or_init(|| Regex::new(r"^- (?P<head>.+)\|(?P<added>[^|]+)$").unwrap());
+    let re_color = RE_COLOR.get_or_init(|| Regex::new(r"\((?P<color>\p{L}+)\)").unwrap());
+    let re_page = RE_PAGE.get_or_init(|| {
+        Regex::new(r"(?i)\b(page|seite|página) (?P<page>\d+)|(?P<page_ja>\d+)ページ").unwrap()
+    });
+    let re_location = RE_LOCATION.get_or_init(|| {
+        Regex::new(r"(?i)(location|position|emplacement|posición|位置No\.) ?(?P<location>\d+)")
+            .unwrap()
+    });
+
+    let mut iter = block.iter().copied().peekable();
+    let line_1 = iter.next().unwrap_or_default();
+    // first line is always the book and author, though sideloaded books often
+    // have no author, leaving it empty
+    let (book, author) = {
+        trace!("{line_1}");
+        let line_1 = line_1.trim();
+        if line_1.is_empty() {
+            return Err(ParseError::MalformedHeader {
+                line: line_number,
+                raw: line_1.to_string(),
+            });
+        }
+        match re_author_book.captures(line_1) {
+            Some(captures) => (captures["book"].to_string(), captures["author"].to_string()),
+            None => (line_1.to_string(), String::new()),
+        }
+    };
+    // a clipping that ends before its date line has an empty one
+    let line_2 = iter.next().unwrap_or_default();
+    let captures = re_header
+        .captures(line_2)
+        .ok_or_else(|| ParseError::MalformedHeader {
+            line: line_number + 1,
+            raw: line_2.to_string(),
+        })?;
+    let head = &captures["head"];
+    let highlight_or_note =
+        locale::annotation_kind(head).map_or_else(|| head.trim().to_string(), str::to_string);
+    // e.g. `- Your Highlight (Pink) on page 3 | ...`
+    let color = re_color
+        .captures(head)
+        .map(|color| color["color"].to_lowercase());
+    // e.g. `on page 12 | Location 170-171`, where either can be missing
+    let page = re_page.captures(head).and_then(|page| {
+        page.name("page")
+            .or_else(|| page.name("page_ja"))
+            .and_then(|page| page.as_str().parse::<u32>().ok())
+    });
+    let location = re_location
+        .captures(head)
+        .and_then(|location| location["location"].parse::<u32>().ok());
+    // e.g. Added on Saturday, November 24, 2018 11:31:30 AM
+    let added = &captures["added"];
+    let naive = locale::parse_date(added, options.date_format.as_deref()).ok_or_else(|| {
+        ParseError::UnrecognizedDate {
+            line: line_number + 1,
+            raw: added.trim().to_string(),
+        }
     })?;
+    let date = local_to_utc(naive);
+
+    let too_old = before_start(date, date_after, options);
+    let excluded = options
+        .exclude_books
+        .iter()
+        .any(|excluded| book.contains(excluded.as_str()));
+    if too_old || excluded {
+        return Ok(());
+    }
+
+    // the header is followed by a blank line, which some devices leave out
+    let mut content_line = line_number + 2;
+    if iter.peek().is_some_and(|line| line.trim().is_empty()) {
+        iter.next();
+        content_line += 1;
+    }
+    let content: Vec<&str> = iter.collect();
+
+    match highlight_or_note.as_str() {
+        "Highlight" => {
+            // drop blank lines around the highlight, but keep the ones
+            // separating the paragraphs of a longer passage
+            let start = content
+                .iter()
+                .position(|line| !line.trim().is_empty())
+                .unwrap_or(content.len());
+            let end = content
+                .iter()
+                .rposition(|line| !line.trim().is_empty())
+                .map_or(start, |end| end + 1);
+            let mut sentence = content[start..end].join("\n");
+            if options.collapse_whitespace {
+                sentence = collapse_whitespace(&sentence);
+            }
+            entries.push(Clipping::Highlight {
+                book,
+                author,
+                date,
+                sentence,
+                color,
+                page,
+                location,
+            });
+        }
+        "Note" => {
+            // every line is a term
+            let mut note_lines = Vec::new();
+            for line in content {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match &options.bullet_split {
+                    Some(marker) => note_lines.extend(split_bullets(line, marker)),
+                    None => note_lines.push(line.to_string()),
+                }
+            }
+
+            // bookmarks made after the highlight don't separate it from its note
+            let noted = entries
+                .iter()
+                .rposition(|entry| !matches!(entry, Clipping::Bookmark { .. }))
+                .map_or(0, |index| index + 1);
+            let terms = note_cards(
+                &note_lines,
+                &entries[..noted],
+                (&book, &author, date),
+                options,
+                warnings,
+            )
+            .map_err(|error| ParseError::InvalidNote {
+                line: content_line,
+                message: format!("{error:#}"),
+            })?;
+            if terms.is_empty() {
+                warnings.push(format!("note in `{book}` from {date} produced no cards"));
+            }
+            // the note replaces the highlight it was made on, but not an
+            // earlier note
+            if let Some(Clipping::Highlight { .. }) = noted.checked_sub(1).map(|i| &entries[i]) {
+                entries.remove(noted - 1);
+            }
+            entries.push(Clipping::Note {
+                book,
+                author,
+                date,
+                cards: terms,
+                page,
+                location,
+            });
+        }
+        // bookmarks have no content to make cards from, but say where
+        // reading stopped
+        "Bookmark" => entries.push(Clipping::Bookmark {
+            book,
+            author,
+            date,
+            location,
+        }),
+        kind => {
+            return Err(ParseError::UnknownAnnotationType {
+                line: line_number + 1,
+                kind: kind.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Function which takes in input from the raw clippings file and returns clippings
+pub fn parse_from_txt(
+    clippings_txt: &str,
+    date_after: Option<DateTime<Utc>>,
+    options: &ParseOptions,
+    warnings: &mut Warnings,
+) -> Result<Vec<Clipping>, Error> {
+    Ok(parse_clippings(
+        clippings_txt,
+        date_after,
+        options,
+        warnings,
+        false,
+    )?)
+}
+
+/// Parses a clippings file. Unless `strict`, clippings that can't be made
+/// sense of are skipped with a warning instead of failing the whole file.
+fn parse_clippings(
+    clippings_txt: &str,
+    date_after: Option<DateTime<Utc>>,
+    options: &ParseOptions,
+    warnings: &mut Warnings,
+    strict: bool,
+) -> Result<Vec<Clipping>, ParseError> {
+    // store all entries
+    let mut entries = Vec::new();
+
+    let original_txt = clippings_txt;
+
+    let (_, clippings_txt) = split_device_header(clippings_txt);
+    let header_lines = original_txt[..original_txt.len() - clippings_txt.len()]
+        .lines()
+        .count();
+
+    // split into `==========`-separated blocks first, so a malformed block can
+    // be skipped without losing track of where the next one starts
+    let mut blocks = Vec::new();
+    let mut block = Vec::new();
+    let mut block_start = header_lines + 1;
+    for (index, line) in clippings_txt.lines().enumerate() {
+        if line.contains("==========") {
+            blocks.push((block_start, std::mem::take(&mut block)));
+            block_start = header_lines + index + 2;
+        } else {
+            block.push(line);
+        }
+    }
+    blocks.push((block_start, block));
+
+    for (line_number, block) in blocks {
+        if block.iter().all(|line| line.trim().is_empty()) {
+            continue;
+        }
+        match parse_block(
+            (line_number, &block),
+            &mut entries,
+            date_after,
+            options,
+            warnings,
+        ) {
+            Err(error) if strict => return Err(error),
+            Err(error) => warnings.push(format!("skipping clipping at {error}")),
+            Ok(()) => {}
+        }
+    }
+    // dbg!("hello world", &entries);
+
+    // highlights are still needed above for notes to pull sentences from, so
+    // they can only be dropped once everything is parsed
+    if options.notes_only {
+        entries.retain(|entry| !matches!(entry, Clipping::Highlight { .. }));
+    }
+
+    Ok(entries)
+}
+
+/// Everything [convert] makes of the clippings, for the caller to write
+#[derive(Debug)]
+pub struct ConvertOutput {
+    /// the markdown output, see [entries_to_markdown]
+    pub markdown: String,
+    /// the clippings as JSON, which `--from-metadata` and
+    /// `--new-books-only` read back
+    pub metadata: String,
+    pub entries: Vec<Clipping>,
+    pub warnings: Warnings,
+    /// the hash of each book's clippings, when
+    /// [ConvertOptions::previous_book_hashes] was set
+    pub book_hashes: Option<BTreeMap<String, String>>,
+}
+
+/// Turns `clippings`, in the format of [ConvertOptions::source], into the
+/// markdown output and its metadata, without reading or writing any file.
+/// The Kindle for Mac database can't be passed as text.
+pub fn convert(clippings: &str, options: &ConvertOptions) -> Result<ConvertOutput, Error> {
+    let mut warnings = Warnings::default();
+    let mut entries = parse_source(clippings, options, &mut warnings)?;
+    dedup_highlights(&mut entries);
+    convert_entries(entries, warnings, options)
+}
+
+/// Parses `clippings` in the format of [ConvertOptions::source]
+fn parse_source(
+    clippings: &str,
+    options: &ConvertOptions,
+    warnings: &mut Warnings,
+) -> Result<Vec<Clipping>, Error> {
+    let ConvertOptions {
+        date_after,
+        parse_options,
+        ..
+    } = options;
+    match options.source {
+        Source::Kindle => parse_from_txt(clippings, *date_after, parse_options, warnings),
+        Source::Readwise => {
+            readwise::parse_from_json(clippings, *date_after, parse_options, warnings)
+        }
+        Source::Extract => {
+            extract::parse_from_extract(clippings, *date_after, parse_options, warnings)
+        }
+        Source::KindleMacDb => {
+            bail!("the Kindle for Mac database is read from its path, not passed as text")
+        }
+    }
+}
+
+/// Fails if any cloze term wasn't found in its highlight
+fn require_cloze_matches(warnings: &Warnings) -> Result<(), Error> {
+    if warnings.unmatched().is_empty() {
+        return Ok(());
+    }
+    let unmatched: Vec<String> = warnings
+        .unmatched()
+        .iter()
+        .map(|(term, sentence)| format!("`{term}` in `{sentence}`"))
+        .collect();
+    bail!(
+        "{} cloze term(s) aren't in their highlight, failing because of --require-cloze-match:\n{}",
+        unmatched.len(),
+        unmatched.join("\n")
+    );
+}
+
+/// The rest of [convert], for clippings that were parsed already
+pub fn convert_entries(
+    mut entries: Vec<Clipping>,
+    mut warnings: Warnings,
+    options: &ConvertOptions,
+) -> Result<ConvertOutput, Error> {
+    if options.require_cloze_match {
+        require_cloze_matches(&warnings)?;
+    }
+
+    if !options.skip_books.is_empty() {
+        entries.retain(|entry| !options.skip_books.contains(entry.book()));
+        info!(
+            "kept {} clippings from books not in the previous run",
+            entries.len()
+        );
+    }
+
+    // hashed before skipping, so the books stay recorded
+    let book_hashes = options.previous_book_hashes.as_ref().map(|previous| {
+        let hashes = book_hashes(&entries);
+        let unchanged: BTreeSet<&String> = hashes
+            .iter()
+            .filter(|(book, hash)| previous.get(*book) == Some(*hash))
+            .map(|(book, _)| book)
+            .collect();
+        for book in &unchanged {
+            info!("unchanged: `{book}`");
+        }
+        entries.retain(|entry| !unchanged.contains(&entry.book().to_string()));
+        hashes
+    });
+
+    if options.warn_duplicate_cards || options.dedupe_cards {
+        check_duplicate_cards(&mut entries, options.dedupe_cards, &mut warnings);
+    }
+
+    // numbered in file order, before it is changed below
+    renumber_clozes(&mut entries, options.reset_cloze_per);
+
+    // notes already own their cards, so shuffling or reversing
+    // clippings never separates a card from its sentence
+    if let Some(seed) = options.shuffle_seed {
+        entries.shuffle(&mut StdRng::seed_from_u64(seed));
+    }
+    if options.reverse_order {
+        entries.reverse();
+    }
+
+    if !options.sounds.is_empty() {
+        attach_sounds(&mut entries, &options.sounds);
+    }
+
+    if let Some(min_cards) = options.require_min_cards {
+        let counts = ClippingCounts::from_entries(&entries);
+        let cards = counts.cloze_cards + counts.basic_cards;
+        if cards < min_cards {
+            bail!("only {cards} cards were made, fewer than the {min_cards} required by --require-min-cards. Nothing was written");
+        }
+    }
+
+    Ok(ConvertOutput {
+        markdown: entries_to_markdown(&entries, &options.card_template),
+        metadata: to_stable_json(&entries)?,
+        entries,
+        warnings,
+        book_hashes,
+    })
+}
+
+pub fn convert_config_to_finished_app(config: Config) -> Result<(), Error> {
+    match config {
+        Config::Regular {
+            clippings_path,
+            output_file_name,
+            out_dir,
+            mut convert_options,
+            fail_on_warning,
+            encoding,
+            output_encoding,
+            list_terms: should_list_terms,
+            archive,
+            max_file_size,
+            media_dir,
+            new_books_only,
+            skip_unchanged_books,
+            echo_cards,
+            extract,
+            stats,
+            dry_run,
+        } => {
+            let source = convert_options.source;
+            // refuse to load something that clearly isn't a clippings file,
+            // like a video picked by mistake, instead of running out of memory
+            if let Ok(metadata) = fs::metadata(&clippings_path) {
+                if metadata.len() > max_file_size {
+                    bail!(
+                        "{clippings_path:?} is {} bytes, larger than the maximum of {max_file_size} bytes. Is it really a clippings file? Raise the limit with --max-file-size",
+                        metadata.len()
+                    );
+                }
+            }
+            // the Kindle for Mac database is read by sqlite instead
+            let clippings_txt = if source == Source::KindleMacDb {
+                String::new()
+            } else {
+                read_clippings(&clippings_path, encoding)?
+            };
+
+            let mut warnings = Warnings::default();
+            let mut entries = match source {
+                Source::KindleMacDb => parse_from_kindle_mac_db(
+                    &clippings_path,
+                    convert_options.date_after,
+                    &convert_options.parse_options,
+                    &mut warnings,
+                )?,
+                Source::Kindle | Source::Readwise | Source::Extract => {
+                    parse_source(&clippings_txt, &convert_options, &mut warnings)?
+                }
+            };
+            dedup_highlights(&mut entries);
+
+            if stats || extract {
+                if convert_options.require_cloze_match {
+                    require_cloze_matches(&warnings)?;
+                }
+                if stats {
+                    let stats_path = out_dir.join("stats.json");
+                    write_file(
+                        &stats_path,
+                        &to_stable_json(&stats::stats(&entries))?,
+                        dry_run,
+                    )?;
+                    info!(
+                        "wrote statistics of {} clippings to {stats_path:?}",
+                        entries.len()
+                    );
+                } else {
+                    let extract_path = out_dir.join("extract.md");
+                    write(
+                        extract::to_extract(&entries),
+                        &extract_path,
+                        OutputEncoding::default(),
+                        dry_run,
+                    )?;
+                    info!("wrote highlights to {extract_path:?}. Add terms under them, then run with `--build {}`", extract_path.display());
+                }
+                return Ok(());
+            }
+
+            let seen_books_path = out_dir.join(SEEN_BOOKS_FILE);
+            if new_books_only {
+                convert_options.skip_books = read_seen_books(&seen_books_path)?;
+            }
+            let book_hashes_path = out_dir.join(BOOK_HASHES_FILE);
+            if skip_unchanged_books {
+                convert_options.previous_book_hashes = Some(read_book_hashes(&book_hashes_path)?);
+            }
+            if let Some(media_dir) = &media_dir {
+                convert_options.sounds = sound_files(media_dir)?;
+            }
+
+            let ConvertOutput {
+                markdown,
+                metadata,
+                entries,
+                warnings,
+                book_hashes,
+            } = convert_entries(entries, warnings, &convert_options)?;
+
+            if echo_cards {
+                for entry in &entries {
+                    let Clipping::Note { cards, .. } = entry else {
+                        continue;
+                    };
+                    for card in cards {
+                        let (front, back) = card.export_fields();
+                        info!("card:\n{front}\n---\n{back}");
+                    }
+                }
+            }
+
+            let mut outputs = vec![output_file_name.clone()];
+            write(
+                markdown,
+                Path::new(&output_file_name),
+                output_encoding,
+                dry_run,
+            )?;
+
+            let metadata_path = out_dir.join("output-metadata.json");
+            write(metadata, &metadata_path, OutputEncoding::default(), dry_run)?;
+            outputs.push(metadata_path.display().to_string());
+            // so the next run only picks up newer clippings. A run without
+            // any clippings leaves the previous date alone
+            let newest = entries.iter().map(Clipping::date).max();
+            let last_date_path = out_dir.join("last-date.json");
+
+            if should_list_terms {
+                let terms = list_terms(&entries);
+                let mut terms_txt = terms.join("\n");
+                terms_txt.push('\n');
+                let terms_path = out_dir.join("terms.txt");
+                write_file(&terms_path, &terms_txt, dry_run)
+                    .with_context(|| format!("unable to write terms to {terms_path:?}"))?;
+                info!("wrote {} terms to {terms_path:?}", terms.len());
+                outputs.push(terms_path.display().to_string());
+            }
+
+            let unmatched_path = out_dir.join("unmatched.csv");
+            write_file(
+                &unmatched_path,
+                &unmatched_csv(warnings.unmatched()),
+                dry_run,
+            )
+            .with_context(|| format!("unable to write unmatched terms to {unmatched_path:?}"))?;
+            outputs.push(unmatched_path.display().to_string());
+
+            // a run failing because of its warnings stops after the manifest,
+            // so it only lists the files written up to there
+            let failed = fail_on_warning && !warnings.is_empty();
+            if !failed {
+                if book_hashes.is_some() {
+                    outputs.push(book_hashes_path.display().to_string());
+                }
+                if new_books_only {
+                    outputs.push(seen_books_path.display().to_string());
+                }
+                if archive && source == Source::Kindle {
+                    outputs.push(out_dir.join(ARCHIVE_FILE).display().to_string());
+                }
+                if newest.is_some() {
+                    outputs.push(last_date_path.display().to_string());
+                }
+            }
+            let manifest_path = out_dir.join("manifest.json");
+            outputs.push(manifest_path.display().to_string());
+
+            let manifest = Manifest {
+                input: clippings_path.to_string_lossy().to_string(),
+                date_after: convert_options.date_after,
+                counts: ClippingCounts::from_entries(&entries),
+                outputs,
+                created: Utc::now(),
+                device: match source {
+                    Source::Kindle => split_device_header(&clippings_txt).0,
+                    Source::Readwise | Source::Extract | Source::KindleMacDb => None,
+                },
+            };
+            write_file(&manifest_path, &to_stable_json(&manifest)?, dry_run)
+                .with_context(|| format!("unable to write run manifest to {manifest_path:?}"))?;
+
+            if failed {
+                bail!(
+                    "{} warning(s) occurred while converting clippings, failing because of --fail-on-warning",
+                    warnings.len()
+                );
+            }
+
+            if let Some(hashes) = book_hashes {
+                let mut recorded = convert_options.previous_book_hashes.unwrap_or_default();
+                recorded.extend(hashes);
+                write_file(&book_hashes_path, &to_stable_json(&recorded)?, dry_run)
+                    .with_context(|| format!("unable to write {book_hashes_path:?}"))?;
+            }
+
+            if new_books_only {
+                // every run only has its own clippings, so the books seen
+                // before it are carried over
+                let mut seen = convert_options.skip_books;
+                seen.extend(entries.iter().map(|entry| entry.book().to_string()));
+                write_file(&seen_books_path, &to_stable_json(&seen)?, dry_run)
+                    .with_context(|| format!("unable to write {seen_books_path:?}"))?;
+            }
+
+            if archive {
+                match source {
+                    Source::Kindle if dry_run => {
+                        println!(
+                            "[dry-run] would append the clippings to {:?}",
+                            out_dir.join(ARCHIVE_FILE)
+                        )
+                    }
+                    Source::Kindle => archive_clippings(&clippings_txt, &out_dir)?,
+                    Source::Readwise | Source::Extract | Source::KindleMacDb => {
+                        warn!("--archive only keeps kindle clippings, not archiving {clippings_path:?}")
+                    }
+                }
+            }
+
+            // last, so a run that fails anywhere is run again from the same date
+            if let Some(date) = newest {
+                write_file(
+                    &last_date_path,
+                    &to_stable_json(&LastDate { date })?,
+                    dry_run,
+                )
+                .with_context(|| format!("unable to write {last_date_path:?}"))?;
+                info!("the next run starts after {date}, the date of the newest clipping");
+            }
+        }
+        Config::Regenerate {
+            output_file_name,
+            out_dir,
+            card_template,
+            output_encoding,
+            dry_run,
+        } => {
+            let metadata_path = out_dir.join("output-metadata.json");
+            let metadata = fs::read_to_string(&metadata_path)
+                .with_context(|| format!("unable to read {metadata_path:?}"))?;
+            let entries: Vec<Clipping> = serde_json::from_str(&metadata)
+                .with_context(|| format!("unable to read the clippings in {metadata_path:?}"))?;
+            write(
+                entries_to_markdown(&entries, &card_template),
+                Path::new(&output_file_name),
+                output_encoding,
+                dry_run,
+            )?;
+            info!(
+                "wrote {} clippings from {metadata_path:?} to {output_file_name:?}",
+                entries.len()
+            );
+        }
+        Config::Validate {
+            output_file_name,
+            out_dir,
+            back_spacing,
+            strict,
+            card_template,
+            format,
+            reset_cloze_per,
+            dry_run,
+        } => {
+            validate(
+                output_file_name,
+                &out_dir,
+                back_spacing,
+                strict,
+                &card_template,
+                format,
+                reset_cloze_per,
+                dry_run,
+            )?;
+        }
+        Config::Package {
+            output_file_name,
+            back_spacing,
+            deck,
+            apkg_path,
+            card_template,
+            append,
+        } => {
+            let (cards, _) = read_output_cards(&output_file_name, back_spacing, &card_template)?;
+            if append {
+                let added = append_package(&cards, &deck, Path::new(&apkg_path))?;
+                info!(
+                    "added {added} of {} cards to the deck `{deck}` in {apkg_path:?}, the rest were in it already",
+                    cards.len()
+                );
+            } else {
+                write_package(&cards, &deck, Path::new(&apkg_path))?;
+                info!(
+                    "wrote {} cards to the deck `{deck}` in {apkg_path:?}",
+                    cards.len()
+                );
+            }
+        }
+        Config::Push {
+            output_file_name,
+            back_spacing,
+            deck,
+            connect_url,
+            card_template,
+        } => {
+            let (cards, _) = read_output_cards(&output_file_name, back_spacing, &card_template)?;
+            push_cards(&cards, &deck, &connect_url)?;
+        }
+    }
 
     Ok(())
 }
+
+/// Collapses highlights of the same sentence in the same book, which kindles
+/// and calibre write again when a passage is highlighted twice, into the first
+/// of them, keeping the earliest date. Everything else stays in order.
+pub fn dedup_highlights(entries: &mut Vec<Clipping>) {
+    let entries_len = entries.len();
+    let mut first: HashMap<(String, String, String), usize> = HashMap::new();
+    let mut kept: Vec<Clipping> = Vec::with_capacity(entries.len());
+    for entry in entries.drain(..) {
+        let Clipping::Highlight {
+            book,
+            author,
+            sentence,
+            date,
+            ..
+        } = &entry
+        else {
+            kept.push(entry);
+            continue;
+        };
+        let key = (book.clone(), author.clone(), sentence.clone());
+        match first.get(&key) {
+            Some(&index) => {
+                if let Clipping::Highlight {
+                    date: first_date, ..
+                } = &mut kept[index]
+                {
+                    *first_date = (*first_date).min(*date);
+                }
+            }
+            None => {
+                first.insert(key, kept.len());
+                kept.push(entry);
+            }
+        }
+    }
+    let removed = entries_len - kept.len();
+    if removed > 0 {
+        info!("dropped {removed} duplicate highlights");
+    }
+    *entries = kept;
+}
+
+/// Warns about cards with the same front and back as a card before them, e.g.
+/// the same word clozed in two identical highlights, and drops them if
+/// `remove`
+pub fn check_duplicate_cards(entries: &mut [Clipping], remove: bool, warnings: &mut Warnings) {
+    // the book of the first card with each front and back
+    let mut seen: HashMap<(String, String), String> = HashMap::new();
+    for entry in entries {
+        let Clipping::Note { book, cards, .. } = entry else {
+            continue;
+        };
+        cards.retain(|card| {
+            let (front, back) = card.export_fields();
+            let fields = (front.to_string(), back.to_string());
+            match seen.get(&fields) {
+                Some(first_book) => {
+                    warnings.push(format!(
+                        "card `{front}` in `{book}` duplicates one in `{first_book}`{}",
+                        if remove { ", dropping it" } else { "" }
+                    ));
+                    !remove
+                }
+                None => {
+                    seen.insert(fields, book.clone());
+                    true
+                }
+            }
+        });
+    }
+}
+
+/// The markdown output of `entries`: highlights between `========` lines, and
+/// the cards of notes between `----` lines, laid out by `card_template`, for
+/// definitions to be added to before `--validate`
+pub fn entries_to_markdown(entries: &[Clipping], card_template: &CardTemplate) -> String {
+    let mut out_string = String::new();
+
+    // separate entries into
+    for entry in entries {
+        match entry {
+            // if it's a highlight, don't even add a bullet, just insert the sentence
+            Clipping::Highlight { sentence, .. } => {
+                let sentence = format::escape(sentence);
+                out_string.push_str(&format!(
+                    "{SENTENCE}\n{sentence}\n{SENTENCE}\n",
+                    SENTENCE = format::SENTENCE
+                ));
+            }
+            // otherwise, for notes,
+            Clipping::Note { cards, .. } => {
+                for card in cards {
+                    // tags go on their own `|#` line, which `validate` reads back
+                    let tag_line = if card.tags().is_empty() {
+                        String::new()
+                    } else {
+                        format!("{} {}\n", format::TAGS, card.tags().join(" "))
+                    };
+                    match card {
+                        Card::Cloze(Cloze {
+                            text,
+                            back_extra,
+                            context,
+                            ..
+                        }) => {
+                            // and the context on `|>` lines
+                            let context_lines = context
+                                .iter()
+                                .flat_map(|context| context.lines())
+                                .map(|line| format!("{} {line}\n", format::CONTEXT))
+                                .collect::<String>();
+                            out_string.push_str(&format!(
+                                "{CARD}\n{}{context_lines}{tag_line}{CARD}\n",
+                                card_template
+                                    .render(&format::escape(text), &format::escape(back_extra)),
+                                CARD = format::CARD
+                            ));
+                        }
+                        Card::Basic(Basic { front, back, .. }) => {
+                            out_string.push_str(&format!(
+                                "{CARD}\n{}{tag_line}{CARD}\n",
+                                card_template.render(&format::escape(front), &format::escape(back)),
+                                CARD = format::CARD
+                            ));
+                        }
+                    }
+                }
+            }
+            // bookmarks only go in the metadata
+            Clipping::Bookmark { .. } => {}
+        }
+    }
+    out_string
+}
+
+fn is_sentence_end(c: char) -> bool {
+    matches!(c, '.' | '!' | '?' | '…')
+}
+
+/// The sentences just before and after `highlight` in `book_text`, whose
+/// whitespace is collapsed, as `before … after`, or `None` if the highlight
+/// isn't in the book. A highlight that starts or ends inside a sentence gets
+/// the rest of that sentence instead.
+pub fn book_context(highlight: &str, book_text: &str) -> Option<String> {
+    let highlight = highlight.split_whitespace().collect::<Vec<_>>().join(" ");
+    if highlight.is_empty() {
+        return None;
+    }
+    let start = book_text.find(&highlight)?;
+    let end = start + highlight.len();
+
+    let head = book_text[..start].trim_end();
+    let head_start = head
+        .trim_end_matches(is_sentence_end)
+        .rfind(is_sentence_end)
+        .map_or(0, |i| i + 1);
+    let before = head[head_start..].trim();
+
+    let tail = book_text[end..].trim_start();
+    let tail_end = tail
+        .char_indices()
+        .skip_while(|(_, c)| is_sentence_end(*c))
+        .find(|(_, c)| is_sentence_end(*c))
+        .map_or(tail.len(), |(i, c)| i + c.len_utf8());
+    let after = tail[..tail_end].trim();
+
+    match (before.is_empty(), after.is_empty()) {
+        (true, true) => None,
+        (false, true) => Some(format!("{before} …")),
+        (true, false) => Some(format!("… {after}")),
+        (false, false) => Some(format!("{before} … {after}")),
+    }
+}
+
+/// Replaces the matches of `re_term` in `text` with the cloze `replacement`,
+/// or only the first one with [ClozeScope::First]
+fn cloze_matches(re_term: &Regex, text: &str, replacement: &str, scope: ClozeScope) -> String {
+    match scope {
+        ClozeScope::First => re_term.replace(text, replacement),
+        ClozeScope::All => re_term.replace_all(text, replacement),
+    }
+    .to_string()
+}
+
+/// Shifts the cloze numbers of each card to continue from the card before it,
+/// starting over at `c1` per [ClozeReset]
+pub fn renumber_clozes(entries: &mut [Clipping], reset: ClozeReset) {
+    static RE_INDEX: OnceLock<Regex> = OnceLock::new();
+    let re_index = RE_INDEX.get_or_init(|| Regex::new(r"\{\{c(?P<index>\d+)::").unwrap());
+    if reset == ClozeReset::Card {
+        return;
+    }
+
+    let mut offset = 0;
+    let mut last_book: Option<String> = None;
+    for entry in entries {
+        let Clipping::Note { book, cards, .. } = entry else {
+            continue;
+        };
+        let new_book = last_book.as_deref() != Some(book.as_str());
+        if reset == ClozeReset::Note || (reset == ClozeReset::Book && new_book) {
+            offset = 0;
+        }
+        last_book = Some(book.clone());
+
+        for card in cards {
+            let Card::Cloze(cloze) = card else {
+                continue;
+            };
+            let mut highest = 0;
+            cloze.text = re_index
+                .replace_all(&cloze.text, |captures: &regex::Captures| {
+                    let index = captures["index"].parse::<usize>().unwrap_or(1);
+                    highest = highest.max(index);
+                    format!("{{{{c{}::", index + offset)
+                })
+                .to_string();
+            offset += highest;
+        }
+    }
+}
+
+/// Audio file types Anki can play, in the order they are looked for
+const SOUND_EXTENSIONS: [&str; 3] = ["mp3", "ogg", "wav"];
+
+/// Adds a `[sound:...]` reference to the back of every card with a recording
+/// of one of its terms, e.g. `hill.mp3`, in `media_dir`. The files still have
+/// to be copied into Anki's media folder.
+pub fn attach_sounds(entries: &mut [Clipping], sounds: &BTreeSet<String>) {
+    for entry in entries {
+        let Clipping::Note { cards, .. } = entry else {
+            continue;
+        };
+        for card in cards {
+            for term in card.terms() {
+                let sound = SOUND_EXTENSIONS
+                    .iter()
+                    .map(|extension| format!("{term}.{extension}"))
+                    .find(|file_name| sounds.contains(file_name));
+                if let Some(sound) = sound {
+                    card.push_back_section(&format!("[sound:{sound}]"), 1);
+                }
+            }
+        }
+    }
+}
+
+/// Names of the files in `media_dir`, for [attach_sounds]
+pub fn sound_files(media_dir: &Path) -> Result<BTreeSet<String>, Error> {
+    let mut sounds = BTreeSet::new();
+    for file in fs::read_dir(media_dir).with_context(|| format!("unable to read {media_dir:?}"))? {
+        let file = file.with_context(|| format!("unable to read {media_dir:?}"))?;
+        if file.path().is_file() {
+            sounds.insert(file.file_name().to_string_lossy().to_string());
+        }
+    }
+    Ok(sounds)
+}
+
+/// Where `--new-books-only` records the titles of every book it has made cards
+/// from, in the output directory
+const SEEN_BOOKS_FILE: &str = "seen-books.json";
+
+/// Titles of the books recorded by previous runs with `--new-books-only`. No
+/// previous run means no books have been seen yet.
+fn read_seen_books(path: &Path) -> Result<BTreeSet<String>, Error> {
+    let Ok(books) = fs::read_to_string(path) else {
+        info!("no books recorded at {path:?}, every book is new");
+        return Ok(BTreeSet::new());
+    };
+    serde_json::from_str(&books).with_context(|| format!("unable to read seen books from {path:?}"))
+}
+
+/// Where `--skip-unchanged-books` records the hash of each book's clippings,
+/// in the output directory
+const BOOK_HASHES_FILE: &str = "book-hashes.json";
+
+/// A hash of the clippings of each book among `entries`, which changes when
+/// anything made from them would
+pub fn book_hashes(entries: &[Clipping]) -> BTreeMap<String, String> {
+    let mut books: BTreeMap<&str, Vec<&Clipping>> = BTreeMap::new();
+    // bookmarks make nothing, so a new one doesn't change the book
+    for entry in entries
+        .iter()
+        .filter(|entry| !matches!(entry, Clipping::Bookmark { .. }))
+    {
+        books.entry(entry.book()).or_default().push(entry);
+    }
+    books
+        .into_iter()
+        .map(|(book, clippings)| {
+            // serde_json always writes the same clippings the same way
+            let json = serde_json::to_string(&clippings).unwrap_or_default();
+            (book.to_string(), format!("{:016x}", fnv1a(json.as_bytes())))
+        })
+        .collect()
+}
+
+/// 64-bit FNV-1a, which unlike std's hasher stays the same across Rust versions
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// The hashes recorded by a previous run with `--skip-unchanged-books`, if any
+fn read_book_hashes(path: &Path) -> Result<BTreeMap<String, String>, Error> {
+    let Ok(hashes) = fs::read_to_string(path) else {
+        return Ok(BTreeMap::new());
+    };
+    serde_json::from_str(&hashes)
+        .with_context(|| format!("unable to read book hashes from {path:?}"))
+}
+
+/// Number of single-character edits turning `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// The run of words in `sentence` closest to `term`, and its edit distance,
+/// e.g. `walked` for the term `walk`. Runs are up to one word longer than the
+/// term, and compared ignoring case and surrounding punctuation.
+pub fn closest_span(term: &str, sentence: &str) -> Option<(String, usize)> {
+    let words: Vec<&str> = sentence
+        .split_whitespace()
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()))
+        .filter(|word| !word.is_empty())
+        .collect();
+    let term_lower = term.to_lowercase();
+    let max_len = term.split_whitespace().count() + 1;
+
+    (1..=max_len)
+        .flat_map(|len| words.windows(len))
+        .map(|span| {
+            let span = span.join(" ");
+            let distance = edit_distance(&term_lower, &span.to_lowercase());
+            (span, distance)
+        })
+        .min_by_key(|(_, distance)| *distance)
+}
+
+/// `out/unmatched.csv`: every cloze term that wasn't found in its sentence,
+/// with the part of the sentence that comes closest, to see why it didn't match
+pub fn unmatched_csv(unmatched: &[(String, String)]) -> String {
+    let quote = |field: &str| format!("\"{}\"", field.replace('"', "\"\""));
+    let mut csv = String::from("term,sentence,closest_span,distance\n");
+    for (term, sentence) in unmatched {
+        let (span, distance) = closest_span(term, sentence)
+            .map(|(span, distance)| (span, distance.to_string()))
+            .unwrap_or_default();
+        csv.push_str(&format!(
+            "{},{},{},{distance}\n",
+            quote(term),
+            quote(sentence),
+            quote(&span)
+        ));
+    }
+    csv
+}
+
+/// Every distinct term across all cards, sorted alphabetically
+pub fn list_terms(entries: &[Clipping]) -> Vec<String> {
+    entries
+        .iter()
+        .filter_map(|entry| match entry {
+            Clipping::Note { cards, .. } => Some(cards),
+            Clipping::Highlight { .. } | Clipping::Bookmark { .. } => None,
+        })
+        .flatten()
+        .flat_map(Card::terms)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Serializes to pretty-printed JSON with the keys of every object sorted
+/// alphabetically, so equal data always produces byte-identical output and
+/// version-controlled outputs diff cleanly between runs
+pub fn to_stable_json<T: Serialize>(value: &T) -> Result<String, Error> {
+    // `serde_json::Value` keeps its object keys in a `BTreeMap`, which sorts them
+    let value = serde_json::to_value(value)?;
+    Ok(serde_json::to_string_pretty(&value)?)
+}
+
+/// Where `--archive` keeps the processed clippings, in the output directory
+const ARCHIVE_FILE: &str = "archive.txt";
+
+/// Appends the clippings processed in this run to `archive.txt` in `out_dir`,
+/// which stays a valid clippings file. The source file is never modified.
+pub fn archive_clippings(clippings_txt: &str, out_dir: &Path) -> Result<(), Error> {
+    let archive_path = out_dir.join(ARCHIVE_FILE);
+    let mut archive = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&archive_path)
+        .with_context(|| format!("unable to open {archive_path:?}"))?;
+    archive
+        .write_all(clippings_txt.as_bytes())
+        .with_context(|| format!("unable to append clippings to {archive_path:?}"))?;
+    // keep the next run's clippings on their own line
+    if !clippings_txt.ends_with('\n') {
+        archive.write_all(b"\n")?;
+    }
+    info!("archived processed clippings to {archive_path:?}");
+    Ok(())
+}
+
+/// Reads the clippings file, fetching it first if the path is an `http(s)://` url,
+/// and decodes it with the given encoding
+pub fn read_clippings(clippings_path: &Path, encoding: &'static Encoding) -> Result<String, Error> {
+    let path_str = clippings_path.to_string_lossy();
+    let bytes = if path_str.starts_with("http://") || path_str.starts_with("https://") {
+        fetch_clippings(&path_str)?
+    } else {
+        fs::read(clippings_path).with_context(|| "unable to read clippings path")?
+    };
+
+    if encoding == UTF_8 {
+        return String::from_utf8(bytes).with_context(|| {
+            "clippings are not valid UTF-8, try passing the file's encoding with --encoding"
+        });
+    }
+    let (text, _, had_errors) = encoding.decode(&bytes);
+    if had_errors {
+        warn!(
+            "some characters could not be decoded as {}, and were replaced",
+            encoding.name()
+        );
+    }
+    Ok(text.into_owned())
+}
+
+#[cfg(feature = "http")]
+fn fetch_clippings(url: &str) -> Result<Vec<u8>, Error> {
+    reqwest::blocking::get(url)
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.bytes())
+        .map(|bytes| bytes.to_vec())
+        .with_context(|| format!("unable to fetch clippings from {url}"))
+}
+
+#[cfg(not(feature = "http"))]
+fn fetch_clippings(url: &str) -> Result<Vec<u8>, Error> {
+    bail!("unable to fetch clippings from {url}: rebuild with `--features http` to read clippings over http")
+}
+
+#[cfg(feature = "http")]
+fn push_cards(cards: &[Card], deck: &str, connect_url: &str) -> Result<Vec<u64>, Error> {
+    ankiconnect::push_cards(cards, deck, connect_url)
+}
+
+#[cfg(not(feature = "http"))]
+fn push_cards(_cards: &[Card], _deck: &str, connect_url: &str) -> Result<Vec<u64>, Error> {
+    bail!(
+        "unable to push cards to {connect_url}: rebuild with `--features http` to use AnkiConnect"
+    )
+}
+
+#[cfg(feature = "kindle-mac")]
+fn parse_from_kindle_mac_db(
+    db_path: &Path,
+    date_after: Option<DateTime<Utc>>,
+    options: &ParseOptions,
+    warnings: &mut Warnings,
+) -> Result<Vec<Clipping>, Error> {
+    kindle_mac::parse_from_db(db_path, date_after, options, warnings)
+}
+
+#[cfg(not(feature = "kindle-mac"))]
+fn parse_from_kindle_mac_db(
+    db_path: &Path,
+    _date_after: Option<DateTime<Utc>>,
+    _options: &ParseOptions,
+    _warnings: &mut Warnings,
+) -> Result<Vec<Clipping>, Error> {
+    bail!("unable to read {db_path:?}: rebuild with `--features kindle-mac` to read the Kindle for Mac database")
+}
+
+#[cfg(feature = "apkg")]
+fn write_package(cards: &[Card], deck: &str, apkg_path: &Path) -> Result<(), Error> {
+    apkg::write_apkg(cards, deck, apkg_path)
+}
+
+#[cfg(not(feature = "apkg"))]
+fn write_package(_cards: &[Card], _deck: &str, apkg_path: &Path) -> Result<(), Error> {
+    bail!("unable to write {apkg_path:?}: rebuild with `--features apkg` to write .apkg packages")
+}
+
+#[cfg(feature = "apkg")]
+fn append_package(cards: &[Card], deck: &str, apkg_path: &Path) -> Result<usize, Error> {
+    apkg::append_apkg(cards, deck, apkg_path)
+}
+
+#[cfg(not(feature = "apkg"))]
+fn append_package(_cards: &[Card], _deck: &str, apkg_path: &Path) -> Result<usize, Error> {
+    bail!("unable to add to {apkg_path:?}: rebuild with `--features apkg` to write .apkg packages")
+}
+
+/// Writes `out` to `output_file_name` in `encoding`, backing up the file it
+/// replaces. With `dry_run`, prints what would be written and copied instead.
+pub fn write(
+    out: String,
+    out_path: &Path,
+    encoding: OutputEncoding,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let bytes =
+        encode_output(&out, encoding).with_context(|| format!("unable to write {out_path:?}"))?;
+    // check if file already exists
+    if dry_run {
+        if out_path.exists() {
+            println!(
+                "[dry-run] overwrote old {:?} (backed up to `{:?}`)",
+                out_path,
+                backup_path(out_path, Local::now())
+            );
+        }
+        return write_file(out_path, &out, true);
+    }
+    if out_path.exists() {
+        // every run keeps its own copy, so older outputs can be recovered too
+        let copy = backup_path(out_path, Local::now());
+        if let Some(dir) = copy.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("unable to create {dir:?} for the backup"))?;
+        }
+        fs::copy(out_path, &copy).with_context(|| {
+            format!(
+                "unable to copy from {:#?} to {:#?} for some reason",
+                out_path, copy
+            )
+        })?;
+        // on disk before the output is replaced, so there's always one of them
+        File::open(&copy)
+            .and_then(|copy| copy.sync_all())
+            .with_context(|| format!("unable to flush {copy:?} to disk"))?;
+        println!("overwrote old {:?} (backed up to `{:?}`)", out_path, copy);
+    }
+    write_atomic(out_path, &bytes)
+}
+
+/// Writes `contents` to a temporary file next to `path`, flushes it to disk
+/// and renames it over `path`, so a crash or a dropped network drive halfway
+/// through leaves either the old file or the new one, never a truncated one
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    let mut tmp = File::create(&tmp_path)
+        .with_context(|| format!("unable to create {tmp_path:?} to write {path:?}"))?;
+    tmp.write_all(contents)
+        .and_then(|_| tmp.sync_all())
+        .with_context(|| format!("unable to write {tmp_path:?}"))?;
+    drop(tmp);
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("unable to move {tmp_path:?} to {path:?}"))?;
+    // the rename itself is only durable once the directory is flushed, which
+    // only unix can do
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("unable to flush {dir:?} to disk"))?;
+    }
+    Ok(())
+}
+
+/// Where [write] backs up the file at `out_path` before replacing it: next to
+/// it, named after it and `now`, e.g. `out/output-copy-20240115-103000.md`. A
+/// number is added if several backups are made within a second.
+fn backup_path(out_path: &Path, now: DateTime<Local>) -> PathBuf {
+    let stem = out_path
+        .file_stem()
+        .map_or_else(|| "output".into(), |stem| stem.to_string_lossy());
+    let extension = out_path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    let name = format!("{stem}-copy-{}", now.format("%Y%m%d-%H%M%S"));
+
+    let mut copy = out_path.with_file_name(format!("{name}{extension}"));
+    let mut number = 2;
+    while copy.exists() {
+        copy = out_path.with_file_name(format!("{name}-{number}{extension}"));
+        number += 1;
+    }
+    copy
+}
+
+/// Writes `contents` to `path`, or with `dry_run` prints them instead
+fn write_file(path: &Path, contents: &str, dry_run: bool) -> Result<(), Error> {
+    if dry_run {
+        println!("[dry-run] would write {path:?}:\n{contents}");
+        return Ok(());
+    }
+    write_atomic(path, contents.as_bytes())
+}
+
+/// Encodes `out`, writing characters the encoding can't represent as html
+/// character references unless `encoding.strict`
+fn encode_output(out: &str, encoding: OutputEncoding) -> Result<Vec<u8>, Error> {
+    let OutputEncoding { encoding, strict } = encoding;
+    let (bytes, _, had_unmappable) = encoding.encode(out);
+    if had_unmappable {
+        if strict {
+            let unmappable: String = out
+                .chars()
+                .filter(|c| encoding.encode(c.encode_utf8(&mut [0; 4])).2)
+                .collect::<BTreeSet<char>>()
+                .into_iter()
+                .collect();
+            bail!(
+                "{} can't represent the characters `{unmappable}`",
+                encoding.name()
+            );
+        }
+        warn!(
+            "wrote characters {} can't represent as html character references",
+            encoding.name()
+        );
+    }
+    Ok(bytes.into_owned())
+}
+
+/// The distinct `N`s of the `{{cN::` deletions in a cloze, in order
+fn cloze_numbers(text: &str) -> BTreeSet<usize> {
+    static RE_INDEX: OnceLock<Regex> = OnceLock::new();
+    let re_index = RE_INDEX.get_or_init(|| Regex::new(r"\{\{c(?P<index>\d+)::").unwrap());
+    re_index
+        .captures_iter(text)
+        .filter_map(|captures| captures["index"].parse::<usize>().ok())
+        .collect()
+}
+
+/// What is wrong with the cloze deletions in the fields of a card, e.g. a
+/// `{{c1::` that is never closed, an empty `{{c1::}}`, `c1` and `c3` without a
+/// `c2`, or a `c2` without a `c1`. Numbering starts at `c1`, or at `first`
+/// when --reset-cloze-per continues it from the card before.
+fn cloze_errors(card: &Card, first: usize) -> Vec<String> {
+    static RE_OPEN: OnceLock<Regex> = OnceLock::new();
+    let re_open = RE_OPEN.get_or_init(|| Regex::new(r"\{\{c(?P<index>\d+)::").unwrap());
+
+    let (front, back) = card.export_fields();
+    let mut errors = Vec::new();
+    for field in [front, back] {
+        let opens: Vec<regex::Captures> = re_open.captures_iter(field).collect();
+        // e.g. `{{c1:hill}}` or `{{c::hill}}`
+        if field.matches("{{c").count() > opens.len() {
+            errors.push("malformed cloze, expected `{{cN::...}}`".to_string());
+        }
+        for open in &opens {
+            let whole = open.get(0).unwrap();
+            let index = &open["index"];
+            match field[whole.end()..].find("}}") {
+                None => errors.push(format!("`{{{{c{index}::` is never closed with `}}}}`")),
+                Some(close) if field[whole.end()..whole.end() + close].trim().is_empty() => {
+                    errors.push(format!("cloze c{index} is empty"));
+                }
+                Some(_) => {}
+            }
+        }
+        let (opening, closing) = (field.matches("{{").count(), field.matches("}}").count());
+        if !opens.is_empty() && opening != closing {
+            errors.push(format!(
+                "unbalanced braces, {opening} `{{{{` but {closing} `}}}}`"
+            ));
+        }
+    }
+
+    let numbers = cloze_numbers(front);
+    if let Some(lowest) = numbers
+        .first()
+        .filter(|lowest| ![1, first].contains(*lowest))
+    {
+        let expected = if first == 1 {
+            "c1".to_string()
+        } else {
+            format!("c1 or c{first}")
+        };
+        errors.push(format!(
+            "cloze numbers start at c{lowest} instead of {expected}"
+        ));
+    }
+    if let (Some(lowest), Some(highest)) = (numbers.first(), numbers.last()) {
+        let missing: Vec<String> = (*lowest..*highest)
+            .filter(|n| !numbers.contains(n))
+            .map(|n| format!("c{n}"))
+            .collect();
+        if !missing.is_empty() {
+            errors.push(format!(
+                "cloze numbers aren't sequential, missing {}",
+                missing.join(", ")
+            ));
+        }
+    }
+    errors
+}
+
+/// Why a card isn't ready to be studied yet, if it isn't
+fn incomplete_reason(card: &Card) -> Option<&'static str> {
+    match card {
+        Card::Basic(Basic { front, .. }) => front
+            .replace("<br>", "")
+            .trim()
+            .is_empty()
+            .then_some("basic card has no definition on its front"),
+        Card::Cloze(Cloze { text, .. }) => {
+            static RE_CLOZE: OnceLock<Regex> = OnceLock::new();
+            let re_cloze = RE_CLOZE.get_or_init(|| Regex::new(r"\{\{c\d+::.+?\}\}").unwrap());
+            (!re_cloze.is_match(text)).then_some("cloze card has no valid `{{cN::...}}` deletion")
+        }
+    }
+}
+
+/// Reads the cards back from the markdown output, after definitions were added
+/// to it, along with the line each card starts on
+fn read_output_cards(
+    output_file_name: &str,
+    back_spacing: usize,
+    card_template: &CardTemplate,
+) -> Result<(Vec<Card>, Vec<usize>), Error> {
+    // construct array of cards
+    let mut cards: Vec<Card> = Vec::new();
+    // line each card starts on, for reporting incomplete cards
+    let mut card_lines: Vec<usize> = Vec::new();
+    let string = fs::read_to_string(output_file_name)
+        .with_context(|| format!("unable to read {output_file_name:?}"))?;
+
+    let mut lines = string.lines().enumerate();
+
+    let mut sentence = String::new();
+    let spacing = "<br>".repeat(back_spacing);
+    // get next line
+    // content lines, with the escapes of lines starting with a delimiter dropped
+    let join = |lines: &[&str]| {
+        lines
+            .iter()
+            .map(|line| format::unescape(line))
+            .collect::<Vec<_>>()
+            .join("<br>")
+    };
+    while let Some((number, line)) = lines.next() {
+        match line {
+            // match the line to either "========" to signal a sentence, or
+            format::SENTENCE => {
+                sentence.clear();
+                let mut buffer: Vec<&str> = Vec::new();
+                // consume until next "========"
+                for (_, line) in lines.by_ref() {
+                    if line != format::SENTENCE {
+                        buffer.push(format::unescape(line));
+                    } else {
+                        break;
+                    }
+                }
+                sentence = buffer.join("<br>");
+            }
+            // "----" to signal a card built off that sentence
+            format::CARD => {
+                let mut buffer: Vec<&str> = Vec::new();
+                // consume until next "----"
+                for (_, line) in lines.by_ref() {
+                    if line != format::CARD {
+                        buffer.push(line);
+                    } else {
+                        break;
+                    }
+                }
+                // dbg!(&buffer);
+
+                // pull out the `|#` tag lines before splitting front and back
+                let tags: Vec<String> = buffer
+                    .iter()
+                    .filter_map(|line| line.strip_prefix(format::TAGS))
+                    .flat_map(str::split_whitespace)
+                    .map(String::from)
+                    .collect();
+                buffer.retain(|line| !line.starts_with(format::TAGS));
+
+                // as well as the `|>` context lines
+                let context_lines: Vec<&str> = buffer
+                    .iter()
+                    .filter_map(|line| line.strip_prefix(format::CONTEXT))
+                    .map(|line| line.strip_prefix(' ').unwrap_or(line))
+                    .collect();
+                let context = (!context_lines.is_empty()).then(|| context_lines.join("<br>"));
+                buffer.retain(|line| !line.starts_with(format::CONTEXT));
+                card_template.strip(&mut buffer);
+
+                // let total_content: String = buffer.join("<br>");
+                let idx = buffer
+                    .iter()
+                    .position(|&s| s == format::SECTION)
+                    .context(format!("error finding |- in card lol {:?}", buffer))?;
+
+                let front = join(&buffer[0..idx]);
+                // a second `|-` starts the extra section, e.g. a mnemonic,
+                // which goes in a field of its own
+                let (back, extra) =
+                    match buffer[idx + 1..].iter().position(|&s| s == format::SECTION) {
+                        Some(extra_idx) => (
+                            join(&buffer[idx + 1..idx + 1 + extra_idx]),
+                            Some(join(&buffer[idx + 2 + extra_idx..])),
+                        ),
+                        None => (join(&buffer[idx + 1..]), None),
+                    };
+
+                // first check for the presence of any cloze beginnings, which
+                // don't start at `c1` with --reset-cloze-per
+                if !cloze_numbers(&front).is_empty() {
+                    // insert it as a cloze, without the sentence
+                    cards.push(Card::Cloze(Cloze {
+                        text: front,
+                        back_extra: back,
+                        tags,
+                        context,
+                        raw_note: None,
+                        extra,
+                    }));
+                } else {
+                    // separate the first line of back (the word) from the rest of the content
+                    let mut lines = back.lines();
+                    let term = lines
+                        .next()
+                        .with_context(|| format!("no term provided for {}", string))?
+                        .trim();
+                    let rest: String = lines.collect::<String>().trim().to_string();
+
+                    if rest.is_empty() {
+                        cards.push(Card::Basic(Basic {
+                            front,
+                            back: format!("{}{}{}", term, spacing, sentence),
+                            tags,
+                            raw_note: None,
+                            extra,
+                        }));
+                    } else {
+                        cards.push(Card::Basic(Basic {
+                            front,
+                            back: format!("{}{}{}{}{}", term, spacing, sentence, spacing, rest),
+                            tags,
+                            raw_note: None,
+                            extra,
+                        }));
+                    }
+                }
+                card_lines.push(number + 1);
+            }
+            _ => {
+                bail!(
+                    "invalid card sequence detected `{}` at line number {}",
+                    line,
+                    number
+                )
+            }
+        }
+    }
+
+    Ok((cards, card_lines))
+}
+
+/// `field` followed by the extra section of a card, for note types without a
+/// field of their own for it
+pub(crate) fn with_extra(field: &str, extra: Option<&str>) -> String {
+    match extra {
+        Some(extra) if !extra.trim().is_empty() => format!("{field}<br>{extra}"),
+        _ => field.to_string(),
+    }
+}
+
+/// Writes `cards` for Anki's plain text import, one note per line with a tags
+/// column. Anki imports a file as a single note type, taken from the first
+/// card, so basic and cloze cards go in separate files: `Front`/`Back` for
+/// basic cards, `Text`/`Back Extra` for clozes.
+pub fn export_tsv(cards: &[Card]) -> String {
+    let notetype = match cards.first() {
+        Some(Card::Cloze(_)) => "Cloze",
+        _ => "Basic",
+    };
+    let mut tsv = format!("#separator:tab\n#html:true\n#notetype:{notetype}\n#tags column:3\n");
+    for card in cards {
+        let (first, second) = match card {
+            Card::Basic(Basic {
+                front, back, extra, ..
+            }) => (front.clone(), with_extra(back, extra.as_deref())),
+            Card::Cloze(Cloze {
+                text,
+                back_extra,
+                context,
+                extra,
+                ..
+            }) => {
+                // the built-in cloze type has no field for the context
+                let back_extra = match context {
+                    Some(context) => format!("{back_extra}<br>{context}"),
+                    None => back_extra.clone(),
+                };
+                (text.clone(), with_extra(&back_extra, extra.as_deref()))
+            }
+        };
+        tsv.push_str(&format!(
+            "{}\t{}\t{}\n",
+            tsv_field(&first),
+            tsv_field(&second),
+            tsv_field(&card.tags().join(" "))
+        ));
+    }
+    tsv
+}
+
+/// Escapes a field of [export_tsv]: line breaks become `<br>`, tabs become a
+/// character reference, and fields with quotes are quoted, as Anki expects
+fn tsv_field(field: &str) -> String {
+    let field = field
+        .trim()
+        .replace("\r\n", "<br>")
+        .replace('\n', "<br>")
+        .replace('\t', "&#9;");
+    if field.contains('"') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+/// Validates if notes have one highlight and one (or more) terms, and that the
+/// cloze deletions of every card are well-formed and numbered from `c1` (or
+/// from the card before, per `reset_cloze_per`) without gaps.
+///
+/// In strict mode every card must also be finished: basic cards need a
+/// definition on the front, and clozes need a well-formed `{{cN::...}}`.
+#[allow(clippy::too_many_arguments)]
+fn validate(
+    output_file_name: String,
+    out_dir: &Path,
+    back_spacing: usize,
+    strict: bool,
+    card_template: &CardTemplate,
+    format: CardFormat,
+    reset_cloze_per: ClozeReset,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let (cards, card_lines) = read_output_cards(&output_file_name, back_spacing, card_template)?;
+
+    let mut malformed = Vec::new();
+    // with --reset-cloze-per a card continues from the highest cloze of the
+    // cloze card before it, unless its note or book started over at `c1`
+    let mut first = 1;
+    for (card, line) in cards.iter().zip(&card_lines) {
+        malformed.extend(
+            cloze_errors(card, first)
+                .into_iter()
+                .map(|error| format!("line {line}: {error}")),
+        );
+        match card {
+            Card::Cloze(Cloze { text, .. }) if reset_cloze_per != ClozeReset::Card => {
+                first = cloze_numbers(text)
+                    .last()
+                    .map_or(first, |highest| highest + 1);
+            }
+            _ => {}
+        }
+    }
+    if !malformed.is_empty() {
+        bail!(
+            "{} malformed cloze(s) in {}:\n{}",
+            malformed.len(),
+            output_file_name,
+            malformed.join("\n")
+        );
+    }
+
+    if strict {
+        let incomplete: Vec<String> = cards
+            .iter()
+            .zip(&card_lines)
+            .filter_map(|(card, line)| {
+                incomplete_reason(card).map(|reason| format!("line {line}: {reason}"))
+            })
+            .collect();
+        if !incomplete.is_empty() {
+            bail!(
+                "{} incomplete card(s) in {}:\n{}",
+                incomplete.len(),
+                output_file_name,
+                incomplete.join("\n")
+            );
+        }
+    }
+
+    if format == CardFormat::Tsv {
+        let (basic, cloze): (Vec<Card>, Vec<Card>) = cards
+            .into_iter()
+            .partition(|card| matches!(card, Card::Basic(_)));
+        for (file_name, cards) in [("output-basic.tsv", basic), ("output-cloze.tsv", cloze)] {
+            if cards.is_empty() {
+                continue;
+            }
+            let path = out_dir.join(file_name);
+            write_file(&path, &export_tsv(&cards), dry_run)
+                .with_context(|| format!("unable to write {path:?}"))?;
+            info!("wrote {} cards to {path:?}", cards.len());
+        }
+        return Ok(());
+    }
+
+    let metadata_path = out_dir.join("output-metadata.json");
+    let metadata: Vec<Clipping> = serde_json::from_str(
+        &fs::read_to_string(&metadata_path)
+            .with_context(|| format!("unable to read {metadata_path:?}"))?,
+    )?;
+
+    let output = Output {
+        cards,
+        // entries may have been shuffled, so don't rely on their order
+        begin_date: metadata
+            .iter()
+            .map(Clipping::date)
+            .min()
+            .context("no first element in output-metadata.json")?,
+        end_date: metadata
+            .iter()
+            .map(Clipping::date)
+            .max()
+            .context("no last element in output-metadata.json")?,
+    };
+
+    write_file(
+        &out_dir.join("output.json"),
+        &to_stable_json(&output)?,
+        dry_run,
+    )
+    .with_context(|| {
+        "Unable to write to final output file from cards .md to `out.json` for some reason."
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A new, empty directory under the system's temp directory
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("anki-kindle-import-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// The config for running the binary with `args`
+    pub(crate) fn config(args: &[&str]) -> Config {
+        let matches = config::command()
+            .try_get_matches_from(std::iter::once("anki-kindle-import").chain(args.iter().copied()))
+            .unwrap();
+        Config::new(&matches).unwrap()
+    }
+
+    /// A block of a `My Clippings.txt` in Moby Dick, added `minute`s after
+    /// 11:00 on 2018-11-24
+    pub(crate) fn clipping(kind: &str, minute: u32, body: &str) -> String {
+        book_clipping("Moby Dick (Herman Melville)", kind, minute, body)
+    }
+
+    /// A block of a `My Clippings.txt` like [clipping], in the book of the
+    /// `title` line
+    pub(crate) fn book_clipping(title: &str, kind: &str, minute: u32, body: &str) -> String {
+        format!(
+            "{title}\n- Your {kind} on page 12 | Location 100-101 | Added on Saturday, November 24, 2018 11:{minute:02}:00 AM\n\n{body}\n==========\n"
+        )
+    }
+
+    /// Parses `clippings` with `options`, from any date
+    pub(crate) fn parse(clippings: &str, options: &ParseOptions) -> (Vec<Clipping>, Warnings) {
+        let mut warnings = Warnings::default();
+        let entries = parse_from_txt(clippings, None, options, &mut warnings).unwrap();
+        (entries, warnings)
+    }
+
+    /// The cards of every note among `entries`
+    pub(crate) fn cards(entries: &[Clipping]) -> Vec<&Card> {
+        entries
+            .iter()
+            .flat_map(|entry| match entry {
+                Clipping::Note { cards, .. } => cards.iter().collect(),
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bookmark_round_trips_through_metadata_without_a_card() {
+        let clippings = clipping("Bookmark", 10, "");
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        assert!(matches!(
+            entries.as_slice(),
+            [Clipping::Bookmark {
+                location: Some(100),
+                ..
+            }]
+        ));
+        let metadata = to_stable_json(&entries).unwrap();
+        let read_back: Vec<Clipping> = serde_json::from_str(&metadata).unwrap();
+        assert_eq!(read_back, entries);
+        assert!(cards(&read_back).is_empty());
+        assert_eq!(
+            entries_to_markdown(&read_back, &CardTemplate::default()),
+            ""
+        );
+    }
+
+    #[test]
+    fn note_after_a_bookmark_still_replaces_its_highlight() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Bookmark", 11, ""),
+            clipping("Note", 12, "hill"),
+        ]
+        .concat();
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        assert!(matches!(
+            entries.as_slice(),
+            [Clipping::Bookmark { .. }, Clipping::Note { .. }]
+        ));
+        assert_eq!(cards(&entries).len(), 1);
+    }
+
+    #[test]
+    fn note_after_a_note_keeps_both() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+            clipping("Note", 12, "walked"),
+        ]
+        .concat();
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        assert_eq!(entries.len(), 2);
+        assert!(entries
+            .iter()
+            .all(|entry| matches!(entry, Clipping::Note { .. })));
+        // the cards of the first note are still there
+        assert!(matches!(&entries[0], Clipping::Note { cards, .. } if cards.len() == 1));
+    }
+
+    /// The clippings in the metadata a run wrote to `out_dir`
+    pub(crate) fn metadata(out_dir: &Path) -> Vec<Clipping> {
+        serde_json::from_str(&fs::read_to_string(out_dir.join("output-metadata.json")).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn last_date_is_the_newest_clipping_and_is_left_out_next_run() {
+        let dir = temp_dir("last-date");
+        let clippings_path = dir.join("My Clippings.txt");
+        let out_dir = dir.join("out");
+        let args = [
+            "--clipping-path",
+            clippings_path.to_str().unwrap(),
+            "--out-dir",
+            out_dir.to_str().unwrap(),
+        ];
+        let mut clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 12, "hill"),
+        ]
+        .concat();
+        fs::write(&clippings_path, &clippings).unwrap();
+        convert_config_to_finished_app(config(&args)).unwrap();
+
+        let last_date: LastDate =
+            serde_json::from_str(&fs::read_to_string(out_dir.join("last-date.json")).unwrap())
+                .unwrap();
+        let newest = local_to_utc(
+            NaiveDate::from_ymd_opt(2018, 11, 24)
+                .unwrap()
+                .and_hms_opt(11, 12, 0)
+                .unwrap(),
+        );
+        assert_eq!(last_date.date, newest);
+
+        // nothing new, so nothing is imported twice
+        convert_config_to_finished_app(config(&args)).unwrap();
+        assert!(metadata(&out_dir).is_empty());
+
+        clippings.push_str(&clipping("Highlight", 20, "The dog ran"));
+        fs::write(&clippings_path, &clippings).unwrap();
+        convert_config_to_finished_app(config(&args)).unwrap();
+        let entries = metadata(&out_dir);
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].date() > newest);
+    }
+
+    #[test]
+    fn failed_run_leaves_last_date_alone() {
+        let dir = temp_dir("last-date-failed");
+        let clippings_path = dir.join("My Clippings.txt");
+        let out_dir = dir.join("out");
+        fs::write(
+            &clippings_path,
+            [
+                clipping("Highlight", 10, "The cat walked over the hill"),
+                clipping("Note", 12, "mountain"),
+            ]
+            .concat(),
+        )
+        .unwrap();
+        let config = config(&[
+            "--clipping-path",
+            clippings_path.to_str().unwrap(),
+            "--out-dir",
+            out_dir.to_str().unwrap(),
+            "--fail-on-warning",
+        ]);
+        let error = convert_config_to_finished_app(config).unwrap_err();
+        assert!(error.to_string().contains("--fail-on-warning"));
+        assert!(!out_dir.join("last-date.json").exists());
+    }
+
+    #[test]
+    fn refuses_oversized_clippings() {
+        let dir = temp_dir("oversized");
+        let clippings = dir.join("My Clippings.txt");
+        fs::write(&clippings, vec![b'a'; 2 * 1024 * 1024]).unwrap();
+        let out_dir = dir.join("out");
+        let config = config(&[
+            "--clipping-path",
+            clippings.to_str().unwrap(),
+            "--out-dir",
+            out_dir.to_str().unwrap(),
+            "--max-file-size",
+            "1",
+        ]);
+        let error = convert_config_to_finished_app(config).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("larger than the maximum of 1048576 bytes"));
+    }
+
+    #[test]
+    fn every_part_but_the_last_of_a_cloze_line_is_clozed() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "walked .. hill .. shared extra"),
+        ]
+        .concat();
+        let options = ParseOptions::default();
+        let (entries, _) = parse(&clippings, &options);
+        let [Card::Cloze(cloze)] = cards(&entries)[..] else {
+            panic!("expected one cloze, got {entries:?}");
+        };
+        let spacing = "\n".repeat(options.back_spacing);
+        assert_eq!(
+            cloze.text,
+            format!("{spacing}The cat {{{{c1::walked}}}} over the {{{{c2::hill}}}}")
+        );
+        assert_eq!(cloze.back_extra, format!("{spacing}shared extra"));
+    }
+
+    #[test]
+    fn parts_of_a_basic_line_are_lines_of_the_back() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill ... a small mountain ... a mound"),
+        ]
+        .concat();
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        let [Card::Basic(basic)] = cards(&entries)[..] else {
+            panic!("expected one basic card, got {entries:?}");
+        };
+        assert_eq!(basic.back, "hill\na small mountain\na mound");
+    }
+
+    /// A cloze card with `text` on its front
+    fn cloze(text: &str) -> Card {
+        Card::Cloze(Cloze {
+            text: text.to_string(),
+            back_extra: String::new(),
+            tags: Vec::new(),
+            context: None,
+            raw_note: None,
+            extra: None,
+        })
+    }
+
+    #[test]
+    fn well_formed_cloze_has_no_errors() {
+        let card = cloze("The cat {{c1::walked}} over the {{c2::hill}}");
+        assert!(cloze_errors(&card, 1).is_empty());
+    }
+
+    #[test]
+    fn unclosed_cloze_is_an_error() {
+        let errors = cloze_errors(&cloze("The cat {{c1::walked over the hill"), 1);
+        assert!(errors
+            .iter()
+            .any(|error| error == "`{{c1::` is never closed with `}}`"));
+    }
+
+    #[test]
+    fn c2_without_c1_is_an_error() {
+        let errors = cloze_errors(&cloze("The cat walked over the {{c2::hill}}"), 1);
+        assert_eq!(errors, ["cloze numbers start at c2 instead of c1"]);
+    }
+
+    #[test]
+    fn clozes_can_continue_from_the_card_before() {
+        let card = cloze("The cat walked over the {{c3::hill}}");
+        assert!(cloze_errors(&card, 3).is_empty());
+        assert!(cloze_errors(&cloze("The {{c1::cat}} walked"), 3).is_empty());
+        assert_eq!(
+            cloze_errors(&card, 2),
+            ["cloze numbers start at c3 instead of c1 or c2"]
+        );
+    }
+
+    #[test]
+    fn gap_in_cloze_numbers_is_an_error() {
+        let errors = cloze_errors(&cloze("The {{c1::cat}} walked over the {{c3::hill}}"), 1);
+        assert_eq!(errors, ["cloze numbers aren't sequential, missing c2"]);
+    }
+
+    #[test]
+    fn lemma_is_on_the_back_of_the_cloze() {
+        for note in ["walked=walk ... extra", "walked=walk .. extra"] {
+            let clippings = [
+                clipping("Highlight", 10, "The cat walked over the hill"),
+                clipping("Note", 11, note),
+            ]
+            .concat();
+            let options = ParseOptions::default();
+            let (entries, _) = parse(&clippings, &options);
+            let [Card::Cloze(cloze)] = cards(&entries)[..] else {
+                panic!("expected one cloze from `{note}`, got {entries:?}");
+            };
+            let spacing = "\n".repeat(options.back_spacing);
+            assert_eq!(
+                cloze.text,
+                format!("{spacing}The cat {{{{c1::walked}}}} over the hill")
+            );
+            assert_eq!(cloze.back_extra, format!("{spacing}walk{spacing}extra"));
+        }
+    }
+
+    #[test]
+    fn lemma_without_extra_content_is_a_cloze_even_for_basic_bare_terms() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "walked=walk"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            bare_term_as: BareTerm::Basic,
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let [Card::Cloze(cloze)] = cards(&entries)[..] else {
+            panic!("expected one cloze, got {entries:?}");
+        };
+        assert!(cloze.text.contains("{{c1::walked}}"));
+        assert!(cloze.back_extra.ends_with("walk"));
+    }
+
+    #[test]
+    fn back_spacing_separates_cloze_sections() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill .. a small mountain"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            back_spacing: 3,
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let [Card::Cloze(cloze)] = cards(&entries)[..] else {
+            panic!("expected one cloze, got {entries:?}");
+        };
+        assert_eq!(cloze.text, "\n\n\nThe cat walked over the {{c1::hill}}");
+        assert_eq!(cloze.back_extra, "\n\n\na small mountain");
+    }
+
+    #[test]
+    fn back_spacing_separates_basic_sections_when_validating() {
+        let dir = temp_dir("back-spacing");
+        let output = dir.join("output.md");
+        fs::write(
+            &output,
+            "========\nThe cat walked over the hill\n========\n----\na small mountain\n|-\nhill\n----\n",
+        )
+        .unwrap();
+        for (back_spacing, spacing) in [(1, "<br>"), (3, "<br><br><br>")] {
+            let (cards, _) = read_output_cards(
+                output.to_str().unwrap(),
+                back_spacing,
+                &CardTemplate::default(),
+            )
+            .unwrap();
+            let [Card::Basic(basic)] = &cards[..] else {
+                panic!("expected one basic card, got {cards:?}");
+            };
+            assert_eq!(
+                basic.back,
+                format!("hill{spacing}The cat walked over the hill")
+            );
+        }
+    }
+
+    #[test]
+    fn short_highlight_is_not_a_cloze_source() {
+        let clippings = [
+            clipping("Highlight", 10, "the hill"),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            min_cloze_words: 3,
+            ..ParseOptions::default()
+        };
+        let (entries, warnings) = parse(&clippings, &options);
+        assert!(cards(&entries).is_empty());
+        assert!(warnings
+            .iter()
+            .any(|warning| warning.contains("has 2 word(s), fewer than the minimum of 3")));
+
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        assert_eq!(cards(&entries).len(), 1);
+    }
+
+    /// Ten highlights of Moby Dick, one a minute
+    fn highlights() -> String {
+        (0..10)
+            .map(|minute| clipping("Highlight", minute, &format!("Sentence number {minute}")))
+            .collect()
+    }
+
+    /// The sentences of `entries`, in order
+    pub(crate) fn sentences(entries: &[Clipping]) -> Vec<&str> {
+        entries
+            .iter()
+            .filter_map(|entry| match entry {
+                Clipping::Highlight { sentence, .. } => Some(sentence.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_shuffles_the_same_way() {
+        let shuffled = |seed| {
+            let options = ConvertOptions {
+                shuffle_seed: Some(seed),
+                ..ConvertOptions::default()
+            };
+            convert(&highlights(), &options).unwrap()
+        };
+        let first = shuffled(7);
+        assert_eq!(first.markdown, shuffled(7).markdown);
+
+        let in_order = convert(&highlights(), &ConvertOptions::default()).unwrap();
+        let mut sorted = sentences(&first.entries);
+        assert_ne!(sorted, sentences(&in_order.entries));
+        assert_ne!(sorted, sentences(&shuffled(8).entries));
+        sorted.sort();
+        assert_eq!(sorted, sentences(&in_order.entries));
+    }
+
+    #[test]
+    fn collapses_spaces_and_tabs_but_not_line_breaks() {
+        let clippings = clipping("Highlight", 10, "The  cat\twalked over\nthe \t hill");
+        let options = ParseOptions {
+            collapse_whitespace: true,
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        assert_eq!(sentences(&entries), ["The cat walked over\nthe hill"]);
+
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        assert_eq!(sentences(&entries), ["The  cat\twalked over\nthe \t hill"]);
+    }
+
+    /// Runs the binary with `args` on `clippings`, written to a new
+    /// `My Clippings.txt`, and returns the output directory it wrote to
+    pub(crate) fn run(name: &str, clippings: &str, args: &[&str]) -> (PathBuf, Result<(), Error>) {
+        let dir = temp_dir(name);
+        let clippings_path = dir.join("My Clippings.txt");
+        fs::write(&clippings_path, clippings).unwrap();
+        let out_dir = dir.join("out");
+        let mut all_args = vec![
+            "--clipping-path",
+            clippings_path.to_str().unwrap(),
+            "--out-dir",
+            out_dir.to_str().unwrap(),
+        ];
+        all_args.extend(args);
+        let result = convert_config_to_finished_app(config(&all_args));
+        (out_dir, result)
+    }
+
+    #[test]
+    fn unmatched_cloze_fails_with_fail_on_warning() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "mountain"),
+        ]
+        .concat();
+        let (_, result) = run("fail-on-warning", &clippings, &["--fail-on-warning"]);
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("1 warning(s) occurred"), "{error}");
+
+        let (_, result) = run("no-fail-on-warning", &clippings, &[]);
+        result.unwrap();
+    }
+
+    #[test]
+    fn notes_only_drops_lone_highlights() {
+        let clippings = [
+            clipping("Highlight", 10, "Call me Ishmael"),
+            clipping("Highlight", 11, "The cat walked over the hill"),
+            clipping("Note", 12, "hill"),
+            clipping("Highlight", 13, "The dog ran"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            notes_only: true,
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        assert!(matches!(entries.as_slice(), [Clipping::Note { .. }]));
+        assert_eq!(cards(&entries).len(), 1);
+
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        assert_eq!(entries.len(), 3);
+    }
+
+    #[test]
+    fn book_tags_are_hierarchical() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            book_tags: true,
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let [card] = cards(&entries)[..] else {
+            panic!("expected one card, got {entries:?}");
+        };
+        assert_eq!(card.tags(), ["book::Herman_Melville::Moby_Dick"]);
+    }
+
+    #[test]
+    fn atomic_write_leaves_the_full_content_and_no_temp_file() {
+        let dir = temp_dir("write-atomic");
+        let path = dir.join("output.md");
+        fs::write(&path, "old content that is longer than the new one").unwrap();
+        write_atomic(&path, b"new content").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new content");
+        let names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["output.md"]);
+    }
+
+    #[test]
+    fn bare_term_is_a_cloze_by_default() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        assert!(matches!(
+            cards(&entries)[..],
+            [Card::Cloze(Cloze { text, .. })] if text.ends_with("The cat walked over the {{c1::hill}}")
+        ));
+    }
+
+    #[test]
+    fn bare_term_as_basic_puts_the_sentence_on_the_back() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            bare_term_as: BareTerm::Basic,
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let [Card::Basic(basic)] = cards(&entries)[..] else {
+            panic!("expected one basic card, got {entries:?}");
+        };
+        assert_eq!(basic.front, "");
+        assert_eq!(basic.back, "hill\n\nThe cat walked over the hill");
+    }
+
+    /// Serves `body` to the first request on a local port, and returns the url
+    #[cfg(feature = "http")]
+    fn serve_once(body: &'static str) -> String {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/My%20Clippings.txt",
+            listener.local_addr().unwrap()
+        );
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // the request ends with an empty line
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+        });
+        url
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn reads_clippings_over_http() {
+        const CLIPPINGS: &str = "Moby Dick (Herman Melville)\n- Your Highlight on page 12 | Location 100-101 | Added on Saturday, November 24, 2018 11:10:00 AM\n\nCall me Ishmael\n==========\n";
+        let url = serve_once(CLIPPINGS);
+        let clippings = read_clippings(Path::new(&url), UTF_8).unwrap();
+        assert_eq!(clippings, CLIPPINGS);
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        assert_eq!(sentences(&entries), ["Call me Ishmael"]);
+    }
+
+    #[cfg(not(feature = "http"))]
+    #[test]
+    fn reading_clippings_over_http_needs_the_feature() {
+        let error = read_clippings(Path::new("http://localhost/My Clippings.txt"), UTF_8)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("--features http"), "{error}");
+    }
+
+    #[test]
+    fn decodes_windows_1252_clippings() {
+        let dir = temp_dir("windows-1252");
+        let path = dir.join("My Clippings.txt");
+        let clippings = "Les Misérables (Victor Hugo)\n- Your Highlight on page 12 | Location 100-101 | Added on Saturday, November 24, 2018 11:10:00 AM\n\nÇa, c'est l'été à Noël\n==========\n";
+        let (bytes, _, _) = encoding_rs::WINDOWS_1252.encode(clippings);
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(read_clippings(&path, UTF_8).is_err());
+        let decoded = read_clippings(&path, encoding_rs::WINDOWS_1252).unwrap();
+        assert_eq!(decoded, clippings);
+        let (entries, _) = parse(&decoded, &ParseOptions::default());
+        assert_eq!(sentences(&entries), ["Ça, c'est l'été à Noël"]);
+        assert_eq!(entries[0].book(), "Les Misérables");
+    }
+
+    #[test]
+    fn bullet_split_makes_a_card_per_bullet() {
+        let clippings = [
+            clipping("Highlight", 10, "The well-fed cat walked over the hill"),
+            clipping("Note", 11, "- walked - hill - well-fed"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            bullet_split: Some("-".to_string()),
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let terms: Vec<Vec<String>> = cards(&entries).iter().map(|card| card.terms()).collect();
+        assert_eq!(terms, [["walked"], ["hill"], ["well-fed"]]);
+    }
+
+    #[test]
+    fn lists_unique_terms_sorted() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "walked\nhill"),
+            clipping("Highlight", 12, "The dog walked up the hill"),
+            clipping("Note", 13, "hill\ndog"),
+        ]
+        .concat();
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        assert_eq!(cards(&entries).len(), 4);
+        assert_eq!(list_terms(&entries), ["dog", "hill", "walked"]);
+    }
+
+    #[test]
+    fn exported_cloze_fields_have_no_padding() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill .. a small mountain"),
+        ]
+        .concat();
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        let [card] = cards(&entries)[..] else {
+            panic!("expected one card, got {entries:?}");
+        };
+        assert!(matches!(card, Card::Cloze(Cloze { text, .. }) if text.starts_with('\n')));
+        assert_eq!(
+            card.export_fields(),
+            ("The cat walked over the {{c1::hill}}", "a small mountain")
+        );
+
+        let tsv = export_tsv(std::slice::from_ref(card));
+        let row = tsv.lines().last().unwrap();
+        assert_eq!(
+            row,
+            "The cat walked over the {{c1::hill}}\ta small mountain\t"
+        );
+    }
+
+    #[test]
+    fn age_tag_buckets_clippings_by_age() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let tag = |days| age_tag(now - chrono::Duration::days(days), now);
+        assert_eq!(tag(0), "age::week");
+        assert_eq!(tag(20), "age::month");
+        assert_eq!(tag(100), "age::year");
+        assert_eq!(tag(400), "age::older");
+    }
+
+    #[test]
+    fn age_based_scheduling_tags_cards() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            age_based_scheduling: true,
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let [card] = cards(&entries)[..] else {
+            panic!("expected one card, got {entries:?}");
+        };
+        // added in 2018
+        assert_eq!(card.tags(), ["age::older"]);
+    }
+
+    #[test]
+    fn note_as_single_card_keeps_the_note_together() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping(
+                "Note",
+                11,
+                "cats climb hills\nbut rarely walk over them\nsee chapter 3",
+            ),
+        ]
+        .concat();
+        let options = ParseOptions {
+            note_as_single_card: true,
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let [Card::Basic(basic)] = cards(&entries)[..] else {
+            panic!("expected one basic card, got {entries:?}");
+        };
+        assert_eq!(
+            basic.back,
+            "cats climb hills\nbut rarely walk over them\nsee chapter 3\n\nThe cat walked over the hill"
+        );
+    }
+
+    #[test]
+    fn default_front_fills_empty_fronts() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill\nwalk ... to move on foot"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            default_front: Some("TODO: define".to_string()),
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let [Card::Cloze(cloze), Card::Basic(basic)] = cards(&entries)[..] else {
+            panic!("expected a cloze and a basic card, got {entries:?}");
+        };
+        assert_eq!(basic.front, "TODO: define");
+        assert!(cloze.text.starts_with("TODO: define\n"));
+    }
+
+    #[test]
+    fn excluded_books_are_left_out() {
+        let clippings = [
+            clipping("Highlight", 10, "Call me Ishmael"),
+            book_clipping(
+                "Oxford Dictionary of English (Oxford)",
+                "Highlight",
+                11,
+                "hill: a naturally raised area of land",
+            ),
+            book_clipping(
+                "Dune (Frank Herbert)",
+                "Highlight",
+                12,
+                "Fear is the mind-killer",
+            ),
+        ]
+        .concat();
+        let options = ParseOptions {
+            exclude_books: vec!["Dictionary".to_string(), "Dune".to_string()],
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let books: Vec<&str> = entries.iter().map(Clipping::book).collect();
+        assert_eq!(books, ["Moby Dick"]);
+    }
+
+    #[test]
+    fn stable_json_is_byte_identical_for_equal_data() {
+        let forwards: HashMap<String, usize> = (0..20).map(|n| (format!("book {n}"), n)).collect();
+        let backwards: HashMap<String, usize> =
+            (0..20).rev().map(|n| (format!("book {n}"), n)).collect();
+        assert_eq!(forwards, backwards);
+        assert_eq!(
+            to_stable_json(&forwards).unwrap(),
+            to_stable_json(&backwards).unwrap()
+        );
+
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        let (first, _) = parse(&clippings, &ParseOptions::default());
+        let (second, _) = parse(&clippings, &ParseOptions::default());
+        let json = to_stable_json(&first).unwrap();
+        assert_eq!(json, to_stable_json(&second).unwrap());
+        // keys are sorted within every object
+        assert!(json.find("\"author\"").unwrap() < json.find("\"book\"").unwrap());
+    }
+
+    #[test]
+    fn captures_the_color_of_a_highlight() {
+        let clippings = [
+            clipping("Highlight (Pink)", 10, "Call me Ishmael"),
+            clipping("Highlight", 11, "The cat walked over the hill"),
+        ]
+        .concat();
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        let colors: Vec<Option<&str>> = entries
+            .iter()
+            .map(|entry| match entry {
+                Clipping::Highlight { color, .. } => color.as_deref(),
+                _ => panic!("expected highlights, got {entries:?}"),
+            })
+            .collect();
+        assert_eq!(colors, [Some("pink"), None]);
+    }
+
+    #[test]
+    fn cloze_context_field_keeps_the_untouched_highlight() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            cloze_context_field: true,
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let [Card::Cloze(cloze)] = cards(&entries)[..] else {
+            panic!("expected one cloze, got {entries:?}");
+        };
+        assert_eq!(
+            cloze.context.as_deref(),
+            Some("The cat walked over the hill")
+        );
+        assert!(entries_to_markdown(&entries, &CardTemplate::default())
+            .contains("|> The cat walked over the hill"));
+
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        assert!(matches!(
+            cards(&entries)[..],
+            [Card::Cloze(Cloze { context: None, .. })]
+        ));
+    }
+
+    #[test]
+    fn colon_definition_is_a_basic_card() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill: a small mountain"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            colon_defs: true,
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let [Card::Basic(basic)] = cards(&entries)[..] else {
+            panic!("expected one basic card, got {entries:?}");
+        };
+        assert_eq!(basic.front, "a small mountain");
+        assert_eq!(basic.back, "hill\n\nThe cat walked over the hill");
+    }
+
+    #[test]
+    fn archive_receives_the_processed_clippings() {
+        let first = clipping("Highlight", 10, "Call me Ishmael");
+        let (out_dir, result) = run("archive", &first, &["--archive"]);
+        result.unwrap();
+        let archive = out_dir.join(ARCHIVE_FILE);
+        assert_eq!(fs::read_to_string(&archive).unwrap(), first);
+
+        // a second run appends, and the archive stays a clippings file
+        let second = clipping("Highlight", 20, "The dog ran");
+        archive_clippings(&second, &out_dir).unwrap();
+        let archived = fs::read_to_string(&archive).unwrap();
+        assert_eq!(archived, [first, second].concat());
+        let (entries, _) = parse(&archived, &ParseOptions::default());
+        assert_eq!(sentences(&entries), ["Call me Ishmael", "The dog ran"]);
+    }
+
+    #[test]
+    fn manifest_records_the_run() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill\nwalk ... to move on foot"),
+            clipping("Highlight", 12, "Call me Ishmael"),
+            clipping("Bookmark", 13, ""),
+        ]
+        .concat();
+        let before = Utc::now();
+        let (out_dir, result) = run("manifest", &clippings, &[]);
+        result.unwrap();
+
+        let manifest: Manifest =
+            serde_json::from_str(&fs::read_to_string(out_dir.join("manifest.json")).unwrap())
+                .unwrap();
+        assert!(manifest.input.ends_with("My Clippings.txt"));
+        assert_eq!(manifest.date_after, None);
+        assert_eq!(
+            manifest.counts,
+            ClippingCounts {
+                highlights: 1,
+                notes: 1,
+                bookmarks: 1,
+                cloze_cards: 1,
+                basic_cards: 1,
+            }
+        );
+        for output in ["output.md", "output-metadata.json"] {
+            assert!(
+                manifest.outputs.iter().any(|path| path.ends_with(output)),
+                "{output} missing from {:?}",
+                manifest.outputs
+            );
+        }
+        assert!(manifest.created >= before.with_nanosecond(0).unwrap());
+        assert_eq!(manifest.device, None);
+    }
+
+    #[test]
+    fn unmatched_cloze_is_demoted_and_tagged_needs_review() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "mountain .. a big hill"),
+        ]
+        .concat();
+        let (entries, warnings) = parse(&clippings, &ParseOptions::default());
+        let [Card::Basic(basic)] = cards(&entries)[..] else {
+            panic!("expected one basic card, got {entries:?}");
+        };
+        assert_eq!(basic.tags, ["needs-review"]);
+        assert_eq!(
+            basic.back,
+            "mountain\n\nThe cat walked over the hill\n\na big hill"
+        );
+        assert_eq!(
+            warnings.unmatched(),
+            [(
+                "mountain".to_string(),
+                "The cat walked over the hill".to_string()
+            )]
+        );
+    }
+
+    /// Writes `markdown` to `output.md` in a new directory, and returns its path
+    fn output_md(name: &str, markdown: &str) -> PathBuf {
+        let path = temp_dir(name).join("output.md");
+        fs::write(&path, markdown).unwrap();
+        path
+    }
+
+    /// Validates the output at `path` into TSV, which unlike JSON needs no
+    /// `output-metadata.json` next to it
+    fn validate_output(path: &Path, strict: bool) -> Result<(), Error> {
+        validate(
+            path.to_string_lossy().to_string(),
+            path.parent().unwrap(),
+            2,
+            strict,
+            &CardTemplate::default(),
+            CardFormat::Tsv,
+            ClozeReset::Card,
+            false,
+        )
+    }
+
+    #[test]
+    fn strict_validation_fails_on_a_basic_card_without_a_definition() {
+        let path = output_md(
+            "validate-strict",
+            "========\nThe cat walked over the hill\n========\n----\na small mountain\n|-\nhill\n----\n----\n\n|-\nwalk\n----\n",
+        );
+        validate_output(&path, false).unwrap();
+        let error = validate_output(&path, true).unwrap_err().to_string();
+        assert!(error.contains("1 incomplete card(s)"), "{error}");
+        assert!(
+            error.contains("line 9: basic card has no definition on its front"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn cloze_window_trims_around_the_deletions() {
+        let text = "\n\nOne two three four {{c1::five}} six seven eight nine";
+        assert_eq!(
+            window_cloze(text, 2),
+            "\n\n... three four {{c1::five}} six seven ..."
+        );
+        // nothing to cut
+        assert_eq!(window_cloze(text, 10), text);
+
+        let clippings = [
+            clipping(
+                "Highlight",
+                10,
+                "It was a long day and the cat walked over the hill before it finally rested",
+            ),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            cloze_window: Some(3),
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let [Card::Cloze(cloze)] = cards(&entries)[..] else {
+            panic!("expected one cloze, got {entries:?}");
+        };
+        assert_eq!(
+            cloze.text,
+            "\n\n... walked over the {{c1::hill}} before it finally ..."
+        );
+    }
+
+    #[test]
+    fn clipping_exactly_at_the_start_date_is_kept() {
+        let clippings = [
+            clipping("Highlight", 9, "Call me Ishmael"),
+            clipping("Highlight", 10, "The cat walked over the hill"),
+        ]
+        .concat();
+        let start = local_to_utc(
+            NaiveDate::from_ymd_opt(2018, 11, 24)
+                .unwrap()
+                .and_hms_opt(11, 10, 0)
+                .unwrap(),
+        );
+        let mut warnings = Warnings::default();
+        let entries = parse_from_txt(
+            &clippings,
+            Some(start),
+            &ParseOptions::default(),
+            &mut warnings,
+        )
+        .unwrap();
+        assert_eq!(sentences(&entries), ["The cat walked over the hill"]);
+    }
+
+    #[test]
+    fn grouped_note_lines_share_one_cloze() {
+        let cloze_texts = |note| {
+            let clippings = [
+                clipping("Highlight", 10, "The cat walked over the hill"),
+                clipping("Note", 11, note),
+            ]
+            .concat();
+            let (entries, _) = parse(&clippings, &ParseOptions::default());
+            cards(&entries)
+                .iter()
+                .map(|card| match card {
+                    Card::Cloze(cloze) => cloze.text.trim().to_string(),
+                    Card::Basic(_) => panic!("expected clozes, got {entries:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            cloze_texts("walked\n+ hill"),
+            ["The cat {{c1::walked}} over the {{c1::hill}}"]
+        );
+        assert_eq!(
+            cloze_texts("walked\nhill"),
+            [
+                "The cat {{c1::walked}} over the hill",
+                "The cat walked over the {{c1::hill}}"
+            ]
+        );
+    }
+
+    #[test]
+    fn device_header_is_split_from_the_clippings() {
+        let clippings = clipping("Highlight", 10, "Call me Ishmael");
+        let with_device =
+            format!("\u{feff}Device: Kindle Paperwhite\nAccount: reader@example.com\n{clippings}");
+        let (device, rest) = split_device_header(&with_device);
+        assert_eq!(
+            device.as_deref(),
+            Some("Device: Kindle Paperwhite; Account: reader@example.com")
+        );
+        assert_eq!(rest, clippings);
+        let (entries, _) = parse(&with_device, &ParseOptions::default());
+        assert_eq!(sentences(&entries), ["Call me Ishmael"]);
+
+        assert_eq!(split_device_header(&clippings), (None, clippings.as_str()));
+    }
+
+    #[test]
+    fn try_parse_never_panics_on_garbage() {
+        use rand::Rng;
+
+        // pieces of real clippings, so the garbage gets past the first checks
+        const PIECES: [&str; 12] = [
+            "Moby Dick (Herman Melville)",
+            "- Your Highlight on page 12 | Location 100-101 | Added on Saturday, November 24, 2018 11:10:00 AM",
+            "- Your Note on Location 100 | Added on ",
+            "- Your Bookmark",
+            "==========",
+            "\n",
+            "\r\n",
+            "walked .. hill ... extra",
+            "{{c1::",
+            "(",
+            "\u{feff}Device: ",
+            "ü日本",
+        ];
+        let mut rng = StdRng::seed_from_u64(237);
+        for _ in 0..2000 {
+            let length = rng.gen_range(0..20);
+            let garbage: String = (0..length)
+                .map(|_| {
+                    if rng.gen_bool(0.7) {
+                        PIECES.choose(&mut rng).unwrap().to_string()
+                    } else {
+                        char::from_u32(rng.gen_range(0..0x3000))
+                            .unwrap_or('?')
+                            .to_string()
+                    }
+                })
+                .collect();
+            let _ = try_parse(&garbage);
+        }
+        for garbage in ["", "==========", "\n\n\n", "a\n- Your\n\n==========\n"] {
+            let _ = try_parse(garbage);
+        }
+    }
+
+    #[test]
+    fn color_tags_tag_cards_by_highlight_color() {
+        let clippings = [
+            clipping("Highlight (Pink)", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+            clipping("Highlight (Yellow)", 12, "The dog walked up the hill"),
+            clipping("Note", 13, "dog"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            color_tags: BTreeMap::from([("pink".to_string(), "important".to_string())]),
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let tags: Vec<&[String]> = cards(&entries).iter().map(|card| card.tags()).collect();
+        assert_eq!(tags, [&["important".to_string()][..], &[]]);
+    }
+
+    #[test]
+    fn cloze_reverse_adds_a_card_asking_for_the_word() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill .. a small mountain"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            cloze_reverse: true,
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let [Card::Cloze(_), Card::Basic(reversed)] = cards(&entries)[..] else {
+            panic!("expected a cloze and its reverse, got {entries:?}");
+        };
+        assert_eq!(reversed.front, "a small mountain");
+        assert_eq!(reversed.back, "hill\n\nThe cat walked over the hill");
+
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        assert_eq!(cards(&entries).len(), 1);
+    }
+
+    #[test]
+    fn media_dir_recording_is_referenced_on_the_back() {
+        let media_dir = temp_dir("media-dir");
+        fs::write(media_dir.join("hill.mp3"), "").unwrap();
+        fs::write(media_dir.join("dog.ogg"), "").unwrap();
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+            clipping("Highlight", 12, "The cat sat on the mat"),
+            clipping("Note", 13, "mat"),
+        ]
+        .concat();
+        let (mut entries, _) = parse(&clippings, &ParseOptions::default());
+        attach_sounds(&mut entries, &sound_files(&media_dir).unwrap());
+        let backs: Vec<&str> = cards(&entries)
+            .iter()
+            .map(|card| match card {
+                Card::Cloze(cloze) => cloze.back_extra.as_str(),
+                Card::Basic(basic) => basic.back.as_str(),
+            })
+            .collect();
+        assert!(backs[0].ends_with("\n[sound:hill.mp3]"));
+        assert!(!backs[1].contains("[sound:"));
+        fs::remove_dir_all(media_dir).unwrap();
+    }
+
+    #[test]
+    fn blank_lines_inside_a_highlight_are_kept() {
+        let clippings = [
+            clipping("Highlight", 10, "\nCall me Ishmael.\n\n\nSome years ago\n"),
+            // no blank line after the header
+            clipping("Highlight", 11, "The cat walked over the hill").replacen("AM\n\n", "AM\n", 1),
+        ]
+        .concat();
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        assert_eq!(
+            sentences(&entries),
+            [
+                "Call me Ishmael.\n\n\nSome years ago",
+                "The cat walked over the hill"
+            ]
+        );
+    }
+
+    #[test]
+    fn new_books_only_skips_books_from_every_previous_run() {
+        let dir = temp_dir("new-books-only");
+        let clippings_path = dir.join("My Clippings.txt");
+        let out_dir = dir.join("out");
+        let args = [
+            "--clipping-path",
+            clippings_path.to_str().unwrap(),
+            "--out-dir",
+            out_dir.to_str().unwrap(),
+            "--ignore-last-date",
+            "--new-books-only",
+        ];
+        let mut clippings = clipping("Highlight", 10, "The cat walked over the hill");
+        fs::write(&clippings_path, &clippings).unwrap();
+        convert_config_to_finished_app(config(&args)).unwrap();
+        assert_eq!(metadata(&out_dir).len(), 1);
+
+        clippings.push_str(&book_clipping(
+            "Dune",
+            "Highlight",
+            20,
+            "Fear is the mind-killer",
+        ));
+        clippings.push_str(&clipping("Highlight", 30, "The dog ran"));
+        fs::write(&clippings_path, &clippings).unwrap();
+        convert_config_to_finished_app(config(&args)).unwrap();
+        let entries = metadata(&out_dir);
+        assert_eq!(sentences(&entries), ["Fear is the mind-killer"]);
+
+        // the second run's metadata only has Dune, Moby Dick is still seen
+        clippings.push_str(&clipping("Highlight", 40, "The bird flew"));
+        fs::write(&clippings_path, &clippings).unwrap();
+        convert_config_to_finished_app(config(&args)).unwrap();
+        assert!(metadata(&out_dir).is_empty());
+    }
+
+    #[test]
+    fn daylight_saving_changes_resolve_without_panicking() {
+        // Berlin, where the clocks went back from 03:00 to 02:00 on
+        // 2018-10-28, and forward from 02:00 to 03:00 on 2019-03-31
+        let berlin = |naive: &NaiveDateTime| {
+            let winter = FixedOffset::east_opt(3600).unwrap();
+            let summer = FixedOffset::east_opt(2 * 3600).unwrap();
+            let at = |offset: FixedOffset| offset.from_local_datetime(naive).unwrap().to_utc();
+            let hour = |date: &str, hour: u32| {
+                NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                    .unwrap()
+                    .and_hms_opt(hour, 0, 0)
+                    .unwrap()
+            };
+            if (hour("2018-10-28", 2)..hour("2018-10-28", 3)).contains(naive) {
+                LocalResult::Ambiguous(at(summer), at(winter))
+            } else if (hour("2019-03-31", 2)..hour("2019-03-31", 3)).contains(naive) {
+                LocalResult::None
+            } else if (hour("2018-10-28", 3)..hour("2019-03-31", 2)).contains(naive) {
+                LocalResult::Single(at(winter))
+            } else {
+                LocalResult::Single(at(summer))
+            }
+        };
+        let naive = |text: &str| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap();
+
+        // the first of the two 02:30s, still in summer time
+        assert_eq!(
+            resolve_local_time(naive("2018-10-28 02:30"), berlin),
+            Utc.with_ymd_and_hms(2018, 10, 28, 0, 30, 0).unwrap()
+        );
+        // 02:30 never happened, so the first time after the gap
+        assert_eq!(
+            resolve_local_time(naive("2019-03-31 02:30"), berlin),
+            Utc.with_ymd_and_hms(2019, 3, 31, 1, 0, 0).unwrap()
+        );
+        assert_eq!(
+            resolve_local_time(naive("2019-01-01 00:00"), berlin),
+            Utc.with_ymd_and_hms(2018, 12, 31, 23, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn read_date_tags_are_the_year_and_month_read() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            read_date_tags: true,
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        assert_eq!(cards(&entries)[0].tags(), ["read::2018", "read::2018-11"]);
+    }
+
+    #[test]
+    fn echo_cards_only_logs() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        // the manifest records when and where it was written, so differs anyway
+        let outputs = |out_dir: PathBuf| -> BTreeMap<_, _> {
+            fs::read_dir(out_dir)
+                .unwrap()
+                .map(|file| file.unwrap().path())
+                .filter(|path| !path.ends_with("manifest.json"))
+                .map(|path| {
+                    let contents = fs::read_to_string(&path).unwrap();
+                    (path.file_name().unwrap().to_owned(), contents)
+                })
+                .collect()
+        };
+        let (out_dir, result) = run("without-echo-cards", &clippings, &[]);
+        result.unwrap();
+        let without = outputs(out_dir);
+        let (out_dir, result) = run("echo-cards", &clippings, &["--echo-cards"]);
+        result.unwrap();
+        assert_eq!(outputs(out_dir), without);
+    }
+
+    #[test]
+    fn missing_description_error_names_the_note_line() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill ... "),
+        ]
+        .concat();
+        let (entries, warnings) = parse(&clippings, &ParseOptions::default());
+        assert!(cards(&entries).is_empty());
+        let error = warnings
+            .iter()
+            .find(|warning| warning.contains("no description provided"))
+            .unwrap();
+        assert!(error.contains("`hill ... `"), "{error}");
+        assert!(error.contains("Moby Dick"), "{error}");
+    }
+
+    #[test]
+    fn tag_prefix_namespaces_every_emitted_tag() {
+        let clippings = [
+            clipping("Highlight (Pink)", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            tag_prefix: "kindle::".to_string(),
+            book_tags: true,
+            read_date_tags: true,
+            color_tags: BTreeMap::from([("pink".to_string(), "important".to_string())]),
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let tags = cards(&entries)[0].tags();
+        assert_eq!(tags.len(), 4, "{tags:?}");
+        assert!(
+            tags.iter().all(|tag| tag.starts_with("kindle::")),
+            "{tags:?}"
+        );
+    }
+
+    #[test]
+    fn corrupt_block_between_good_ones_is_skipped() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            // a partial record, cut off after the header
+            "Moby Dick (Herman Melville)\n- Your Highlight on page 12 | Added on\n==========\n"
+                .to_string(),
+            clipping("Highlight", 12, "Call me Ishmael"),
+        ]
+        .concat();
+        let (entries, warnings) = parse(&clippings, &ParseOptions::default());
+        assert_eq!(
+            sentences(&entries),
+            ["The cat walked over the hill", "Call me Ishmael"]
+        );
+        assert!(
+            warnings.iter().any(|warning| warning.contains("line 7")),
+            "{warnings:?}"
+        );
+    }
+
+    #[test]
+    fn unmatched_csv_suggests_the_closest_span() {
+        let sentence = "The cat walked over the hill";
+        assert_eq!(
+            closest_span("walkd", sentence),
+            Some(("walked".to_string(), 1))
+        );
+        assert_eq!(
+            closest_span("Hills", sentence),
+            Some(("hill".to_string(), 1))
+        );
+        let csv = unmatched_csv(&[("walkd".to_string(), sentence.to_string())]);
+        assert_eq!(
+            csv,
+            "term,sentence,closest_span,distance\n\"walkd\",\"The cat walked over the hill\",\"walked\",1\n"
+        );
+    }
+
+    #[test]
+    fn first_line_shared_is_added_to_every_card_of_the_note() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "chapter one\nwalked\nhill .. a small mountain"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            first_line_shared: true,
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let backs: Vec<&str> = cards(&entries)
+            .iter()
+            .map(|card| card.export_fields().1)
+            .collect();
+        assert_eq!(backs, ["chapter one", "a small mountain\n\nchapter one"]);
+
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        assert_eq!(cards(&entries).len(), 3);
+    }
+
+    #[test]
+    fn merged_cloze_lines_number_their_clozes() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping(
+                "Note",
+                11,
+                "walked .. went on foot\nhill .. a small mountain",
+            ),
+        ]
+        .concat();
+        let options = ParseOptions {
+            merge_cloze_lines: true,
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let [Card::Cloze(cloze)] = cards(&entries)[..] else {
+            panic!("expected a single cloze, got {entries:?}");
+        };
+        assert_eq!(
+            cloze.text.trim(),
+            "The cat {{c1::walked}} over the {{c2::hill}}"
+        );
+        assert_eq!(
+            cards(&entries)[0].export_fields().1,
+            "went on foot\n\na small mountain"
+        );
+    }
+
+    #[test]
+    fn keeps_the_page_and_location_it_has() {
+        let with_header = |position: &str, minute: u32, body: &str| {
+            clipping("Highlight", minute, body)
+                .replace("on page 12 | Location 100-101 |", &format!("{position}|"))
+        };
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            with_header("on Location 1234-1236 ", 11, "Call me Ishmael"),
+            with_header("", 12, "Some years ago"),
+        ]
+        .concat();
+        let (entries, warnings) = parse(&clippings, &ParseOptions::default());
+        assert!(warnings.is_empty(), "{warnings:?}");
+        let positions: Vec<_> = entries
+            .iter()
+            .map(|entry| match entry {
+                Clipping::Highlight { page, location, .. } => (*page, *location),
+                entry => panic!("expected a highlight, got {entry:?}"),
+            })
+            .collect();
+        assert_eq!(
+            positions,
+            [(Some(12), Some(100)), (None, Some(1234)), (None, None)]
+        );
+        let json = serde_json::to_string(&entries).unwrap();
+        assert!(json.contains(r#""page":12"#) && json.contains(r#""location":1234"#));
+    }
+
+    #[test]
+    fn reverse_order_writes_the_newest_first() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+            clipping("Highlight", 12, "Call me Ishmael"),
+            clipping("Highlight", 13, "The dog ran down the road"),
+            clipping("Note", 14, "road"),
+        ]
+        .concat();
+        let (entries, warnings) = parse(&clippings, &ParseOptions::default());
+        let options = ConvertOptions {
+            reverse_order: true,
+            ..ConvertOptions::default()
+        };
+        let output = convert_entries(entries, warnings, &options).unwrap();
+        let minutes: Vec<u32> = output
+            .entries
+            .iter()
+            .map(|entry| entry.date().minute())
+            .collect();
+        assert_eq!(minutes, [14, 12, 11]);
+        // each note still makes its card from its own highlight
+        let road = output.markdown.find("{{c1::road}}").unwrap();
+        let hill = output.markdown.find("{{c1::hill}}").unwrap();
+        assert!(road < hill);
+        assert!(output
+            .markdown
+            .contains("The dog ran down the {{c1::road}}"));
+    }
+
+    #[test]
+    fn cloze_hiding_a_one_word_highlight_is_a_basic_card() {
+        let clippings = [
+            clipping("Highlight", 10, "Serendipity"),
+            clipping("Note", 11, "Serendipity .. a happy accident"),
+        ]
+        .concat();
+        let (entries, warnings) = parse(&clippings, &ParseOptions::default());
+        assert!(
+            warnings
+                .iter()
+                .any(|warning| warning.contains("hides all of")),
+            "{warnings:?}"
+        );
+        let [Card::Basic(basic)] = cards(&entries)[..] else {
+            panic!("expected a basic card, got {entries:?}");
+        };
+        assert!(!basic.back.contains("{{c"));
+        assert!(basic.back.ends_with("a happy accident"));
+    }
+
+    #[test]
+    fn custom_separators_replace_the_dots() {
+        let clippings = [
+            clipping("Highlight", 10, "He paused.. then walked over the hill"),
+            clipping("Note", 11, "hill >> a small mountain .. or a mound"),
+            clipping("Highlight", 12, "Call me Ishmael"),
+            clipping("Note", 13, "harpoon >>> a spear"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            cloze_sep: " >> ".to_string(),
+            extra_sep: " >>> ".to_string(),
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let [Card::Cloze(cloze), Card::Basic(basic)] = cards(&entries)[..] else {
+            panic!("expected a cloze and a basic card, got {entries:?}");
+        };
+        assert_eq!(
+            cloze.text.trim(),
+            "He paused.. then walked over the {{c1::hill}}"
+        );
+        assert_eq!(cloze.back_extra.trim(), "a small mountain .. or a mound");
+        assert_eq!(basic.back, "harpoon\na spear");
+    }
+
+    #[test]
+    fn card_template_round_trips_through_the_markdown() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill .. a small mountain"),
+            clipping("Highlight", 12, "Call me Ishmael"),
+            clipping("Note", 13, "harpoon ... a spear"),
+        ]
+        .concat();
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        let template =
+            CardTemplate::parse("<!-- id: kindle-import -->\n{front}\n|-\n{back}\n<!-- end -->")
+                .unwrap();
+        let markdown = entries_to_markdown(&entries, &template);
+        assert_eq!(markdown.matches("<!-- id: kindle-import -->\n").count(), 2);
+
+        let path = output_md("card-template", &markdown);
+        let (templated, _) = read_output_cards(path.to_str().unwrap(), 2, &template).unwrap();
+        let plain_markdown = entries_to_markdown(&entries, &CardTemplate::default());
+        let path = output_md("card-template-default", &plain_markdown);
+        let (plain, _) =
+            read_output_cards(path.to_str().unwrap(), 2, &CardTemplate::default()).unwrap();
+        assert_eq!(templated.len(), 2);
+        assert_eq!(templated, plain);
+        assert!(!format!("{templated:?}").contains("<!--"));
+    }
+
+    #[test]
+    fn book_reset_numbers_clozes_per_book() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+            clipping("Highlight", 12, "The dog ran down the road"),
+            clipping("Note", 13, "road"),
+            book_clipping("Dune", "Highlight", 14, "Fear is the mind-killer"),
+            book_clipping("Dune", "Note", 15, "Fear"),
+        ]
+        .concat();
+        let numbers = |reset: ClozeReset| {
+            let (mut entries, _) = parse(&clippings, &ParseOptions::default());
+            renumber_clozes(&mut entries, reset);
+            cards(&entries)
+                .iter()
+                .map(|card| match card {
+                    Card::Cloze(cloze) => cloze.text[cloze.text.find("{{c").unwrap()..]
+                        .split("::")
+                        .next()
+                        .unwrap()
+                        .to_string(),
+                    Card::Basic(_) => panic!("expected only clozes"),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(numbers(ClozeReset::Book), ["{{c1", "{{c2", "{{c1"]);
+        assert_eq!(numbers(ClozeReset::Never), ["{{c1", "{{c2", "{{c3"]);
+        assert_eq!(numbers(ClozeReset::Card), ["{{c1", "{{c1", "{{c1"]);
+    }
+
+    #[test]
+    fn parse_errors_report_the_line_of_the_clippings_file() {
+        let good = clipping("Highlight", 10, "The cat walked over the hill");
+        let bad_header =
+            format!("{good}Moby Dick (Herman Melville)\n- Your Highlight\n\nhill\n==========\n");
+        let error = try_parse(&bad_header).unwrap_err();
+        assert!(
+            matches!(error, ParseError::MalformedHeader { line: 7, .. }),
+            "{error:?}"
+        );
+
+        let bad_date = format!(
+            "{good}{}",
+            clipping("Highlight", 12, "Call me Ishmael").replace(
+                "Saturday, November 24, 2018 11:12:00 AM",
+                "sometime, never 12"
+            )
+        );
+        let error = try_parse(&bad_date).unwrap_err();
+        assert!(
+            matches!(&error, ParseError::UnrecognizedDate { line: 7, raw } if raw.contains("never")),
+            "{error:?}"
+        );
+        assert_eq!(error.line(), 7);
+
+        let unknown_kind = format!("{good}{}", clipping("Scribble", 12, "a drawing"));
+        let error = try_parse(&unknown_kind).unwrap_err();
+        assert!(
+            matches!(&error, ParseError::UnknownAnnotationType { line: 7, kind } if kind.contains("Scribble")),
+            "{error:?}"
+        );
+    }
+
+    #[test]
+    fn keep_raw_stores_the_note_line_in_the_metadata() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill .. a small mountain"),
+        ]
+        .concat();
+        let (out_dir, result) = run("keep-raw", &clippings, &["--keep-raw"]);
+        result.unwrap();
+        let entries = metadata(&out_dir);
+        let [Card::Cloze(cloze)] = cards(&entries)[..] else {
+            panic!("expected a cloze, got {entries:?}");
+        };
+        assert_eq!(cloze.raw_note.as_deref(), Some("hill .. a small mountain"));
+
+        let (out_dir, result) = run("without-keep-raw", &clippings, &[]);
+        result.unwrap();
+        assert!(!fs::read_to_string(out_dir.join("output-metadata.json"))
+            .unwrap()
+            .contains("raw_note"));
+    }
+
+    #[test]
+    fn dry_run_creates_no_files() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        let (out_dir, result) = run("dry-run", &clippings, &["--dry-run"]);
+        result.unwrap();
+        assert!(!out_dir.exists());
+
+        // an output that would be backed up and replaced is left alone too
+        let clippings_path = out_dir.with_file_name("My Clippings.txt");
+        fs::create_dir_all(&out_dir).unwrap();
+        fs::write(out_dir.join("output.md"), "edited").unwrap();
+        convert_config_to_finished_app(config(&[
+            "--clipping-path",
+            clippings_path.to_str().unwrap(),
+            "--out-dir",
+            out_dir.to_str().unwrap(),
+            "--dry-run",
+        ]))
+        .unwrap();
+        let files: Vec<_> = fs::read_dir(&out_dir)
+            .unwrap()
+            .map(|file| file.unwrap().file_name())
+            .collect();
+        assert_eq!(files, ["output.md"]);
+        assert_eq!(
+            fs::read_to_string(out_dir.join("output.md")).unwrap(),
+            "edited"
+        );
+    }
+
+    #[test]
+    fn validate_reports_a_gapped_cloze_card() {
+        let path = output_md(
+            "validate-gapped-cloze",
+            "----\n\n\nThe {{c1::cat}} walked over the {{c3::hill}}\n|-\n\n----\n",
+        );
+        let error = validate_output(&path, true).unwrap_err().to_string();
+        assert!(
+            error.contains("line 1: cloze numbers aren't sequential, missing c2"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn duplicate_highlights_collapse_into_the_earliest() {
+        let clippings = [
+            clipping("Highlight", 20, "The cat walked over the hill"),
+            clipping("Highlight", 21, "Call me Ishmael"),
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            book_clipping("Dune", "Highlight", 22, "The cat walked over the hill"),
+        ]
+        .concat();
+        let (mut entries, _) = parse(&clippings, &ParseOptions::default());
+        dedup_highlights(&mut entries);
+        assert_eq!(
+            sentences(&entries),
+            [
+                "The cat walked over the hill",
+                "Call me Ishmael",
+                "The cat walked over the hill"
+            ]
+        );
+        let minutes: Vec<u32> = entries.iter().map(|entry| entry.date().minute()).collect();
+        assert_eq!(minutes, [10, 21, 22]);
+    }
+
+    #[test]
+    fn title_lines_without_or_with_nested_parentheses() {
+        let clippings = [
+            "Moby Dick",
+            "Dune (Dune Chronicles (Book 1)) (Frank Herbert)",
+            "The Hobbit (Or There and Back Again)",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(minute, title)| book_clipping(title, "Highlight", minute as u32, "Call me Ishmael"))
+        .collect::<String>();
+        let (entries, warnings) = parse(&clippings, &ParseOptions::default());
+        assert!(warnings.is_empty(), "{warnings:?}");
+        let books: Vec<(&str, &str)> = entries
+            .iter()
+            .map(|entry| match entry {
+                Clipping::Highlight { book, author, .. } => (book.as_str(), author.as_str()),
+                entry => panic!("expected a highlight, got {entry:?}"),
+            })
+            .collect();
+        assert_eq!(
+            books,
+            [
+                ("Moby Dick", ""),
+                ("Dune (Dune Chronicles (Book 1))", "Frank Herbert"),
+                // can't be told apart from an author
+                ("The Hobbit", "Or There and Back Again"),
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_windows_1252_output() {
+        let path = temp_dir("output-encoding").join("output.md");
+        let encoding = OutputEncoding {
+            encoding: encoding_rs::WINDOWS_1252,
+            strict: false,
+        };
+        write("Café à la crème ✓".to_string(), &path, encoding, false).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        // one byte per accented letter, unlike utf-8
+        assert!(bytes.starts_with(b"Caf\xe9 \xe0 la cr\xe8me "));
+        let (decoded, _, had_errors) = encoding_rs::WINDOWS_1252.decode(&bytes);
+        assert!(!had_errors);
+        assert_eq!(decoded, "Café à la crème &#10003;");
+
+        let strict = OutputEncoding {
+            strict: true,
+            ..encoding
+        };
+        let error = write("✓".to_string(), &path, strict, false).unwrap_err();
+        assert!(format!("{error:#}").contains("can't represent the characters `✓`"));
+    }
+
+    #[test]
+    fn familiarity_prefix_tags_the_card() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "@3 hill .. a small mountain\nwalked"),
+        ]
+        .concat();
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        let [Card::Cloze(hill), Card::Cloze(walked)] = cards(&entries)[..] else {
+            panic!("expected two clozes, got {entries:?}");
+        };
+        assert_eq!(hill.tags, ["familiarity::3"]);
+        assert!(hill.text.contains("{{c1::hill}}"));
+        assert!(!hill.text.contains('@'));
+        assert!(walked.tags.is_empty());
+    }
+
+    #[test]
+    fn tsv_escapes_tabs_line_breaks_and_quotes() {
+        let card = Card::Basic(Basic {
+            front: "a small mountain".to_string(),
+            back: "hill\tnoun\nThe \"cat\" walked over the hill".to_string(),
+            tags: vec!["moby_dick".to_string()],
+            raw_note: None,
+            extra: None,
+        });
+        assert_eq!(
+            export_tsv(std::slice::from_ref(&card)),
+            "#separator:tab\n#html:true\n#notetype:Basic\n#tags column:3\n\
+             a small mountain\t\"hill&#9;noun<br>The \"\"cat\"\" walked over the hill\"\tmoby_dick\n"
+        );
+        // a cloze file is its own note type
+        let tsv = export_tsv(&[cloze("The cat walked over the {{c1::hill}}")]);
+        assert!(tsv.contains("#notetype:Cloze\n"));
+        assert!(tsv.ends_with("The cat walked over the {{c1::hill}}\t\t\n"));
+    }
+
+    #[test]
+    fn third_section_of_a_card_is_its_extra() {
+        let path = output_md(
+            "three-sections",
+            "========\nThe cat walked over the hill\n========\n----\na small mountain\n|-\nhill\n|-\nfrom the old english hyll\n----\n",
+        );
+        let (cards, _) =
+            read_output_cards(path.to_str().unwrap(), 2, &CardTemplate::default()).unwrap();
+        let [Card::Basic(basic)] = &cards[..] else {
+            panic!("expected a basic card, got {cards:?}");
+        };
+        assert_eq!(basic.front, "a small mountain");
+        assert_eq!(basic.back, "hill<br><br>The cat walked over the hill");
+        assert_eq!(basic.extra.as_deref(), Some("from the old english hyll"));
+    }
+
+    #[test]
+    fn every_overwrite_keeps_its_own_backup() {
+        let dir = temp_dir("backups");
+        let path = dir.join("output.md");
+        for contents in ["first", "second", "third"] {
+            write(
+                contents.to_string(),
+                &path,
+                OutputEncoding::default(),
+                false,
+            )
+            .unwrap();
+        }
+        let mut backups: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|file| file.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with("output-copy-"))
+            .collect();
+        backups.sort();
+        assert_eq!(backups.len(), 2, "{backups:?}");
+        let mut contents: Vec<String> = backups
+            .iter()
+            .map(|name| fs::read_to_string(dir.join(name)).unwrap())
+            .collect();
+        contents.sort();
+        assert_eq!(contents, ["first", "second"]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third");
+    }
+
+    #[test]
+    fn tag_flags_tag_every_card_in_the_output() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill\nwalked"),
+        ]
+        .concat();
+        let (out_dir, result) = run("tags", &clippings, &["--tag", "kindle", "--tag", "2024-q1"]);
+        result.unwrap();
+        let entries = metadata(&out_dir);
+        let tags: Vec<&[String]> = cards(&entries).iter().map(|card| card.tags()).collect();
+        assert_eq!(tags.len(), 2);
+        assert!(tags.iter().all(|tags| *tags == ["kindle", "2024-q1"]));
+        let markdown = fs::read_to_string(out_dir.join("output.md")).unwrap();
+        assert_eq!(markdown.matches("|# kindle 2024-q1\n").count(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn transform_cmd_rewrites_cloze_terms() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = temp_dir("transform-cmd").join("upper.sh");
+        fs::write(&script, "#!/bin/sh\ntr a-z A-Z\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the HILL"),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            transform_cmd: Some(script),
+            ..ParseOptions::default()
+        };
+        let (entries, warnings) = parse(&clippings, &options);
+        assert!(warnings.is_empty(), "{warnings:?}");
+        let [Card::Cloze(cloze)] = cards(&entries)[..] else {
+            panic!("expected a cloze, got {entries:?}");
+        };
+        assert!(cloze.text.ends_with("{{c1::HILL}}"), "{}", cloze.text);
+
+        // a failing command leaves the term alone
+        let options = ParseOptions {
+            transform_cmd: Some(PathBuf::from("false")),
+            ..ParseOptions::default()
+        };
+        let mut warnings = Warnings::default();
+        assert_eq!(transform_term("hill", &options, &mut warnings), "hill");
+        assert!(warnings
+            .iter()
+            .any(|warning| warning.contains("keeping it as it is")));
+    }
+
+    #[test]
+    fn book_tag_is_a_clean_slug_of_the_title() {
+        assert_eq!(
+            book_tag("Crime & Punishment: Vol. 1"),
+            "crime_punishment_vol_1"
+        );
+        assert_eq!(book_tag("Either/Or"), "either_or");
+        assert_eq!(book_tag("  Les Misérables!  "), "les_miserables");
+        assert_eq!(book_tag("白鯨"), "白鯨");
+        assert_eq!(book_tag(":/"), "");
+
+        let clippings = [
+            book_clipping(
+                "Crime & Punishment: Vol. 1",
+                "Highlight",
+                10,
+                "The cat walked over the hill",
+            ),
+            book_clipping("Crime & Punishment: Vol. 1", "Note", 11, "hill"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            book_slug_tags: true,
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        assert_eq!(cards(&entries)[0].tags(), ["crime_punishment_vol_1"]);
+    }
+
+    #[test]
+    fn second_run_with_the_same_clippings_skips_every_book() {
+        let dir = temp_dir("skip-unchanged-books");
+        let clippings_path = dir.join("My Clippings.txt");
+        let out_dir = dir.join("out");
+        let args = [
+            "--clipping-path",
+            clippings_path.to_str().unwrap(),
+            "--out-dir",
+            out_dir.to_str().unwrap(),
+            "--ignore-last-date",
+            "--skip-unchanged-books",
+        ];
+        let mut clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+            book_clipping("Dune", "Highlight", 12, "Fear is the mind-killer"),
+        ]
+        .concat();
+        fs::write(&clippings_path, &clippings).unwrap();
+        convert_config_to_finished_app(config(&args)).unwrap();
+        assert_eq!(metadata(&out_dir).len(), 2);
+        assert!(out_dir.join(BOOK_HASHES_FILE).exists());
+
+        convert_config_to_finished_app(config(&args)).unwrap();
+        assert!(metadata(&out_dir).is_empty());
+
+        // only the book that changed is made again
+        clippings.push_str(&book_clipping(
+            "Dune",
+            "Highlight",
+            13,
+            "Walk without rhythm",
+        ));
+        fs::write(&clippings_path, &clippings).unwrap();
+        convert_config_to_finished_app(config(&args)).unwrap();
+        assert_eq!(
+            sentences(&metadata(&out_dir)),
+            ["Fear is the mind-killer", "Walk without rhythm"]
+        );
+    }
+
+    #[test]
+    fn identical_cards_are_warned_about_or_dropped() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+            clipping("Highlight", 12, "The cat walked over the hill"),
+            clipping("Note", 13, "hill"),
+        ]
+        .concat();
+        let (mut entries, _) = parse(&clippings, &ParseOptions::default());
+        let mut warnings = Warnings::default();
+        check_duplicate_cards(&mut entries, false, &mut warnings);
+        assert_eq!(cards(&entries).len(), 2);
+        assert_eq!(
+            warnings.iter().collect::<Vec<_>>(),
+            ["card `The cat walked over the {{c1::hill}}` in `Moby Dick` duplicates one in `Moby Dick`"]
+        );
+
+        let mut warnings = Warnings::default();
+        check_duplicate_cards(&mut entries, true, &mut warnings);
+        assert_eq!(cards(&entries).len(), 1);
+        assert!(warnings.iter().next().unwrap().ends_with(", dropping it"));
+    }
+
+    #[test]
+    fn inflected_terms_are_found_by_their_stem() {
+        let clippings = [
+            clipping("Highlight", 10, "I was walking home"),
+            clipping("Note", 11, "walked"),
+            clipping("Highlight", 12, "The dog runs down the road"),
+            clipping("Note", 13, "running"),
+        ]
+        .concat();
+        let (entries, warnings) = parse(&clippings, &ParseOptions::default());
+        assert!(warnings.unmatched().is_empty(), "{warnings:?}");
+        let texts: Vec<&str> = cards(&entries)
+            .iter()
+            .map(|card| card.export_fields().0)
+            .collect();
+        assert_eq!(
+            texts,
+            [
+                "I was {{c1::walking}} home",
+                "The dog {{c1::runs}} down the road"
+            ]
+        );
+    }
+
+    #[test]
+    fn require_cloze_match_fails_on_a_term_not_in_its_highlight() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "mountain"),
+        ]
+        .concat();
+        let (out_dir, result) = run(
+            "require-cloze-match",
+            &clippings,
+            &["--require-cloze-match"],
+        );
+        let error = result.unwrap_err().to_string();
+        assert!(
+            error.contains("`mountain` in `The cat walked over the hill`"),
+            "{error}"
+        );
+        assert!(!out_dir.join("output.md").exists());
+    }
+
+    #[test]
+    fn require_min_cards_fails_before_writing() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill\nwalked"),
+        ]
+        .concat();
+        let (out_dir, result) = run("min-cards-unmet", &clippings, &["--require-min-cards", "3"]);
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("only 2 cards were made"), "{error}");
+        assert_eq!(fs::read_dir(&out_dir).unwrap().count(), 0);
+
+        let (out_dir, result) = run("min-cards-met", &clippings, &["--require-min-cards", "2"]);
+        result.unwrap();
+        assert_eq!(cards(&metadata(&out_dir)).len(), 2);
+    }
+
+    #[test]
+    fn start_date_exclusive_drops_the_clipping_on_the_boundary() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Highlight", 11, "Call me Ishmael"),
+        ]
+        .concat();
+        let start = local_to_utc(
+            NaiveDate::from_ymd_opt(2018, 11, 24)
+                .unwrap()
+                .and_hms_opt(11, 10, 0)
+                .unwrap(),
+        );
+        let minutes_after = |start_date_exclusive| {
+            let options = ParseOptions {
+                start_date_exclusive,
+                ..ParseOptions::default()
+            };
+            let entries =
+                parse_from_txt(&clippings, Some(start), &options, &mut Warnings::default())
+                    .unwrap();
+            entries
+                .iter()
+                .map(|entry| entry.date().minute())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(minutes_after(false), [10, 11]);
+        assert_eq!(minutes_after(true), [11]);
+    }
+
+    #[test]
+    fn from_metadata_writes_the_same_markdown_again() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill .. a small mountain\nharpoon ... a spear"),
+            clipping("Highlight", 12, "Call me Ishmael"),
+        ]
+        .concat();
+        let (out_dir, result) = run("from-metadata", &clippings, &["--tag", "kindle"]);
+        result.unwrap();
+        let output_path = out_dir.join("output.md");
+        let markdown = fs::read_to_string(&output_path).unwrap();
+        fs::remove_file(&output_path).unwrap();
+
+        convert_config_to_finished_app(config(&[
+            "--from-metadata",
+            "--out-dir",
+            out_dir.to_str().unwrap(),
+        ]))
+        .unwrap();
+        assert_eq!(fs::read_to_string(&output_path).unwrap(), markdown);
+    }
+
+    #[test]
+    fn book_text_puts_the_neighboring_sentences_on_the_back() {
+        let book_text =
+            "It was a cold morning. The cat walked over the hill. Then it rained! Nobody came.";
+        assert_eq!(
+            book_context("The cat walked over the hill.", book_text).as_deref(),
+            Some("It was a cold morning. … Then it rained!")
+        );
+        assert_eq!(
+            book_context("It was a cold", book_text).as_deref(),
+            Some("… morning.")
+        );
+        assert_eq!(book_context("Call me Ishmael", book_text), None);
+
+        // the book's line breaks don't get in the way
+        let book_path = temp_dir("book-text-file").join("moby-dick.txt");
+        fs::write(&book_path, book_text.replace(" The cat", "\n\nThe  cat")).unwrap();
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill."),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        let (out_dir, result) = run(
+            "book-text",
+            &clippings,
+            &["--book-text", book_path.to_str().unwrap()],
+        );
+        result.unwrap();
+        assert_eq!(
+            cards(&metadata(&out_dir))[0].export_fields().1,
+            "It was a cold morning. … Then it rained!"
+        );
+    }
+
+    #[test]
+    fn highlight_with_a_delimiter_line_survives_the_markdown() {
+        let clippings = clipping(
+            "Highlight",
+            10,
+            "CHAPTER ONE\n========\nThe cat walked over the hill",
+        );
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        let mut markdown = entries_to_markdown(&entries, &CardTemplate::default());
+        assert!(markdown.contains("\n\\========\n"));
+        // a definition added under the highlight
+        markdown.push_str("----\na small animal\n|-\ncat\n----\n");
+
+        let path = output_md("delimiter-in-highlight", &markdown);
+        let (cards, _) =
+            read_output_cards(path.to_str().unwrap(), 2, &CardTemplate::default()).unwrap();
+        let [Card::Basic(basic)] = &cards[..] else {
+            panic!("expected one basic card, got {cards:?}");
+        };
+        assert_eq!(
+            basic.back,
+            "cat<br><br>CHAPTER ONE<br>========<br>The cat walked over the hill"
+        );
+    }
+
+    #[test]
+    fn convert_works_on_clippings_in_memory() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill .. a small mountain"),
+            clipping("Highlight", 12, "Call me Ishmael"),
+            clipping("Highlight", 13, "Call me Ishmael"),
+        ]
+        .concat();
+        let output = convert(&clippings, &ConvertOptions::default()).unwrap();
+        assert!(output.warnings.is_empty());
+        // the repeated highlight is only kept once
+        assert_eq!(output.entries.len(), 2);
+        assert_eq!(
+            output.markdown,
+            entries_to_markdown(&output.entries, &CardTemplate::default())
+        );
+        assert!(output
+            .markdown
+            .contains("The cat walked over the {{c1::hill}}"));
+        let metadata: Vec<Clipping> = serde_json::from_str(&output.metadata).unwrap();
+        assert_eq!(metadata, output.entries);
+        assert!(output.book_hashes.is_none());
+    }
+
+    #[test]
+    fn convert_reads_other_sources_and_reports_errors() {
+        let export = r#"{ "results": [ { "title": "Moby Dick", "highlights": [
+            { "text": "Call me Ishmael", "highlighted_at": "2022-03-14T10:00:00Z" }
+        ] } ] }"#;
+        let options = ConvertOptions {
+            source: Source::Readwise,
+            ..ConvertOptions::default()
+        };
+        let output = convert(export, &options).unwrap();
+        assert_eq!(sentences(&output.entries), ["Call me Ishmael"]);
+        assert!(convert("not json", &options).is_err());
+    }
+
+    #[test]
+    fn cloze_scope_first_only_blanks_the_first_occurrence() {
+        let clippings = [
+            clipping("Highlight", 10, "The hill behind the hill"),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        let (all, _) = parse(&clippings, &ParseOptions::default());
+        assert_eq!(
+            cards(&all)[0].export_fields().0,
+            "The {{c1::hill}} behind the {{c1::hill}}"
+        );
+        let options = ParseOptions {
+            cloze_scope: ClozeScope::First,
+            ..ParseOptions::default()
+        };
+        let (first, _) = parse(&clippings, &options);
+        assert_eq!(
+            cards(&first)[0].export_fields().0,
+            "The {{c1::hill}} behind the hill"
+        );
+    }
+
+    #[test]
+    fn writing_over_an_output_backs_it_up_first() {
+        let dir = temp_dir("write-backup");
+        let path = dir.join("output.md");
+        fs::write(&path, "cards from the last run").unwrap();
+        write(
+            "cards from this run".to_string(),
+            &path,
+            OutputEncoding::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "cards from this run");
+        let mut names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|file| file.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        let [backup, output] = &names[..] else {
+            panic!("expected the output and one backup, got {names:?}");
+        };
+        assert_eq!(output, "output.md");
+        assert!(backup.starts_with("output-copy-"), "{backup}");
+        assert_eq!(
+            fs::read_to_string(dir.join(backup)).unwrap(),
+            "cards from the last run"
+        );
+    }
+
+    #[test]
+    fn out_dir_holds_every_file_of_a_run() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        let (out_dir, result) = run("out-dir", &clippings, &[]);
+        result.unwrap();
+        let dir = out_dir.parent().unwrap();
+        convert_config_to_finished_app(config(&[
+            "--clipping-path",
+            dir.join("My Clippings.txt").to_str().unwrap(),
+            "--out-dir",
+            out_dir.to_str().unwrap(),
+            "--validate",
+        ]))
+        .unwrap();
+        for name in [
+            "output.md",
+            "output-metadata.json",
+            "output.json",
+            "last-date.json",
+        ] {
+            assert!(out_dir.join(name).is_file(), "{name} is missing");
+        }
+        // nothing is written next to the out dir either
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|file| file.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["My Clippings.txt", "out"]);
+    }
+
+    #[test]
+    fn manifest_lists_every_file_the_run_wrote() {
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill"),
+        ]
+        .concat();
+        let (out_dir, result) = run(
+            "manifest-outputs",
+            &clippings,
+            &[
+                "--archive",
+                "--skip-unchanged-books",
+                "--new-books-only",
+                "--list-terms",
+            ],
+        );
+        result.unwrap();
+        let manifest: Manifest =
+            serde_json::from_str(&fs::read_to_string(out_dir.join("manifest.json")).unwrap())
+                .unwrap();
+        let mut listed: Vec<String> = manifest
+            .outputs
+            .iter()
+            .map(|path| {
+                Path::new(path)
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        listed.sort();
+        let mut written: Vec<String> = fs::read_dir(&out_dir)
+            .unwrap()
+            .map(|file| file.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        written.sort();
+        assert_eq!(listed, written);
+    }
+}
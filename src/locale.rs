@@ -0,0 +1,277 @@
+//! Kindles write the header line of a clipping in the language of the device,
+//! e.g.
+//!
+//! ```txt
+//! - Your Highlight on page 12 | Location 170-171 | Added on Saturday, November 24, 2018 11:31:30 AM
+//! - Ihre Markierung auf Seite 12 | Position 170-171 | Hinzugefügt am Samstag, 24. November 2018 11:31:30
+//! - Votre surlignement sur la page 12 | emplacement 170-171 | Ajouté le samedi 24 novembre 2018 11:31:30
+//! - Tu subrayado en la página 12 | posición 170-171 | Añadido el sábado, 24 de noviembre de 2018 11:31:30
+//! - 12ページ|位置No. 170-171のハイライト |作成日: 2018年11月24日土曜日 11:31:30
+//! ```
+//!
+//! so the kind and date of a clipping are looked up in each of these languages.
+use chrono::NaiveDateTime;
+
+/// The kinds of annotation as the English kindle calls them, and what the
+/// other languages call them
+const KINDS: [(&str, &[&str]); 3] = [
+    (
+        "Highlight",
+        &[
+            "highlight",
+            "markierung",
+            "surlignement",
+            "subrayado",
+            "ハイライト",
+        ],
+    ),
+    ("Note", &["note", "notiz", "nota", "メモ"]),
+    (
+        "Bookmark",
+        &[
+            "bookmark",
+            "lesezeichen",
+            "signet",
+            "marcador",
+            "ブックマーク",
+        ],
+    ),
+];
+
+/// What comes before the date after the last `|`
+const LABELS: [&str; 5] = [
+    "Added on",
+    "Hinzugefügt am",
+    "Ajouté le",
+    "Añadido el",
+    "作成日:",
+];
+
+const WEEKDAYS: [&str; 35] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+    "montag",
+    "dienstag",
+    "mittwoch",
+    "donnerstag",
+    "freitag",
+    "samstag",
+    "sonntag",
+    "lundi",
+    "mardi",
+    "mercredi",
+    "jeudi",
+    "vendredi",
+    "samedi",
+    "dimanche",
+    "lunes",
+    "martes",
+    "miércoles",
+    "jueves",
+    "viernes",
+    "sábado",
+    "domingo",
+    "月曜日",
+    "火曜日",
+    "水曜日",
+    "木曜日",
+    "金曜日",
+    "土曜日",
+    "日曜日",
+];
+
+/// Month names of the other languages, in order, which are translated to
+/// English since chrono only parses English ones
+const MONTHS: [[&str; 12]; 3] = [
+    [
+        "januar",
+        "februar",
+        "märz",
+        "april",
+        "mai",
+        "juni",
+        "juli",
+        "august",
+        "september",
+        "oktober",
+        "november",
+        "dezember",
+    ],
+    [
+        "janvier",
+        "février",
+        "mars",
+        "avril",
+        "mai",
+        "juin",
+        "juillet",
+        "août",
+        "septembre",
+        "octobre",
+        "novembre",
+        "décembre",
+    ],
+    [
+        "enero",
+        "febrero",
+        "marzo",
+        "abril",
+        "mayo",
+        "junio",
+        "julio",
+        "agosto",
+        "septiembre",
+        "octubre",
+        "noviembre",
+        "diciembre",
+    ],
+];
+
+const ENGLISH_MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// Formats of the date once the weekday is dropped and the month translated,
+/// tried in order
+const DATE_FORMATS: [&str; 5] = [
+    // November 24, 2018 11:31:30 AM
+    "%B %d, %Y %-I:%M:%S %p",
+    // 24. November 2018 11:31:30
+    "%d. %B %Y %H:%M:%S",
+    // 24 November 2018 11:31:30
+    "%d %B %Y %H:%M:%S",
+    // 24 de November de 2018 11:31:30
+    "%d de %B de %Y %H:%M:%S",
+    // 2018年11月24日 11:31:30
+    "%Y年%m月%d日 %H:%M:%S",
+];
+
+/// The kind of annotation described by the part of a header before the date,
+/// e.g. `Highlight` for `Ihre Markierung auf Seite 12`
+pub(crate) fn annotation_kind(head: &str) -> Option<&'static str> {
+    let head = head.to_lowercase();
+    KINDS
+        .iter()
+        .find(|(_, names)| names.iter().any(|name| head.contains(name)))
+        .map(|(kind, _)| *kind)
+}
+
+/// Parses the date after the last `|` of a header, e.g. `Added on Saturday,
+/// November 24, 2018 11:31:30 AM`. A `format` replaces the known ones, and is
+/// matched against the date once its label and weekday are dropped and its
+/// month is translated to English.
+pub(crate) fn parse_date(added: &str, format: Option<&str>) -> Option<NaiveDateTime> {
+    let added = added.trim();
+    let date = LABELS
+        .iter()
+        .find_map(|label| added.strip_prefix(label))
+        .unwrap_or(added);
+    let date = normalize(date);
+
+    match format {
+        Some(format) => NaiveDateTime::parse_from_str(&date, format).ok(),
+        None => DATE_FORMATS
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(&date, format).ok()),
+    }
+}
+
+/// Drops the weekday and translates the month to English, e.g. `Samstag, 24.
+/// November 2018` becomes `24. November 2018`
+fn normalize(date: &str) -> String {
+    let mut date = date.to_string();
+    for weekday in WEEKDAYS {
+        // Japanese weekdays follow the day without a space, e.g. `24日土曜日`
+        if let Some(start) = date.to_lowercase().find(weekday) {
+            if date.is_char_boundary(start) && date.is_char_boundary(start + weekday.len()) {
+                date.replace_range(start..start + weekday.len(), "");
+            }
+        }
+    }
+
+    let words: Vec<String> = date
+        .split(' ')
+        .map(|word| {
+            let lower = word.to_lowercase();
+            MONTHS
+                .iter()
+                .find_map(|months| months.iter().position(|month| *month == lower))
+                .map_or_else(
+                    || word.to_string(),
+                    |index| ENGLISH_MONTHS[index].to_string(),
+                )
+        })
+        .collect();
+    words
+        .join(" ")
+        .trim_start_matches([',', ' '])
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ParseOptions;
+    use crate::tests::{parse, sentences};
+    use crate::Clipping;
+
+    fn november_24() -> NaiveDateTime {
+        NaiveDateTime::parse_from_str("2018-11-24 11:31:30", "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn parses_german_and_japanese_dates() {
+        assert_eq!(
+            parse_date("Hinzugefügt am Samstag, 24. November 2018 11:31:30", None),
+            Some(november_24())
+        );
+        assert_eq!(
+            parse_date("作成日: 2018年11月24日土曜日 11:31:30", None),
+            Some(november_24())
+        );
+        assert_eq!(
+            parse_date("24/11/2018 11:31:30", Some("%d/%m/%Y %H:%M:%S")),
+            Some(november_24())
+        );
+    }
+
+    #[test]
+    fn parses_german_and_japanese_clippings() {
+        let clippings = "\
+Moby Dick (Herman Melville)
+- Ihre Markierung auf Seite 12 | Position 170-171 | Hinzugefügt am Samstag, 24. November 2018 11:31:30
+
+Call me Ishmael
+==========
+白鯨 (メルヴィル)
+- 12ページ|位置No. 170-171のハイライト |作成日: 2018年11月24日土曜日 11:31:30
+
+私をイシュメールと呼んでくれ
+==========
+";
+        let (entries, warnings) = parse(clippings, &ParseOptions::default());
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(
+            sentences(&entries),
+            ["Call me Ishmael", "私をイシュメールと呼んでくれ"]
+        );
+        assert!(matches!(&entries[1], Clipping::Highlight { book, .. } if book == "白鯨"));
+    }
+}
@@ -52,7 +52,7 @@
 //!
 //! ## To attach additional info to cards,
 //! - ` .. ` can be added in a note. Content after the ` ..` is added to the
-//! Anki backside after the example sentence as extra content. Additional
+//!   Anki backside after the example sentence as extra content. Additional
 //!
 //! For example, the following note and higlight pair
 //!
@@ -76,11 +76,13 @@
 //! I remember when I was six years old on a hill in yellowstone and almost rolled face-first into a pile of bison dung
 //! ```
 //!
-//! You can add multiple ` .. `s to create newlines in your comments, and in every newline you can add a term.
+//! Adding more ` .. `s clozes several words in one card, see [below](#to-cloze-several-words-in-one-card).
+//! To put several lines on the back of a basic card instead, separate them with ` ... `s.
 //!
 //! ## To create a cloze card
-//! - ` ...` can be added after term to designate the word that should be clozed. After which, any content after the ` ... ` functions as "extra" content. 
-//! ???Please note that there can only be one cloze term per sentence/higlight as of now.
+//! - ` ...` can be added after term to designate the word that should be clozed. After which, any content after the ` ... ` functions as "extra" content.
+//!   Each line makes its own cloze card, unless `--merge-cloze-lines` is passed, which puts the
+//!   clozes of consecutive lines in one card as `c1`, `c2`, ...
 //!
 //! For example, the following note and higlight pair
 //!
@@ -99,23 +101,114 @@
 //! =====
 //! I remember when I first began walking: my mama balked her eyes out (no I didn't remember)
 //! ```
-//! 
+//!
 //! Similarly with basic cards, (TODO WIP: by adding additional ...s you can chain comment lines together)
 //! And by creating multiple lines, you can add multiple terms
+//!
+//! ## To show the dictionary form of a clozed word
+//! - `walked=walk` clozes `walked` in the highlight and adds the lemma `walk` to the back,
+//!   before any extra content. A line with a lemma is always a cloze, so `walked=walk ... extra`
+//!   is one too, with `extra` as its extra content.
+//!
+//! ## To cloze several words in one card
+//! - `walked .. hill .. extra` clozes both `walked` (`c1`) and `hill` (`c2`). Every part but the
+//!   last is a word to cloze, and the last part is the shared extra content.
+//!
+//! ## To hide several words on the same card
+//! - a note line starting with `+ ` adds its term to the cloze on the line before as another
+//!   `c1` blank, so Anki shows one card with both words hidden instead of a card per word.
+//!
+//! ```txt
+//! walked
+//! + hill
+//! ```
+//!
+//! Would create the cloze card `the cat {{c1::walked}} over the {{c1::hill}}`
+//!
+//! ## To rate how well you already know a term
+//! - a note line starting with `@1 ` to `@5 ` tags its card `familiarity::1` to `familiarity::5`,
+//!   e.g. `@3 walked ... to move on foot`, so cards can be filtered or rescheduled by it in Anki.
+//!
+//! ## To write notes with other separators
+//! - `--cloze-sep ' >> '` and `--extra-sep ' :: '` replace ` .. ` and ` ... `, for books whose
+//!   highlights and notes use dots themselves.
 
-use anki_kindle_import::{config::Config, convert_config_to_finished_app};
+use std::io::Write;
+use std::path::Path;
+
+use anki_kindle_import::{
+    batch,
+    config::{self, Config, LogFormat},
+    convert_config_to_finished_app,
+};
 use env_logger::Env;
 use log::info;
 
 use anyhow::Error;
 
 fn main() -> Result<(), Error> {
+    let matches = config::command().get_matches();
+
     // initialize logger
-    env_logger::Builder::from_env(Env::default().default_filter_or("trace")).init();
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("trace"));
+    if LogFormat::from_matches(&matches) == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", json_log_line(record)));
+    }
+    builder.init();
     info!("Program started");
 
+    if let Some(batch_path) = matches.get_one::<String>("batch") {
+        return batch::run(Path::new(batch_path), &matches);
+    }
+
     // generate config
-    convert_config_to_finished_app(Config::new()?)?;
+    convert_config_to_finished_app(Config::new(&matches)?)?;
 
     Ok(())
 }
+
+/// A log line as a JSON object, for `--log-format json`
+fn json_log_line(record: &log::Record) -> String {
+    serde_json::json!({
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_log_format_writes_json_lines() {
+        let matches = config::command()
+            .try_get_matches_from(["anki-kindle-import", "--log-format", "json"])
+            .unwrap();
+        assert_eq!(LogFormat::from_matches(&matches), LogFormat::Json);
+
+        let line = json_log_line(
+            &log::Record::builder()
+                .args(format_args!("no match for `hill`"))
+                .level(log::Level::Warn)
+                .target("anki_kindle_import")
+                .build(),
+        );
+        assert!(!line.contains('\n'));
+        let line: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "anki_kindle_import");
+        assert_eq!(line["message"], "no match for `hill`");
+        assert!(line["timestamp"].is_string());
+    }
+
+    #[test]
+    fn text_log_format_is_the_default() {
+        let matches = config::command()
+            .try_get_matches_from(["anki-kindle-import"])
+            .unwrap();
+        assert_eq!(LogFormat::from_matches(&matches), LogFormat::Text);
+    }
+}
@@ -0,0 +1,211 @@
+//! Highlights exported from [Readwise](https://readwise.io) as JSON, in the
+//! shape of its export api:
+//!
+//! ```json
+//! { "results": [ { "title": "...", "author": "...", "highlights": [
+//!     { "text": "...", "note": "walked .. hill", "highlighted_at": "2022-03-14T10:00:00Z" }
+//! ] } ] }
+//! ```
+use anyhow::{Context, Error};
+use chrono::prelude::*;
+use serde::Deserialize;
+
+use crate::config::ParseOptions;
+use crate::{before_start, collapse_whitespace, note_cards, split_bullets, Clipping, Warnings};
+
+#[derive(Debug, Deserialize)]
+pub struct Export {
+    pub results: Vec<Book>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Book {
+    pub title: String,
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub highlights: Vec<Highlight>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Highlight {
+    pub text: String,
+    /// written the same way as a kindle note, e.g. `walked .. hill`
+    #[serde(default)]
+    pub note: Option<String>,
+    #[serde(default)]
+    pub highlighted_at: Option<DateTime<Utc>>,
+    /// a page or a kindle location, depending on `location_type`
+    #[serde(default)]
+    pub location: Option<u32>,
+    #[serde(default)]
+    pub location_type: Option<String>,
+}
+
+/// Maps a Readwise export onto the same clippings a kindle file produces. Each
+/// highlight becomes a [Clipping::Highlight], or a [Clipping::Note] if it has a
+/// note, whose lines are turned into cards just like kindle notes are.
+pub fn parse_from_json(
+    json: &str,
+    date_after: Option<DateTime<Utc>>,
+    options: &ParseOptions,
+    warnings: &mut Warnings,
+) -> Result<Vec<Clipping>, Error> {
+    let export: Export =
+        serde_json::from_str(json).with_context(|| "unable to parse Readwise export")?;
+
+    let mut entries = Vec::new();
+    for book in export.results {
+        if options
+            .exclude_books
+            .iter()
+            .any(|excluded| book.title.contains(excluded.as_str()))
+        {
+            continue;
+        }
+        let author = book.author.unwrap_or_default();
+
+        for highlight in book.highlights {
+            // dating them now would let them through any start date, and move
+            // `out/last-date.json` past every real clipping
+            let Some(date) = highlight.highlighted_at else {
+                warnings.push(format!(
+                    "skipping highlight `{}` in `{}` without a `highlighted_at` date",
+                    highlight.text, book.title
+                ));
+                continue;
+            };
+            if before_start(date, date_after, options) {
+                continue;
+            }
+
+            let (page, location) = match highlight.location_type.as_deref() {
+                Some("page") => (highlight.location, None),
+                Some("location") => (None, highlight.location),
+                _ => (None, None),
+            };
+            let mut sentence = highlight.text;
+            if options.collapse_whitespace {
+                sentence = collapse_whitespace(&sentence);
+            }
+            entries.push(Clipping::Highlight {
+                book: book.title.clone(),
+                author: author.clone(),
+                date,
+                sentence,
+                color: None,
+                page,
+                location,
+            });
+
+            let note = highlight.note.unwrap_or_default();
+            let mut note_lines = Vec::new();
+            for line in note.lines().filter(|line| !line.trim().is_empty()) {
+                match &options.bullet_split {
+                    Some(marker) => note_lines.extend(split_bullets(line, marker)),
+                    None => note_lines.push(line.to_string()),
+                }
+            }
+            if note_lines.is_empty() {
+                continue;
+            }
+
+            let cards = note_cards(
+                &note_lines,
+                &entries,
+                (&book.title, &author, date),
+                options,
+                warnings,
+            )?;
+            if cards.is_empty() {
+                warnings.push(format!(
+                    "note in `{}` from {date} produced no cards",
+                    book.title
+                ));
+            }
+            entries.pop();
+            entries.push(Clipping::Note {
+                book: book.title.clone(),
+                author: author.clone(),
+                date,
+                cards,
+                page,
+                location,
+            });
+        }
+    }
+
+    if options.notes_only {
+        entries.retain(|entry| matches!(entry, Clipping::Note { .. }));
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Card;
+
+    const EXPORT: &str = r#"{ "results": [
+        { "title": "Moby Dick", "author": "Herman Melville", "highlights": [
+            { "text": "The cat walked over the hill", "note": "hill", "highlighted_at": "2022-03-14T10:00:00Z", "location": 120, "location_type": "location" },
+            { "text": "Call me Ishmael", "highlighted_at": "2022-03-15T10:00:00Z", "location": 3, "location_type": "page" },
+            { "text": "Imported without a date" }
+        ] },
+        { "title": "Dune", "highlights": [] }
+    ] }"#;
+
+    #[test]
+    fn parses_highlights_and_notes() {
+        let mut warnings = Warnings::default();
+        let entries =
+            parse_from_json(EXPORT, None, &ParseOptions::default(), &mut warnings).unwrap();
+        assert_eq!(entries.len(), 2);
+        match &entries[0] {
+            Clipping::Note {
+                book,
+                author,
+                cards,
+                location,
+                ..
+            } => {
+                assert_eq!(book, "Moby Dick");
+                assert_eq!(author, "Herman Melville");
+                assert_eq!(*location, Some(120));
+                assert!(
+                    matches!(&cards[..], [Card::Cloze(cloze)] if cloze.text.contains("{{c1::hill}}"))
+                );
+            }
+            entry => panic!("expected a note, got {entry:?}"),
+        }
+        assert!(matches!(
+            &entries[1],
+            Clipping::Highlight { sentence, page: Some(3), .. } if sentence == "Call me Ishmael"
+        ));
+    }
+
+    #[test]
+    fn skips_undated_highlights_with_a_warning() {
+        let mut warnings = Warnings::default();
+        let entries =
+            parse_from_json(EXPORT, None, &ParseOptions::default(), &mut warnings).unwrap();
+        assert!(entries.iter().all(|entry| entry.date().year() == 2022));
+        assert!(warnings
+            .iter()
+            .any(|warning| warning.contains("Imported without a date")));
+    }
+
+    #[test]
+    fn filters_by_start_date() {
+        let date_after = Utc.with_ymd_and_hms(2022, 3, 15, 0, 0, 0).unwrap();
+        let entries = parse_from_json(
+            EXPORT,
+            Some(date_after),
+            &ParseOptions::default(),
+            &mut Warnings::default(),
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 1);
+    }
+}
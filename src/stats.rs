@@ -0,0 +1,127 @@
+//! `--stats` writes reading statistics over the parsed clippings to
+//! `out/stats.json` instead of making cards, e.g.
+//!
+//! ```json
+//! {
+//!   "average_highlight_length": 84.5,
+//!   "bookmarks": 3,
+//!   "cards": 31,
+//!   "clippings_per_month": { "2018-11": 12, "2018-12": 40 },
+//!   "highlights": 44,
+//!   "highlights_per_book": { "Dune": 30, "Moby Dick": 14 },
+//!   "most_highlighted_book": "Dune",
+//!   "notes": 8
+//! }
+//! ```
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::Clipping;
+
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct Stats {
+    /// every highlight, with or without a note
+    pub highlights: usize,
+    pub notes: usize,
+    pub bookmarks: usize,
+    /// cards made from all of the notes
+    pub cards: usize,
+    pub highlights_per_book: BTreeMap<String, usize>,
+    /// highlights and notes per `YYYY-MM` they were added in
+    pub clippings_per_month: BTreeMap<String, usize>,
+    /// in characters, over the highlights without a note, since a note keeps
+    /// only the cards made from its highlight
+    pub average_highlight_length: f64,
+    /// the book with the most highlights, the first by title on a tie
+    pub most_highlighted_book: Option<String>,
+}
+
+/// Counts what was read and highlighted in `entries`
+pub fn stats(entries: &[Clipping]) -> Stats {
+    let mut stats = Stats::default();
+    let mut highlighted = 0;
+    let mut highlight_length = 0;
+    for entry in entries {
+        if let Clipping::Bookmark { .. } = entry {
+            stats.bookmarks += 1;
+            continue;
+        }
+        *stats
+            .clippings_per_month
+            .entry(entry.date().format("%Y-%m").to_string())
+            .or_default() += 1;
+        match entry {
+            Clipping::Highlight { book, sentence, .. } => {
+                stats.highlights += 1;
+                highlighted += 1;
+                highlight_length += sentence.chars().count();
+                *stats.highlights_per_book.entry(book.clone()).or_default() += 1;
+            }
+            // a kindle note is always attached to a highlight, which it replaces
+            Clipping::Note { book, cards, .. } => {
+                stats.highlights += 1;
+                *stats.highlights_per_book.entry(book.clone()).or_default() += 1;
+                stats.notes += 1;
+                stats.cards += cards.len();
+            }
+            Clipping::Bookmark { .. } => {}
+        }
+    }
+    if highlighted > 0 {
+        stats.average_highlight_length = highlight_length as f64 / highlighted as f64;
+    }
+    // `max_by_key` keeps the last of equal counts, so go through the titles backwards
+    stats.most_highlighted_book = stats
+        .highlights_per_book
+        .iter()
+        .rev()
+        .max_by_key(|(_, count)| **count)
+        .map(|(book, _)| book.clone());
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ParseOptions;
+    use crate::tests::{book_clipping, clipping, parse};
+
+    #[test]
+    fn counts_a_small_history() {
+        let clippings = [
+            clipping("Highlight", 10, "Call me Ishmael"),
+            clipping("Highlight", 11, "The cat walked over the hill"),
+            clipping("Note", 12, "hill .. a small mountain\nwalked"),
+            clipping("Bookmark", 13, ""),
+            book_clipping("Dune", "Highlight", 14, "Fear is the mind-killer"),
+            book_clipping("Dune", "Highlight", 15, "Walk without rhythm"),
+            clipping("Highlight", 16, "The dog ran"),
+            clipping("Note", 17, "ran"),
+        ]
+        .concat();
+        let (entries, _) = parse(&clippings, &ParseOptions::default());
+        let stats = stats(&entries);
+        assert_eq!(
+            stats,
+            Stats {
+                highlights: 5,
+                notes: 2,
+                bookmarks: 1,
+                cards: 3,
+                highlights_per_book: BTreeMap::from([
+                    ("Dune".to_string(), 2),
+                    ("Moby Dick".to_string(), 3),
+                ]),
+                clippings_per_month: BTreeMap::from([("2018-11".to_string(), 5)]),
+                average_highlight_length: 19.0,
+                most_highlighted_book: Some("Moby Dick".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn nothing_read_has_no_average_or_most_highlighted_book() {
+        assert_eq!(stats(&[]), Stats::default());
+    }
+}
@@ -0,0 +1,124 @@
+//! Definitions from an offline Wiktionary extract, in the JSON lines format of
+//! [kaikki.org](https://kaikki.org/dictionary/rawdata.html): one entry per line,
+//!
+//! ```json
+//! { "word": "hill", "pos": "noun", "senses": [ { "glosses": ["An elevated location smaller than a mountain."] } ] }
+//! ```
+//!
+//! The first gloss of a word's first entry is used as its definition.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use anyhow::{Context, Error};
+use log::{info, warn};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+struct Entry {
+    word: String,
+    #[serde(default)]
+    senses: Vec<Sense>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Sense {
+    #[serde(default)]
+    glosses: Vec<String>,
+}
+
+/// Reads the definition of every word in the extract at `path`, keyed by the
+/// lowercased word. Lines that aren't entries are skipped.
+pub fn load(path: &Path) -> Result<BTreeMap<String, String>, Error> {
+    let file = File::open(path).with_context(|| format!("unable to open {path:?}"))?;
+
+    let mut definitions = BTreeMap::new();
+    let mut skipped = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("unable to read {path:?}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Ok(entry) = serde_json::from_str::<Entry>(&line) else {
+            skipped += 1;
+            continue;
+        };
+        let gloss = entry
+            .senses
+            .into_iter()
+            .flat_map(|sense| sense.glosses)
+            .next();
+        if let Some(gloss) = gloss {
+            definitions
+                .entry(entry.word.to_lowercase())
+                .or_insert(gloss);
+        }
+    }
+    if skipped > 0 {
+        warn!("skipped {skipped} lines of {path:?} that aren't Wiktionary entries");
+    }
+    info!("read {} definitions from {path:?}", definitions.len());
+    Ok(definitions)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::config::{BareTerm, ParseOptions};
+    use crate::tests::{cards, clipping, parse, temp_dir};
+    use crate::Card;
+
+    const EXTRACT: &str = r#"{"word": "hill", "pos": "noun", "senses": [{"glosses": ["An elevated location smaller than a mountain."]}, {"glosses": ["A heap of earth."]}]}
+{"word": "hill", "pos": "verb", "senses": [{"glosses": ["To form into a heap."]}]}
+{"word": "Harpoon", "pos": "noun", "senses": [{"glosses": ["A spear used to hunt whales."]}]}
+{"word": "the", "pos": "article", "senses": [{"tags": ["form-of"]}]}
+not an entry
+"#;
+
+    #[test]
+    fn fills_basic_fronts_from_the_extract() {
+        let path = temp_dir("wiktionary").join("extract.jsonl");
+        fs::write(&path, EXTRACT).unwrap();
+        let definitions = load(&path).unwrap();
+        assert_eq!(
+            definitions,
+            BTreeMap::from([
+                (
+                    "harpoon".to_string(),
+                    "A spear used to hunt whales.".to_string()
+                ),
+                (
+                    "hill".to_string(),
+                    "An elevated location smaller than a mountain.".to_string()
+                ),
+            ])
+        );
+
+        let clippings = [
+            clipping("Highlight", 10, "The cat walked over the hill"),
+            clipping("Note", 11, "hill\nwalked"),
+        ]
+        .concat();
+        let options = ParseOptions {
+            bare_term_as: BareTerm::Basic,
+            definitions,
+            ..ParseOptions::default()
+        };
+        let (entries, _) = parse(&clippings, &options);
+        let fronts: Vec<&str> = cards(&entries)
+            .iter()
+            .map(|card| card.export_fields().0)
+            .collect();
+        assert_eq!(
+            fronts,
+            ["An elevated location smaller than a mountain.", ""]
+        );
+        assert!(matches!(
+            cards(&entries)[..],
+            [Card::Basic(_), Card::Basic(_)]
+        ));
+    }
+}